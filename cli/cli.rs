@@ -2,6 +2,10 @@ use std::path::PathBuf;
 
 use clap::Clap;
 use clap::{crate_authors, crate_description, crate_name, crate_version};
+use log::LevelFilter;
+use muso::catalog::CatalogFormat;
+use muso::format::TargetFs;
+use muso::sorting::{ConflictPolicy, MissingTagPolicy};
 
 #[derive(Debug, Clap)]
 #[clap(name = crate_name!())]
@@ -13,46 +17,227 @@ pub struct CliArgs {
     #[clap(short, long)]
     pub config: Option<PathBuf>,
 
+    /// Most verbose level logged, overrides the config file.
+    #[clap(name = "log-level", long, possible_values = &["off", "error", "warn", "info", "debug"])]
+    pub log_level: Option<LevelFilter>,
+
+    /// Don't color the level of each line.
+    #[clap(name = "no-color", long)]
+    pub no_color: bool,
+
+    /// Prefix each line with the date and time.
+    #[clap(long)]
+    pub timestamps: bool,
+
     #[clap(subcommand)]
     pub cmd: SubCommand,
 }
 
 #[derive(Debug, Clap)]
+#[allow(clippy::large_enum_variant)]
 pub enum SubCommand {
     /// Copy service file to systemd user config dir.
     #[clap(name = "copy-service")]
     CopyService,
 
     /// Watch libraries and sort added files.
-    Watch,
+    Watch(WatchArgs),
 
     /// Sort a music directory.
-    Sort {
-        /// Path to music directory.
-        path: Option<PathBuf>,
-
-        /// Custom format string.
-        #[clap(short, long)]
-        format: Option<String>,
+    Sort(SortArgs),
 
-        /// Don't sort anything (simulated run).
-        #[clap(short, long)]
-        dryrun: bool,
+    /// Check the config file, library folders and service for common mistakes.
+    Doctor,
 
-        /// Sort files recursively.
-        #[clap(short, long)]
-        recursive: bool,
+    /// List the placeholders available in format strings.
+    Placeholders,
 
-        /// Remove empty directories found while and after sorting.
-        #[clap(name = "rm-empty", long)]
-        remove_empty: bool,
+    /// Show where muso looks for its files.
+    Config(ConfigArgs),
 
-        /// Mantain file names compatible with FAT32.
-        #[clap(short, long)]
-        exfat_compat: bool,
-    },
+    /// Write the path and tags of every file in a library to stdout.
+    Catalog(CatalogArgs),
 
     /// Goodies related to sync mode.
     #[cfg(feature = "sync")]
     Sync,
 }
+
+#[derive(Debug, Clap)]
+pub struct WatchArgs {
+    /// Log a line every this many files instead of one per file (0 logs every file).
+    #[clap(name = "batch-log", long)]
+    pub batch_log: Option<usize>,
+}
+
+#[derive(Debug, Clap)]
+pub struct ConfigArgs {
+    #[clap(subcommand)]
+    pub cmd: ConfigCmd,
+}
+
+#[derive(Debug, Clap)]
+pub enum ConfigCmd {
+    /// Print the paths of the config file, the service file and the data folder.
+    Path,
+}
+
+#[derive(Debug, Clap)]
+pub struct CatalogArgs {
+    /// Name of the library in the config file.
+    pub library: String,
+
+    /// Output format.
+    #[clap(long, default_value = "json", possible_values = &["json", "csv"])]
+    pub format: CatalogFormat,
+}
+
+#[derive(Debug, Clap)]
+pub struct SortArgs {
+    /// Path to music directory.
+    pub path: Option<PathBuf>,
+
+    /// Custom format string.
+    #[clap(short, long)]
+    pub format: Option<String>,
+
+    /// Don't sort anything (simulated run).
+    #[clap(short, long)]
+    pub dryrun: bool,
+
+    /// Sort files recursively.
+    #[clap(short, long)]
+    pub recursive: bool,
+
+    /// Remove empty directories found while and after sorting.
+    #[clap(name = "rm-empty", long)]
+    pub remove_empty: bool,
+
+    /// Mantain file names compatible with exFAT (same as --target-fs exfat).
+    #[clap(short, long)]
+    pub exfat_compat: bool,
+
+    /// Filesystem the files end up in, which decides the characters and names avoided.
+    #[clap(name = "target-fs", long, possible_values = &["posix", "vfat", "ntfs", "exfat"], conflicts_with = "exfat-compat")]
+    pub target_fs: Option<TargetFs>,
+
+    /// Remove folders left only with files of these extensions (e.g. nfo,txt,jpg).
+    #[clap(name = "clean-leftovers", long, use_delimiter = true)]
+    pub clean_leftovers: Vec<String>,
+
+    /// Use the format and settings of this library from the config file.
+    #[clap(short, long)]
+    pub library: Option<String>,
+
+    /// What to do when a destination is already taken.
+    #[clap(long, possible_values = &["skip", "rename"])]
+    pub conflict: Option<ConflictPolicy>,
+
+    /// What to do with files missing a required tag: error, skip or placeholder=<text>.
+    #[clap(name = "missing-tag", long)]
+    pub missing_tag_policy: Option<MissingTagPolicy>,
+
+    /// File albums with at least this many distinct artists under "Various Artists".
+    #[clap(name = "compilation-threshold", long)]
+    pub compilation_threshold: Option<usize>,
+
+    /// Command run after every moved file (with MUSO_SRC and MUSO_DST set).
+    #[clap(name = "each-file-hook", long)]
+    pub each_file_hook: Option<String>,
+
+    /// Compare files copied across filesystems before removing the original.
+    #[clap(long)]
+    pub verify: bool,
+
+    /// Only report destinations that would be taken by more than one file (implies dryrun).
+    #[clap(name = "simulate-conflicts", long)]
+    pub simulate_conflicts: bool,
+
+    /// Only list the files that would move and count the ones in place (implies dryrun).
+    #[clap(long)]
+    pub diff: bool,
+
+    /// Write the moves as a shell script to this path instead of sorting (implies dryrun).
+    #[clap(name = "emit-script", long)]
+    pub emit_script: Option<PathBuf>,
+
+    /// Stop at the first file that couldn't be sorted.
+    #[clap(name = "fail-fast", long)]
+    pub fail_fast: bool,
+
+    /// Omit directories whose optional placeholders are all missing.
+    #[clap(name = "collapse-empty-dirs", long)]
+    pub collapse_empty_dirs: bool,
+
+    /// Prefer the sort variants of artist and album tags (e.g. "Beatles, The").
+    #[clap(name = "prefer-sort-tags", long)]
+    pub prefer_sort_tags: bool,
+
+    /// Skip files smaller than this many bytes.
+    #[clap(name = "move-threshold", long)]
+    pub min_size: Option<u64>,
+
+    /// Sort files into this folder instead of the one being sorted.
+    #[clap(name = "into", long, alias = "dest")]
+    pub dest_root: Option<PathBuf>,
+
+    /// Create the folder given to --into (and its parents) if it doesn't exist.
+    #[clap(long, requires = "into")]
+    pub mkdir: bool,
+
+    /// Render extensions differently in paths (e.g. ogg=oga), file contents are untouched.
+    #[clap(name = "ext-map", long, use_delimiter = true)]
+    pub ext_map: Vec<String>,
+
+    /// Lowercase extensions in paths (e.g. MP3 to mp3).
+    #[clap(name = "lowercase-ext", long)]
+    pub lowercase_ext: bool,
+
+    /// Use the artist and album of one track per folder for all of its tracks.
+    #[clap(name = "album-mode", long)]
+    pub album_mode: bool,
+
+    /// Use only the first artist in folders, when several are joined by this separator (e.g. ";").
+    #[clap(name = "split-artist", long)]
+    pub split_artist: Option<String>,
+
+    /// Keep the folder each file was found in as a prefix of its new path.
+    #[clap(name = "preserve-source-tree", long)]
+    pub preserve_source_tree: bool,
+
+    /// Descend into symlinked folders.
+    #[clap(name = "follow-symlinks", long)]
+    pub follow_symlinks: bool,
+
+    /// Leave files whose path already matches the format without reading their tags.
+    #[clap(name = "skip-conforming", long)]
+    pub skip_conforming: bool,
+
+    /// Don't use the release year when the original year is missing.
+    #[clap(name = "strict-original-year", long)]
+    pub strict_original_year: bool,
+
+    /// Record every move in this file, so the sort can be resumed or rolled back.
+    #[clap(long)]
+    pub journal: Option<PathBuf>,
+
+    /// Finish the moves left pending in the journal, then sort as usual.
+    #[clap(long, requires = "journal", conflicts_with = "rollback")]
+    pub resume: bool,
+
+    /// Move back every file recorded in the journal instead of sorting.
+    #[clap(long, requires = "journal")]
+    pub rollback: bool,
+
+    /// Write the tags used for sorting back to the files (FLAC, MP3, M4A and APE only).
+    #[clap(name = "fix-tags", long)]
+    pub fix_tags: bool,
+
+    /// Abort unless the destination is on the same device as this file (e.g. on a mount).
+    #[clap(name = "device-sentinel", long)]
+    pub device_sentinel: Option<PathBuf>,
+
+    /// Also sort hidden files and folders (e.g. ".git"), skipped by default.
+    #[clap(name = "include-hidden", long)]
+    pub include_hidden: bool,
+}
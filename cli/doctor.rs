@@ -0,0 +1,211 @@
+// Copyright (C) 2020 kevin
+//
+// This file is part of muso.
+//
+// muso is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// muso is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with muso.  If not, see <http://www.gnu.org/licenses/>.
+
+use std::env;
+use std::fmt::Display;
+use std::fs::{self, OpenOptions};
+use std::io;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+use ansi_term::Color::{Green, Red, Yellow};
+use muso::config::Config;
+use muso::format::ParsedFormat;
+use muso::utils;
+
+#[derive(Default)]
+struct Checklist {
+    failed: usize,
+}
+
+impl Checklist {
+    fn pass(&mut self, check: impl Display) {
+        println!("{} {}", Green.bold().paint("[ ok ]"), check);
+    }
+
+    fn fail(&mut self, check: impl Display, reason: impl Display) {
+        println!("{} {} ({})", Red.bold().paint("[fail]"), check, reason);
+        self.failed += 1;
+    }
+
+    fn skip(&mut self, check: impl Display, reason: impl Display) {
+        println!("{} {} ({})", Yellow.bold().paint("[skip]"), check, reason);
+    }
+}
+
+/// Checks the config file (and everything it points to) and the installed service,
+/// printing a checklist. Returns how many checks failed.
+pub fn run(config_path: &Path) -> usize {
+    let mut checklist = Checklist::default();
+
+    check_config(&mut checklist, config_path);
+    check_service(&mut checklist);
+
+    checklist.failed
+}
+
+fn check_config(checklist: &mut Checklist, path: &Path) {
+    let check = format!("Config file exists: \"{}\"", path.display());
+    let contents = match fs::read_to_string(path) {
+        Ok(contents) => {
+            checklist.pass(check);
+            contents
+        }
+
+        Err(e) => return checklist.fail(check, e),
+    };
+
+    // Formats are checked one by one, so a broken one doesn't hide the others
+    let value = match toml::Value::from_str(&contents) {
+        Ok(value) => value,
+        Err(e) => return checklist.fail("Config file is valid TOML", e),
+    };
+
+    if let Some(format) = value.get("default-format").and_then(|f| f.as_str()) {
+        check_format(checklist, "Default format is valid", format, false);
+    }
+
+    if let Some(libraries) = value.get("libraries").and_then(|l| l.as_table()) {
+        for (name, library) in libraries {
+            let collapse_empty_dirs = library
+                .get("collapse-empty-dirs")
+                .and_then(|c| c.as_bool())
+                .unwrap_or(false);
+
+            if let Some(format) = library.get("format").and_then(|f| f.as_str()) {
+                let check = format!("Format of library \"{}\" is valid", name);
+                check_format(checklist, check, format, collapse_empty_dirs);
+            }
+        }
+    }
+
+    let (config, warnings) = match Config::from_path_with_warnings(path) {
+        Ok(parsed) => {
+            checklist.pass("Config file is valid");
+            parsed
+        }
+
+        Err(e) => return checklist.fail("Config file is valid", e),
+    };
+
+    for warning in warnings {
+        checklist.fail("Library folders exist", warning);
+    }
+
+    for name in &config.watch.libraries {
+        let check = format!("Watched library \"{}\" is defined", name);
+        if config.libraries.contains_key(name) {
+            checklist.pass(check);
+        } else {
+            checklist.fail(check, "missing from [libraries]");
+        }
+    }
+
+    for (name, library) in &config.libraries {
+        for folder in &library.folders {
+            let check = format!(
+                "Folder \"{}\" of library \"{}\" is writable",
+                folder.display(),
+                name
+            );
+
+            match probe_writable(folder) {
+                Ok(_) => checklist.pass(check),
+                Err(e) => checklist.fail(check, e),
+            }
+        }
+    }
+}
+
+fn check_format(
+    checklist: &mut Checklist,
+    check: impl Display,
+    format: &str,
+    collapse_empty_dirs: bool,
+) {
+    let validated =
+        ParsedFormat::from_str(format).and_then(|format| format.validate(collapse_empty_dirs));
+
+    match validated {
+        Ok(_) => checklist.pass(check),
+        Err(e) => checklist.fail(check, e),
+    }
+}
+
+/// Creates (and removes) a hidden file in `folder`, hidden files are ignored by the
+/// watcher by default.
+fn probe_writable(folder: &Path) -> io::Result<()> {
+    let probe = folder.join(".muso-doctor");
+    OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .open(&probe)?;
+
+    fs::remove_file(&probe)
+}
+
+fn check_service(checklist: &mut Checklist) {
+    let path = match utils::default_service_path() {
+        Ok(path) => path,
+        Err(e) => return checklist.skip("Service runs this binary", e),
+    };
+
+    let check = format!("Service runs this binary: \"{}\"", path.display());
+
+    let contents = match fs::read_to_string(&path) {
+        Ok(contents) => contents,
+        Err(_) => return checklist.skip(check, "service not installed"),
+    };
+
+    let command = contents
+        .lines()
+        .find_map(|line| line.trim().strip_prefix("ExecStart="))
+        .map(|command| command.split_whitespace().collect::<Vec<_>>())
+        .unwrap_or_default();
+
+    // The provided service runs `/usr/bin/env muso watch`
+    let (name, program) = match command.as_slice() {
+        [env, name, ..] if env.ends_with("/env") => (*name, find_in_path(name)),
+        [name, ..] => (*name, Some(PathBuf::from(name))),
+        [] => return checklist.fail(check, "no ExecStart found"),
+    };
+
+    let program = match program.and_then(|program| program.canonicalize().ok()) {
+        Some(program) => program,
+        None => return checklist.fail(check, format!("couldn't find \"{}\"", name)),
+    };
+
+    match env::current_exe().and_then(|exe| exe.canonicalize()) {
+        Ok(exe) if exe == program => checklist.pass(check),
+        Ok(exe) => checklist.fail(
+            check,
+            format!(
+                "runs \"{}\" instead of \"{}\"",
+                program.display(),
+                exe.display()
+            ),
+        ),
+        Err(e) => checklist.fail(check, e),
+    }
+}
+
+fn find_in_path(program: &str) -> Option<PathBuf> {
+    let paths = env::var_os("PATH")?;
+    env::split_paths(&paths)
+        .map(|dir| dir.join(program))
+        .find(|path| path.is_file())
+}
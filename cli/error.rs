@@ -21,4 +21,22 @@ use thiserror::Error;
 pub enum Error {
     #[error("Path {path} is not valid as root folder!")]
     InvalidRoot { path: String },
+
+    #[error("Path {path} is not a folder to sort into!")]
+    InvalidDest { path: String },
+
+    #[error("Library \"{name}\" doesn't exist in config file!")]
+    UnknownLibrary { name: String },
+
+    #[error("{failed} file(s) couldn't be sorted!")]
+    PartialFailure { failed: usize },
+
+    #[error("Invalid extension mapping \"{mapping}\" (expected \"from=to\")")]
+    InvalidExtMap { mapping: String },
+
+    #[error("{count} destination(s) would be taken by more than one file!")]
+    ConflictsFound { count: usize },
+
+    #[error("{failed} check(s) failed!")]
+    ChecksFailed { failed: usize },
 }
@@ -15,28 +15,111 @@
 // You should have received a copy of the GNU General Public License
 // along with muso.  If not, see <http://www.gnu.org/licenses/>.
 
-use ansi_term::Color::{Cyan, Red, Yellow};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+use ansi_term::Color::{self, Cyan, Purple, Red, Yellow};
 use log::{set_logger, set_max_level, Level, LevelFilter, Log, Metadata, Record, SetLoggerError};
+use muso::sorting::FILE_LOG_TARGET;
 
-pub struct MusoLogger;
+pub struct MusoLogger {
+    /// Files between batched lines, `0` logs every line.
+    batch_size: AtomicUsize,
+    /// Files sorted since the last summary.
+    batched: AtomicUsize,
+    /// Whether levels are colored.
+    color: AtomicBool,
+    /// Whether lines are prefixed with the local date and time.
+    timestamps: AtomicBool,
+}
 
-static MUSO_LOGGER: MusoLogger = MusoLogger {};
+static MUSO_LOGGER: MusoLogger = MusoLogger {
+    batch_size: AtomicUsize::new(0),
+    batched: AtomicUsize::new(0),
+    color: AtomicBool::new(true),
+    timestamps: AtomicBool::new(false),
+};
 
 pub fn init_logger() -> Result<(), SetLoggerError> {
     set_logger(&MUSO_LOGGER).map(|_| set_max_level(LevelFilter::Info))
 }
 
+/// Sets whether levels are colored and lines prefixed with the date and time.
+pub fn set_style(color: bool, timestamps: bool) {
+    MUSO_LOGGER.color.store(color, Ordering::Relaxed);
+    MUSO_LOGGER.timestamps.store(timestamps, Ordering::Relaxed);
+}
+
+/// Replaces the info lines logged while sorting each file with a single line every
+/// `size` files, the summaries logged after each sort are kept.
+pub fn set_batch_size(size: usize) {
+    MUSO_LOGGER.batch_size.store(size, Ordering::Relaxed);
+}
+
+impl MusoLogger {
+    /// Whether `record` is swallowed by the current batch, logging the batched line
+    /// when it's full.
+    fn batch(&self, record: &Record) -> bool {
+        let size = self.batch_size.load(Ordering::Relaxed);
+        if size == 0 || record.level() != Level::Info {
+            return false;
+        }
+
+        if !record.target().starts_with("muso::sorting") {
+            // Summaries close the batch
+            self.batched.store(0, Ordering::Relaxed);
+            return false;
+        }
+
+        if record.target() == FILE_LOG_TARGET {
+            let batched = self.batched.fetch_add(1, Ordering::Relaxed) + 1;
+            if batched.is_multiple_of(size) {
+                println!(
+                    "{} Sorting: {} file(s) so far",
+                    self.prefix("[info]", Cyan),
+                    batched
+                );
+            }
+        }
+
+        true
+    }
+
+    /// The level `label` (colored if enabled), after the date and time if enabled.
+    fn prefix(&self, label: &str, color: Color) -> String {
+        let label = if self.color.load(Ordering::Relaxed) {
+            color.bold().paint(label).to_string()
+        } else {
+            label.to_owned()
+        };
+
+        if self.timestamps.load(Ordering::Relaxed) {
+            format!(
+                "{} {}",
+                chrono::Local::now().format("%Y-%m-%d %H:%M:%S"),
+                label
+            )
+        } else {
+            label
+        }
+    }
+}
+
 impl Log for MusoLogger {
     fn enabled(&self, _metadata: &Metadata) -> bool {
         true
     }
 
     fn log(&self, record: &Record) {
+        if self.batch(record) {
+            return;
+        }
+
         match record.level() {
-            Level::Info => println!("{} {}", Cyan.bold().paint("[info]"), record.args()),
-            Level::Warn => eprintln!("{} {}", Yellow.bold().paint("[warn]"), record.args()),
-            Level::Error => eprintln!("{} {}", Red.bold().paint("[err!]"), record.args()),
-            _ => {}
+            Level::Debug => println!("{} {}", self.prefix("[dbug]", Purple), record.args()),
+            Level::Info => println!("{} {}", self.prefix("[info]", Cyan), record.args()),
+            Level::Warn => eprintln!("{} {}", self.prefix("[warn]", Yellow), record.args()),
+            Level::Error => eprintln!("{} {}", self.prefix("[err!]", Red), record.args()),
+            Level::Trace => {}
         }
     }
 
@@ -16,33 +16,44 @@
 // along with muso.  If not, see <http://www.gnu.org/licenses/>.
 
 mod cli;
+mod doctor;
 mod error;
 mod logger;
+#[cfg(feature = "notify-desktop")]
+mod notification;
 
 use std::env;
-use std::path::Path;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
 use std::process;
 use std::str::FromStr;
 
 use clap::Clap;
 use human_panic::setup_panic;
-use muso::config::Config;
-use muso::format::ParsedFormat;
-use muso::sorting::{sort_folder, Options};
+use log::LevelFilter;
+use muso::catalog::{self, CatalogEntry};
+use muso::config::{Config, LibraryConfig, LoggingConfig};
+use muso::format::{ParsedFormat, Tag, TargetFs};
+use muso::sorting::{
+    plan_folder, plan_folder_detailed, plan_script, resume_journal, rollback_journal, sort_folder,
+    Move, Options,
+};
 use muso::utils;
 use muso::watcher::Watcher;
 
-use crate::cli::{CliArgs, SubCommand};
+use crate::cli::{CliArgs, ConfigArgs, ConfigCmd, SortArgs, SubCommand};
 use crate::error::Error;
-use crate::logger::init_logger;
+use crate::logger::{init_logger, set_batch_size, set_style};
 
 pub type AnyResult<T> = std::result::Result<T, anyhow::Error>;
 
 fn load_config(path: impl AsRef<Path>) -> AnyResult<Config> {
     let path = path.as_ref();
-    let default_path = utils::default_config_path();
+    // Custom paths work even without a config folder
+    let is_default = utils::default_config_path().is_ok_and(|default| default == path);
 
-    if path == default_path && !path.exists() {
+    if is_default && !path.exists() {
         cfg_if::cfg_if! {
             if #[cfg(feature = "standalone")] {
                 utils::generate_resource(utils::Resource::Config, Some(include_str!("../share/config.toml")))?;
@@ -55,9 +66,204 @@ fn load_config(path: impl AsRef<Path>) -> AnyResult<Config> {
     Ok(Config::from_path(path)?)
 }
 
+fn build_options(config: &Config, path: &Path, args: SortArgs) -> AnyResult<Options<ParsedFormat>> {
+    let library = match &args.library {
+        Some(name) => Some(
+            config
+                .libraries
+                .get(name)
+                .ok_or_else(|| Error::UnknownLibrary { name: name.clone() })?,
+        ),
+
+        None => config.search_library(path),
+    };
+
+    // The library decides unless a flag says otherwise
+    let mut options = library.map(LibraryConfig::options).unwrap_or_default();
+
+    options.format = args
+        .format
+        .map_or(library.map(|library| library.format.clone()), |s| {
+            ParsedFormat::from_str(&s).ok()
+        })
+        .or_else(|| config.default_format.clone())
+        .unwrap_or_default();
+
+    if let Some(target_fs) = args.target_fs {
+        options.target_fs = target_fs;
+    } else if args.exfat_compat {
+        options.target_fs = TargetFs::ExFat;
+    }
+
+    if !args.clean_leftovers.is_empty() {
+        options.clean_leftovers = args.clean_leftovers;
+    }
+
+    for mapping in &args.ext_map {
+        match mapping.split_once('=') {
+            Some((from, to)) => options.ext_map.insert(from.to_owned(), to.to_owned()),
+            None => {
+                let err = Error::InvalidExtMap {
+                    mapping: mapping.clone(),
+                };
+
+                return Err(err.into());
+            }
+        };
+    }
+
+    options.conflict = args.conflict.unwrap_or(options.conflict);
+    if let Some(policy) = args.missing_tag_policy {
+        options.missing_tag_policy = policy;
+    }
+
+    options.compilation_threshold = args.compilation_threshold.or(options.compilation_threshold);
+    options.each_file_hook = args.each_file_hook.or(options.each_file_hook);
+    options.min_size = args.min_size.or(options.min_size);
+    options.split_artist = args.split_artist.or(options.split_artist);
+    options.device_sentinel = args.device_sentinel.or(options.device_sentinel);
+
+    options.verify |= args.verify;
+    options.collapse_empty_dirs |= args.collapse_empty_dirs;
+    options.prefer_sort_tags |= args.prefer_sort_tags;
+    options.lowercase_ext |= args.lowercase_ext;
+    options.album_mode |= args.album_mode;
+    options.preserve_source_tree |= args.preserve_source_tree;
+    options.follow_symlinks |= args.follow_symlinks;
+    options.skip_conforming |= args.skip_conforming;
+    options.strict_original_year |= args.strict_original_year;
+    options.fix_tags |= args.fix_tags;
+    options.include_hidden |= args.include_hidden;
+
+    // Run settings only come from the flags
+    options.dryrun =
+        args.dryrun || args.diff || args.simulate_conflicts || args.emit_script.is_some();
+    options.recursive = args.recursive;
+    options.remove_empty = args.remove_empty;
+    options.fail_fast = args.fail_fast;
+    options.dest_root = args.dest_root;
+    options.create_root = args.mkdir;
+    options.journal = args.journal;
+
+    Ok(options)
+}
+
+/// Prints the config file used (`-c` or the default one), the service file and the data
+/// folder, along with whether each one exists.
+fn print_paths(config_path: &Path) {
+    let describe = |path: muso::Result<PathBuf>| match path {
+        Ok(path) if path.exists() => format!("{} (found)", path.display()),
+        Ok(path) => format!("{} (missing)", path.display()),
+        Err(e) => e.to_string(),
+    };
+
+    println!("Config file:  {}", describe(Ok(config_path.to_path_buf())));
+    println!("Service file: {}", describe(utils::default_service_path()));
+    println!("Data folder:  {}", describe(utils::default_data_dir()));
+}
+
+fn print_placeholders() {
+    let rows: Vec<_> = Tag::ALL
+        .iter()
+        .map(|tag| {
+            let placeholder = match tag {
+                Tag::Path { .. } => format!("{{{}N}}", tag.name()),
+                _ => format!("{{{}}}", tag.name()),
+            };
+
+            let padding = if tag.is_paddable() {
+                format!("{{{}:n}}", tag.name())
+            } else {
+                "-".to_owned()
+            };
+
+            [
+                placeholder,
+                padding,
+                tag.description().to_owned(),
+                tag.example().to_owned(),
+            ]
+        })
+        .collect();
+
+    let header = ["PLACEHOLDER", "PADDING", "DESCRIPTION", "EXAMPLE"];
+    let widths: Vec<_> = (0..header.len())
+        .map(|i| {
+            rows.iter()
+                .map(|row| row[i].len())
+                .chain([header[i].len()])
+                .max()
+                .unwrap()
+        })
+        .collect();
+
+    for row in std::iter::once(header.map(String::from)).chain(rows) {
+        println!(
+            "{:w0$}  {:w1$}  {:w2$}  {}",
+            row[0],
+            row[1],
+            row[2],
+            row[3],
+            w0 = widths[0],
+            w1 = widths[1],
+            w2 = widths[2]
+        );
+    }
+
+    println!();
+    println!("Placeholders (except {{ext}}) can be made optional with `?`, e.g. {{artist?}} or {{disc:2?}}.");
+    println!("Parts wrapped in brackets, e.g. [{{disc}}.], are omitted when any of their tags is missing.");
+    println!(
+        "An optional {{disc?}} is left empty on single-disc albums, e.g. [{{disc?}}.]{{track}}."
+    );
+    println!("Several artists can be joined with a separator, e.g. {{artist:join(, )}}.");
+}
+
+/// Flags take precedence over the `[logging]` table of the config file.
+fn configure_logger(opts: &CliArgs, logging: &LoggingConfig) {
+    log::set_max_level(
+        opts.log_level
+            .or(logging.level)
+            .unwrap_or(LevelFilter::Info),
+    );
+    set_style(
+        logging.color && !opts.no_color,
+        logging.timestamps || opts.timestamps,
+    );
+}
+
 fn run(opts: CliArgs) -> AnyResult<()> {
-    let config = opts.config.unwrap_or_else(utils::default_config_path);
-    let config = load_config(config)?;
+    // Until the config is loaded, which may fail
+    configure_logger(&opts, &LoggingConfig::default());
+
+    let config_path = match &opts.config {
+        Some(path) => path.clone(),
+        None => utils::default_config_path()?,
+    };
+
+    // Checked before loading the config, as it may be what's broken
+    if let SubCommand::Doctor = opts.cmd {
+        return match doctor::run(&config_path) {
+            0 => Ok(()),
+            failed => Err(Error::ChecksFailed { failed }.into()),
+        };
+    }
+
+    if let SubCommand::Placeholders = opts.cmd {
+        print_placeholders();
+        return Ok(());
+    }
+
+    if let SubCommand::Config(ConfigArgs {
+        cmd: ConfigCmd::Path,
+    }) = opts.cmd
+    {
+        print_paths(&config_path);
+        return Ok(());
+    }
+
+    let config = load_config(config_path)?;
+    configure_logger(&opts, &config.logging);
 
     match opts.cmd {
         SubCommand::CopyService => {
@@ -70,41 +276,166 @@ fn run(opts: CliArgs) -> AnyResult<()> {
             };
         }
 
-        SubCommand::Watch => Watcher::new(config).watch()?,
-
-        SubCommand::Sort {
-            path,
-            format,
-            dryrun,
-            recursive,
-            remove_empty,
-            exfat_compat,
-        } => {
-            let path = path.unwrap_or(env::current_dir()?);
-            let format = format
-                .map_or(config.search_format(&path).cloned(), |s| {
-                    ParsedFormat::from_str(&s).ok()
-                })
-                .unwrap_or_else(|| {
-                    ParsedFormat::from_str("{artist}/{album}/{track} - {title}.{ext}").unwrap()
-                });
-
-            let options = Options {
-                format,
-                dryrun,
-                recursive,
-                exfat_compat,
-                remove_empty,
+        SubCommand::Watch(args) => {
+            if let Some(every) = args.batch_log.or(config.watch.batch_log) {
+                set_batch_size(every);
+            }
+
+            let watcher = Watcher::new(config.clone());
+
+            #[cfg(feature = "notify-desktop")]
+            let watcher = if config.watch.notify_desktop {
+                notification::notify_on_sort(watcher, &config.watch)
+            } else {
+                watcher
+            };
+
+            #[cfg(not(feature = "notify-desktop"))]
+            if config.watch.notify_desktop {
+                log::warn!("Desktop notifications need muso built with feature `notify-desktop`");
+            }
+
+            watcher.watch()?
+        }
+
+        SubCommand::Sort(args) => {
+            let path = match &args.path {
+                Some(path) => path.clone(),
+                None => env::current_dir()?,
             };
 
+            // A missing folder is created when sorting starts (if --mkdir is given)
+            if let Some(into) = &args.dest_root {
+                if !into.is_dir() && (into.exists() || !args.mkdir) {
+                    let err = Error::InvalidDest {
+                        path: into.display().to_string(),
+                    };
+
+                    return Err(err.into());
+                }
+            }
+
+            let simulate_conflicts = args.simulate_conflicts;
+            let diff = args.diff;
+            let emit_script = args.emit_script.clone();
+            let (resume, rollback) = (args.resume, args.rollback);
+            let options = build_options(&config, &path, args)?;
+
+            if let Some(journal) = options.journal.as_ref().filter(|_| resume || rollback) {
+                let report = if rollback {
+                    rollback_journal(journal, &options)?
+                } else {
+                    resume_journal(journal, &options)?
+                };
+
+                log::info!(
+                    "{}: {} done, {} failed",
+                    if rollback { "Rolled back" } else { "Resumed" },
+                    report.success,
+                    report.total - report.success
+                );
+
+                if report.success < report.total {
+                    let err = Error::PartialFailure {
+                        failed: report.total - report.success,
+                    };
+
+                    return Err(err.into());
+                }
+
+                if rollback {
+                    return Ok(());
+                }
+            }
+
             if path.is_dir() {
-                match sort_folder(&path, &path, &options) {
-                    Ok(report) => log::info!(
-                        "Done: {} successful out of {} ({} failed)",
-                        report.success,
-                        report.total,
+                if diff {
+                    // Only the moves matter, not the lines logged for every file
+                    let level = log::max_level();
+                    log::set_max_level(level.min(LevelFilter::Warn));
+                    let planned = plan_folder_detailed(&path, &path, &options);
+                    log::set_max_level(level);
+
+                    // Moves are between resolved paths
+                    let (plan, report) = planned?;
+                    let root = utils::canonicalize_or_given(&path);
+                    for Move { from, to } in &plan {
+                        let from = from.strip_prefix(&root).unwrap_or(from);
+                        let to = to.strip_prefix(&root).unwrap_or(to);
+                        println!("{} -> {}", from.display(), to.display());
+                    }
+
+                    log::info!(
+                        "{} file(s) would move, {} already in place, {} failed",
+                        plan.len(),
+                        report.in_place,
                         report.total - report.success
-                    ),
+                    );
+
+                    return Ok(());
+                }
+
+                if let Some(script) = emit_script {
+                    let plan = plan_folder(&path, &path, &options)?;
+                    fs::write(&script, plan_script(&plan))?;
+
+                    log::info!("Wrote {} move(s) to: \"{}\"", plan.len(), script.display());
+
+                    return Ok(());
+                }
+
+                match sort_folder(&path, &path, &options) {
+                    Ok(report) if simulate_conflicts => {
+                        for (destination, sources) in &report.conflicts {
+                            log::warn!("\"{}\" would be taken by:", destination.display());
+                            for source in sources {
+                                log::warn!("  - \"{}\"", source.display());
+                            }
+                        }
+
+                        if !report.conflicts.is_empty() {
+                            let err = Error::ConflictsFound {
+                                count: report.conflicts.len(),
+                            };
+
+                            return Err(err.into());
+                        }
+
+                        log::info!("No conflicts found");
+                    }
+
+                    Ok(report) if report.total == 0 => {
+                        log::info!(
+                            "Nothing to do, no supported audio files found in: \"{}\"",
+                            path.display()
+                        );
+                    }
+
+                    Ok(report) => {
+                        log::info!(
+                            "Done: {} sorted, {} failed, {} skipped ({} folder(s) removed)",
+                            report.success,
+                            report.total - report.success,
+                            report.skipped,
+                            report.dirs_removed
+                        );
+
+                        if report.collisions > 0 {
+                            log::warn!("Found {} destination collision(s)", report.collisions);
+                        }
+
+                        if report.hook_failures > 0 {
+                            log::warn!("Hook failed for {} file(s)", report.hook_failures);
+                        }
+
+                        if report.success < report.total {
+                            let err = Error::PartialFailure {
+                                failed: report.total - report.success,
+                            };
+
+                            return Err(err.into());
+                        }
+                    }
 
                     Err(e) => return Err(e.into()),
                 }
@@ -117,6 +448,35 @@ fn run(opts: CliArgs) -> AnyResult<()> {
             }
         }
 
+        SubCommand::Catalog(args) => {
+            if !config.libraries.contains_key(&args.library) {
+                return Err(Error::UnknownLibrary { name: args.library }.into());
+            }
+
+            let mut entries = Vec::new();
+            let mut skipped = 0;
+            for (path, metadata) in config.iter_library_files(&args.library) {
+                match metadata {
+                    Ok(metadata) => entries.push(CatalogEntry { path, metadata }),
+                    Err(e) => {
+                        log::debug!("Skipping: \"{}\" ({})", path.display(), e);
+                        skipped += 1;
+                    }
+                }
+            }
+
+            catalog::write_catalog(&entries, args.format, io::stdout().lock())?;
+
+            // Info lines go to stdout, which holds the catalog
+            eprintln!(
+                "Cataloged {} file(s), skipped {} unreadable file(s)",
+                entries.len(),
+                skipped
+            );
+        }
+
+        SubCommand::Doctor | SubCommand::Placeholders | SubCommand::Config(_) => unreachable!(),
+
         #[cfg(feature = "sync")]
         SubCommand::Sync => {}
     }
@@ -124,6 +484,8 @@ fn run(opts: CliArgs) -> AnyResult<()> {
     Ok(())
 }
 
+// `setup_panic!` still names `PanicInfo`, deprecated in recent toolchains
+#[allow(deprecated)]
 fn main() {
     setup_panic!();
     init_logger().unwrap();
@@ -132,7 +494,10 @@ fn main() {
     process::exit(match run(opts) {
         Err(e) => {
             log::error!("{}", e);
-            1
+            match e.downcast_ref() {
+                Some(Error::PartialFailure { .. }) => 2,
+                _ => 1,
+            }
         }
 
         Ok(_) => 0,
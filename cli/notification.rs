@@ -0,0 +1,62 @@
+// Copyright (C) 2020 kevin
+//
+// This file is part of muso.
+//
+// muso is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// muso is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with muso.  If not, see <http://www.gnu.org/licenses/>.
+
+use std::path::Path;
+
+use muso::config::WatchConfig;
+use muso::sorting::SortReport;
+use muso::watcher::Watcher;
+use notify_rust::Notification;
+
+/// Makes the watcher show a desktop notification after each sort.
+pub fn notify_on_sort(watcher: Watcher, config: &WatchConfig) -> Watcher {
+    let title = config.notify_title.clone();
+    let body = config.notify_body.clone();
+
+    watcher.with_handler(move |source, report| {
+        let result = Notification::new()
+            .summary(&render(&title, source, report))
+            .body(&render(&body, source, report))
+            .show();
+
+        if let Err(e) = result {
+            log::error!("Couldn't show notification ({})", e);
+        }
+    })
+}
+
+fn render(template: &str, source: &Path, report: &SortReport) -> String {
+    let source = source
+        .file_name()
+        .map(|name| name.to_string_lossy())
+        .unwrap_or_default();
+
+    let dest = report
+        .new_paths
+        .first()
+        .and_then(|path| path.parent())
+        .map(|parent| parent.display().to_string())
+        .unwrap_or_default();
+
+    template
+        .replace("{success}", &report.success.to_string())
+        .replace("{total}", &report.total.to_string())
+        .replace("{failed}", &(report.total - report.success).to_string())
+        .replace("{skipped}", &report.skipped.to_string())
+        .replace("{source}", &source)
+        .replace("{dest}", &dest)
+}
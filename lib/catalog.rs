@@ -0,0 +1,185 @@
+// Copyright (C) 2020 kevin
+//
+// This file is part of muso.
+//
+// muso is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// muso is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with muso.  If not, see <http://www.gnu.org/licenses/>.
+
+//! Exports of the metadata read from a library, e.g. to load it into a spreadsheet.
+
+use std::io::{self, Write};
+use std::path::PathBuf;
+use std::str::FromStr;
+
+use serde::Serialize;
+
+use crate::metadata::Metadata;
+use crate::{Error, Result};
+
+/// Output format of a catalog.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub enum CatalogFormat {
+    /// An array of objects, one per file.
+    #[default]
+    Json,
+    /// A header row followed by one row per file.
+    Csv,
+}
+
+impl FromStr for CatalogFormat {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "json" => Ok(CatalogFormat::Json),
+            "csv" => Ok(CatalogFormat::Csv),
+            _ => Err(Error::InvalidCatalogFormat { format: s.into() }),
+        }
+    }
+}
+
+/// A file of the library along with the metadata read from it.
+#[derive(Debug, Serialize)]
+pub struct CatalogEntry {
+    pub path: PathBuf,
+    #[serde(flatten)]
+    pub metadata: Metadata,
+}
+
+const CSV_HEADER: &[&str] = &[
+    "path",
+    "artist",
+    "album",
+    "disc",
+    "total_discs",
+    "track",
+    "title",
+    "ext",
+    "track_gain",
+    "disc_subtitle",
+    "artist_sort",
+    "album_sort",
+    "bpm",
+    "year",
+    "original_year",
+    "codec",
+    "cover",
+    "has_lyrics",
+    "sample_rate",
+    "bit_depth",
+    "channels",
+];
+
+/// Writes `entries` to `writer` in the given format. Covers are written as their
+/// dimensions in CSV (e.g. `500x500`, empty if there's no cover or they're unknown).
+pub fn write_catalog(
+    entries: &[CatalogEntry],
+    format: CatalogFormat,
+    mut writer: impl Write,
+) -> Result<()> {
+    match format {
+        CatalogFormat::Json => {
+            serde_json::to_writer_pretty(&mut writer, entries).map_err(io::Error::from)?;
+            writeln!(writer)?;
+        }
+
+        CatalogFormat::Csv => {
+            writeln!(writer, "{}", CSV_HEADER.join(","))?;
+
+            for entry in entries {
+                let row: Vec<_> = csv_fields(entry).iter().map(|f| csv_escape(f)).collect();
+                writeln!(writer, "{}", row.join(","))?;
+            }
+        }
+    }
+
+    Ok(())
+}
+
+fn csv_fields(entry: &CatalogEntry) -> Vec<String> {
+    fn opt<T: ToString>(value: &Option<T>) -> String {
+        value.as_ref().map(T::to_string).unwrap_or_default()
+    }
+
+    let metadata = &entry.metadata;
+    let cover = metadata
+        .cover
+        .and_then(|cover| cover.dimensions)
+        .map(|(width, height)| format!("{}x{}", width, height));
+
+    vec![
+        entry.path.to_string_lossy().into_owned(),
+        opt(&metadata.artist),
+        opt(&metadata.album),
+        opt(&metadata.disc),
+        opt(&metadata.total_discs),
+        opt(&metadata.track),
+        opt(&metadata.title),
+        metadata.ext.clone(),
+        opt(&metadata.track_gain),
+        opt(&metadata.disc_subtitle),
+        opt(&metadata.artist_sort),
+        opt(&metadata.album_sort),
+        opt(&metadata.bpm),
+        opt(&metadata.year),
+        opt(&metadata.original_year),
+        opt(&metadata.codec),
+        opt(&cover),
+        metadata.has_lyrics.to_string(),
+        opt(&metadata.sample_rate),
+        opt(&metadata.bit_depth),
+        opt(&metadata.channels),
+    ]
+}
+
+/// Quotes a field if it contains a comma, a quote or a line break (doubling its quotes).
+fn csv_escape(field: &str) -> String {
+    if field.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field.to_owned()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn csv_rows() -> Result<()> {
+        let entries = vec![CatalogEntry {
+            path: PathBuf::from("Artist/Album, Vol. 1/01.flac"),
+            metadata: Metadata {
+                artist: Some("Artist".into()),
+                title: Some("Say \"Hi\"".into()),
+                track: Some(1),
+                ext: "flac".into(),
+                ..Default::default()
+            },
+        }];
+
+        let mut csv = Vec::new();
+        write_catalog(&entries, CatalogFormat::Csv, &mut csv)?;
+
+        let csv = String::from_utf8(csv).unwrap();
+        let mut lines = csv.lines();
+        assert_eq!(lines.next(), Some(CSV_HEADER.join(",").as_str()));
+        assert_eq!(
+            lines.next(),
+            Some("\"Artist/Album, Vol. 1/01.flac\",Artist,,,,1,\"Say \"\"Hi\"\"\",flac,,,,,,,,,,false,,,")
+        );
+        assert_eq!(lines.next(), None);
+
+        Ok(())
+    }
+}
@@ -16,18 +16,159 @@
 // along with muso.  If not, see <http://www.gnu.org/licenses/>.
 
 use std::collections::{HashMap, HashSet};
+use std::convert::TryFrom;
 use std::fs;
+use std::io::Read;
 use std::path::{Path, PathBuf};
+use std::result::Result as StdResult;
+use std::str::FromStr;
+use std::time::Duration;
 
-use serde::Deserialize;
+use ignore::WalkBuilder;
+use log::LevelFilter;
+use serde::de::{self, Visitor};
+use serde::{Deserialize, Deserializer};
 
-use crate::format::ParsedFormat;
+use crate::format::{ParsedFormat, TargetFs};
+use crate::metadata::{self, Metadata};
+use crate::sorting::{ConflictPolicy, MissingTagPolicy, Options, IGNORE_FILE};
 use crate::{Error, Result};
 
 #[derive(Debug, Clone, Deserialize)]
 pub struct WatchConfig {
-    pub every: Option<u64>,
+    /// Delay used to debounce events, either seconds or a string with a unit suffix
+    /// (`ms`, `s`, `m` or `h`, e.g. `"500ms"`).
+    #[serde(default, deserialize_with = "deserialize_every")]
+    pub every: Option<Duration>,
     pub libraries: Vec<String>,
+
+    /// Gitignore-like patterns (matched against file names) of files the watcher
+    /// shouldn't try to sort, e.g. partial downloads.
+    #[serde(default = "default_watch_ignore")]
+    pub ignore: Vec<String>,
+
+    /// Times a sort failing with an I/O error is retried (with an increasing delay).
+    #[serde(default = "default_watch_retries")]
+    pub retries: u32,
+
+    /// Show a desktop notification after each sort (needs the `notify-desktop` feature).
+    #[serde(rename = "notify-desktop", default)]
+    pub notify_desktop: bool,
+
+    /// Templates for the notification, see `share/config.toml` for the placeholders.
+    #[serde(rename = "notify-title", default = "default_notify_title")]
+    pub notify_title: String,
+
+    #[serde(rename = "notify-body", default = "default_notify_body")]
+    pub notify_body: String,
+
+    /// Log a line every this many files instead of one (or more) per file.
+    #[serde(rename = "batch-log")]
+    pub batch_log: Option<usize>,
+}
+
+struct EveryVisitor;
+
+impl<'d> Visitor<'d> for EveryVisitor {
+    type Value = Duration;
+
+    fn expecting(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(
+            f,
+            "seconds or a duration with a unit (e.g. \"500ms\", \"2s\" or \"1m\")"
+        )
+    }
+
+    fn visit_u64<E>(self, v: u64) -> StdResult<Self::Value, E>
+    where
+        E: de::Error,
+    {
+        Ok(Duration::from_secs(v))
+    }
+
+    fn visit_i64<E>(self, v: i64) -> StdResult<Self::Value, E>
+    where
+        E: de::Error,
+    {
+        u64::try_from(v)
+            .map(Duration::from_secs)
+            .map_err(|_| de::Error::invalid_value(de::Unexpected::Signed(v), &self))
+    }
+
+    fn visit_str<E>(self, v: &str) -> StdResult<Self::Value, E>
+    where
+        E: de::Error,
+    {
+        parse_duration(v).ok_or_else(|| de::Error::invalid_value(de::Unexpected::Str(v), &self))
+    }
+}
+
+fn deserialize_every<'d, D>(deserializer: D) -> StdResult<Option<Duration>, D::Error>
+where
+    D: Deserializer<'d>,
+{
+    deserializer.deserialize_any(EveryVisitor).map(Some)
+}
+
+/// Parses a number followed by an optional unit (`ms`, `s`, `m` or `h`, seconds if
+/// there's none), e.g. `500ms`.
+fn parse_duration(s: &str) -> Option<Duration> {
+    let s = s.trim();
+    let unit_start = s.find(|c: char| !c.is_ascii_digit()).unwrap_or(s.len());
+    let value: u64 = s[..unit_start].parse().ok()?;
+
+    match s[unit_start..].trim() {
+        "ms" => Some(Duration::from_millis(value)),
+        "" | "s" => Some(Duration::from_secs(value)),
+        "m" => Some(Duration::from_secs(value.checked_mul(60)?)),
+        "h" => Some(Duration::from_secs(value.checked_mul(60 * 60)?)),
+        _ => None,
+    }
+}
+
+/// Defaults for the CLI logger, its flags take precedence.
+#[derive(Debug, Clone, Deserialize)]
+pub struct LoggingConfig {
+    /// Most verbose level logged (e.g. `warn` or `debug`), `info` by default.
+    pub level: Option<LevelFilter>,
+
+    /// Color the level of each line.
+    #[serde(default = "default_logging_color")]
+    pub color: bool,
+
+    /// Prefix each line with the local date and time.
+    #[serde(default)]
+    pub timestamps: bool,
+}
+
+impl Default for LoggingConfig {
+    fn default() -> Self {
+        LoggingConfig {
+            level: None,
+            color: default_logging_color(),
+            timestamps: false,
+        }
+    }
+}
+
+fn default_logging_color() -> bool {
+    true
+}
+
+fn default_watch_ignore() -> Vec<String> {
+    vec!["*.part".into(), "*.crdownload".into(), ".*".into()]
+}
+
+fn default_watch_retries() -> u32 {
+    3
+}
+
+fn default_notify_title() -> String {
+    "muso".into()
+}
+
+fn default_notify_body() -> String {
+    "Sorted {success} file(s) into {dest}".into()
 }
 
 #[derive(Debug, Clone, Deserialize)]
@@ -37,58 +178,274 @@ pub struct LibraryConfig {
 
     #[serde(rename = "exfat-compat")]
     pub exfat_compat: Option<bool>,
+
+    /// Takes precedence over `exfat-compat`.
+    #[serde(rename = "target-fs")]
+    pub target_fs: Option<TargetFs>,
+
+    #[serde(rename = "char-map", default)]
+    pub char_map: HashMap<char, String>,
+
+    /// Non-standard vorbis comment keys, along with the standard key each one stands for.
+    #[serde(rename = "vorbis-aliases", default)]
+    pub vorbis_aliases: HashMap<String, String>,
+
+    #[serde(rename = "clean-leftovers", default)]
+    pub clean_leftovers: Vec<String>,
+
+    #[serde(default)]
+    pub conflict: ConflictPolicy,
+
+    #[serde(rename = "missing-tag-policy", default)]
+    pub missing_tag_policy: MissingTagPolicy,
+
+    #[serde(rename = "compilation-threshold")]
+    pub compilation_threshold: Option<usize>,
+
+    #[serde(rename = "dir-mode")]
+    pub dir_mode: Option<u32>,
+
+    #[serde(rename = "each-file-hook")]
+    pub each_file_hook: Option<String>,
+
+    #[serde(default)]
+    pub verify: bool,
+
+    #[serde(rename = "collapse-empty-dirs", default)]
+    pub collapse_empty_dirs: bool,
+
+    #[serde(rename = "prefer-sort-tags", default)]
+    pub prefer_sort_tags: bool,
+
+    #[serde(rename = "min-size")]
+    pub min_size: Option<u64>,
+
+    #[serde(rename = "ext-map", default)]
+    pub ext_map: HashMap<String, String>,
+
+    #[serde(rename = "lowercase-ext", default)]
+    pub lowercase_ext: bool,
+
+    #[serde(rename = "album-mode", default)]
+    pub album_mode: bool,
+
+    #[serde(rename = "watch-recursive")]
+    pub watch_recursive: Option<bool>,
+
+    #[serde(rename = "split-artist")]
+    pub split_artist: Option<String>,
+
+    #[serde(rename = "preserve-source-tree", default)]
+    pub preserve_source_tree: bool,
+
+    #[serde(rename = "follow-symlinks", default)]
+    pub follow_symlinks: bool,
+
+    #[serde(rename = "skip-conforming", default)]
+    pub skip_conforming: bool,
+
+    #[serde(rename = "strict-original-year", default)]
+    pub strict_original_year: bool,
+
+    #[serde(rename = "fix-tags", default)]
+    pub fix_tags: bool,
+
+    #[serde(rename = "include-hidden", default)]
+    pub include_hidden: bool,
+
+    /// File that must be on the same device as the library for it to be sorted.
+    #[serde(rename = "device-sentinel")]
+    pub device_sentinel: Option<PathBuf>,
+}
+
+impl LibraryConfig {
+    /// The `target-fs` of the library, or exFAT if it only enables `exfat-compat`.
+    pub fn target_fs(&self) -> TargetFs {
+        match (self.target_fs, self.exfat_compat) {
+            (Some(target_fs), _) => target_fs,
+            (None, Some(true)) => TargetFs::ExFat,
+            _ => TargetFs::Posix,
+        }
+    }
+
+    /// Sorting options with the settings of the library, the rest (e.g. `dryrun` or
+    /// `recursive`) are left to their defaults.
+    pub fn options(&self) -> Options<ParsedFormat> {
+        Options {
+            format: self.format.clone(),
+            char_map: self.char_map.clone(),
+            clean_leftovers: self.clean_leftovers.clone(),
+            conflict: self.conflict,
+            compilation_threshold: self.compilation_threshold,
+            dir_mode: self.dir_mode,
+            each_file_hook: self.each_file_hook.clone(),
+            verify: self.verify,
+            collapse_empty_dirs: self.collapse_empty_dirs,
+            prefer_sort_tags: self.prefer_sort_tags,
+            min_size: self.min_size,
+            ext_map: self.ext_map.clone(),
+            lowercase_ext: self.lowercase_ext,
+            album_mode: self.album_mode,
+            split_artist: self.split_artist.clone(),
+            preserve_source_tree: self.preserve_source_tree,
+            follow_symlinks: self.follow_symlinks,
+            skip_conforming: self.skip_conforming,
+            missing_tag_policy: self.missing_tag_policy.clone(),
+            strict_original_year: self.strict_original_year,
+            fix_tags: self.fix_tags,
+            include_hidden: self.include_hidden,
+            target_fs: self.target_fs(),
+            vorbis_aliases: self.vorbis_aliases.clone(),
+            device_sentinel: self.device_sentinel.clone(),
+            ..Default::default()
+        }
+    }
 }
 
 #[derive(Debug, Clone, Deserialize)]
 pub struct Config {
     pub watch: WatchConfig,
     pub libraries: HashMap<String, LibraryConfig>,
+
+    #[serde(default)]
+    pub logging: LoggingConfig,
+
+    #[serde(rename = "default-format")]
+    pub default_format: Option<ParsedFormat>,
+
+    /// Folder that relative library folders are resolved against, defaults to the
+    /// config file's directory.
+    pub base: Option<PathBuf>,
+}
+
+impl FromStr for Config {
+    type Err = Error;
+
+    /// Parses a config held in memory, relative folders are resolved against the
+    /// current directory (or `base`).
+    fn from_str(s: &str) -> Result<Self> {
+        let (config, warnings) = Self::parse(s, &std::env::current_dir()?)?;
+        Ok(Self::log_warnings(config, warnings))
+    }
 }
 
 impl Config {
     pub fn from_path(path: impl AsRef<Path>) -> Result<Self> {
+        let (config, warnings) = Self::from_path_with_warnings(path)?;
+        Ok(Self::log_warnings(config, warnings))
+    }
+
+    /// Reads a config from any reader, relative folders are resolved against the
+    /// current directory (or `base`).
+    pub fn from_reader(mut reader: impl Read) -> Result<Self> {
+        let mut contents = String::new();
+        reader.read_to_string(&mut contents)?;
+
+        contents.parse()
+    }
+
+    fn log_warnings(config: Self, warnings: Vec<String>) -> Self {
+        for warning in warnings {
+            log::warn!("{}", warning);
+        }
+
+        config
+    }
+
+    /// Same as [`Config::from_path`] but returns the warnings found while sanitizing
+    /// library folders (e.g. ignored folders) instead of logging them.
+    pub fn from_path_with_warnings(path: impl AsRef<Path>) -> Result<(Self, Vec<String>)> {
         let path = path.as_ref();
         let contents = fs::read_to_string(path)?;
 
-        let mut config: Self = toml::from_str(&contents).map_err(|e| Error::InvalidConfig {
+        let config_dir = match path.parent() {
+            Some(parent) if !parent.as_os_str().is_empty() => parent,
+            _ => Path::new("."),
+        };
+
+        Self::parse(&contents, &fs::canonicalize(config_dir)?)
+    }
+
+    fn parse(contents: &str, config_dir: &Path) -> Result<(Self, Vec<String>)> {
+        let mut config: Self = toml::from_str(contents).map_err(|e| Error::InvalidConfig {
             reason: e.to_string(),
         })?;
 
-        config.sanitize_folders()?;
+        let warnings = config.sanitize_folders(config_dir)?;
+        config.check_char_maps()?;
 
-        Ok(config)
+        Ok((config, warnings))
     }
 
-    fn sanitize_folders(&mut self) -> Result<()> {
+    /// Mapping a character to `.` or `..` could turn a whole name into a relative path.
+    fn check_char_maps(&self) -> Result<()> {
+        for (name, library) in &self.libraries {
+            for (c, mapped) in &library.char_map {
+                if mapped == "." || mapped == ".." {
+                    return Err(Error::InvalidConfig {
+                        reason: format!(
+                            "Library \"{}\" maps '{}' to \"{}\" in its char-map",
+                            name, c, mapped
+                        ),
+                    });
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    fn sanitize_folders(&mut self, config_dir: &Path) -> Result<Vec<String>> {
         let mut seen_folders = HashSet::new();
+        let mut warnings = Vec::new();
+
+        let base = match &self.base {
+            Some(base) => match expand_path(base) {
+                Ok(base) => config_dir.join(base),
+                Err(e) => {
+                    return Err(Error::InvalidConfig {
+                        reason: format!("Invalid base path: {}", e),
+                    });
+                }
+            },
+
+            None => config_dir.to_path_buf(),
+        };
 
         for (name, library) in &mut self.libraries {
             let mut sanitized: Vec<PathBuf> = Vec::new();
 
             for folder in library.folders.drain(..) {
-                let folder = if let Some(folder_str) = folder.as_os_str().to_str() {
-                    match shellexpand::full(folder_str) {
-                        Ok(full) => Path::new(full.as_ref()).to_path_buf(),
-
-                        Err(e) => {
-                            log::warn!(
-                                "Library \"{}\" contains an invalid path: {} (ignoring)",
-                                name,
-                                e
-                            );
-                            continue;
-                        }
+                let folder = match expand_path(&folder) {
+                    Ok(folder) => folder,
+                    Err(e) => {
+                        warnings.push(format!(
+                            "Library \"{}\" contains an invalid path: {} (ignoring)",
+                            name, e
+                        ));
+                        continue;
                     }
+                };
+
+                let folder = if folder.is_relative() {
+                    let resolved = base.join(&folder);
+                    log::info!(
+                        "Library \"{}\" folder \"{}\" resolved to: {}",
+                        name,
+                        folder.display(),
+                        resolved.display()
+                    );
+                    resolved
                 } else {
                     folder
                 };
 
                 if !folder.exists() || !folder.is_absolute() {
-                    log::warn!(
+                    warnings.push(format!(
                         "Library \"{}\" contains an invalid path: {} (ignoring)",
                         name,
                         folder.display()
-                    );
+                    ));
                 } else if seen_folders.contains(&folder) {
                     log::error!(
                         "Library \"{}\" contains a repeated folder: {}",
@@ -106,31 +463,106 @@ impl Config {
             }
 
             library.folders = sanitized;
+
+            // Unlike folders, a missing sentinel is kept (it's what the check is for)
+            if let Some(sentinel) = library.device_sentinel.take() {
+                let sentinel = expand_path(&sentinel).map_err(|e| Error::InvalidConfig {
+                    reason: format!("Invalid device sentinel in library \"{}\": {}", name, e),
+                })?;
+
+                library.device_sentinel = Some(base.join(sentinel));
+            }
         }
 
-        Ok(())
+        Ok(warnings)
+    }
+
+    /// Returns the library owning `path`, see [`Config::library_for`].
+    pub fn search_library(&self, path: impl AsRef<Path>) -> Option<&LibraryConfig> {
+        self.library_for(path)
+            .and_then(|library| self.libraries.get(library))
     }
 
+    /// Returns the name of the library owning `path`, that is, the one with the nearest
+    /// folder among `path` and its ancestors.
+    pub fn library_for(&self, path: impl AsRef<Path>) -> Option<&str> {
+        path.as_ref().ancestors().find_map(|ancestor| {
+            self.libraries
+                .iter()
+                .find(|(_, library)| library.folders.iter().any(|folder| folder == ancestor))
+                .map(|(name, _)| name.as_str())
+        })
+    }
+
+    /// Returns the format of the library owning `path`, which may be any descendant of
+    /// the library folders.
     pub fn search_format(&self, path: impl AsRef<Path>) -> Option<&ParsedFormat> {
-        let path = path.as_ref().to_path_buf();
-        for library in self.libraries.values() {
-            if library.folders.contains(&path) {
-                return Some(&library.format);
-            }
-        }
+        self.search_library(path).map(|library| &library.format)
+    }
+
+    /// Lazily walks the folders of `library` (nothing if there's no such library), yielding
+    /// every supported file along with its metadata (read with the library's vorbis
+    /// aliases). Files excluded by `.musoignore` files
+    /// (and hidden ones, unless the library includes them) are left out, same as when
+    /// sorting.
+    pub fn iter_library_files(
+        &self,
+        library: &str,
+    ) -> impl Iterator<Item = (PathBuf, Result<Metadata>)> + '_ {
+        let library = self.libraries.get(library);
+        let folders = library
+            .map(|library| library.folders.as_slice())
+            .unwrap_or_default();
 
-        None
+        let include_hidden = matches!(library, Some(library) if library.include_hidden);
+        let aliases = library
+            .map(|library| library.vorbis_aliases.clone())
+            .unwrap_or_default();
+
+        folders
+            .iter()
+            .flat_map(move |folder| {
+                WalkBuilder::new(folder)
+                    .standard_filters(false)
+                    .hidden(!include_hidden)
+                    .add_custom_ignore_filename(IGNORE_FILE)
+                    .build()
+            })
+            .filter_map(|entry| match entry {
+                Ok(entry) => Some(entry),
+                Err(e) => {
+                    log::error!("{}", e);
+                    None
+                }
+            })
+            .filter(|entry| matches!(entry.file_type(), Some(t) if t.is_file()))
+            .map(|entry| entry.into_path())
+            .filter(|path| metadata::is_supported_path(path))
+            .map(move |path| {
+                let metadata = Metadata::from_path_with_aliases(&path, &aliases);
+                (path, metadata)
+            })
     }
 
     pub fn format_of(&self, library: &str) -> Option<&ParsedFormat> {
         self.libraries.get(library).map(|library| &library.format)
     }
 
-    pub fn is_exfat_compat(&self, library: &str) -> bool {
+    pub fn is_watch_recursive(&self, library: &str) -> bool {
         self.libraries
             .get(library)
-            .map(|library| library.exfat_compat)
-            .flatten()
-            .unwrap_or(false)
+            .and_then(|library| library.watch_recursive)
+            .unwrap_or(true)
+    }
+}
+
+/// Expands environment variables and `~` in `path`, leaving non UTF-8 paths untouched.
+fn expand_path(path: &Path) -> std::result::Result<PathBuf, String> {
+    match path.to_str() {
+        Some(path_str) => shellexpand::full(path_str)
+            .map(|full| PathBuf::from(full.as_ref()))
+            .map_err(|e| e.to_string()),
+
+        None => Ok(path.to_path_buf()),
     }
 }
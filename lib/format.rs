@@ -17,19 +17,137 @@
 
 mod parser;
 
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
 use std::result::Result as StdResult;
-use std::{path::PathBuf, str::FromStr};
+use std::str::FromStr;
 
 use serde::de::{self, Visitor};
 use serde::{Deserialize, Deserializer, Serialize, Serializer};
 
 use self::parser::parse_format_string;
 use self::parser::{BasicComponent, FsComponent};
-use self::parser::{Placeholder, Tag};
 
-use crate::metadata::Metadata;
+pub use self::parser::{Placeholder, Separator, Tag};
+
+use crate::metadata::{self, Metadata};
 use crate::{Error, Result};
 
+/// Format used when neither the user nor the config file provide one.
+pub const DEFAULT_FORMAT: &str = "{artist}/{album}/{track} - {title}.{ext}";
+
+/// Filesystem the sorted files end up in, which decides the characters and names that
+/// must be avoided in the built paths.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum TargetFs {
+    /// Only `/` is forbidden (`.` is replaced on every filesystem).
+    #[default]
+    Posix,
+    /// FAT32 with long names, forbids `"*:<>\?|` and control characters, and reserves
+    /// Windows device names (e.g. `CON`).
+    Vfat,
+    /// NTFS as seen from Windows, same rules as `Vfat`.
+    Ntfs,
+    /// Forbids the same characters as `Vfat`, but reserves no names.
+    ExFat,
+}
+
+impl TargetFs {
+    /// Whether `c` can't be part of a file name on this filesystem.
+    fn forbids(self, c: char) -> bool {
+        match self {
+            TargetFs::Posix => matches!(c, '/' | '.'),
+            TargetFs::Vfat | TargetFs::Ntfs | TargetFs::ExFat => {
+                c.is_ascii_control()
+                    || matches!(
+                        c,
+                        '/' | '"' | '*' | ':' | '<' | '>' | '\\' | '?' | '|' | '.'
+                    )
+            }
+        }
+    }
+
+    /// Whether `name` is (with or without an extension) a device name reserved by
+    /// Windows on this filesystem, e.g. `CON` or `nul.txt`.
+    fn reserves(self, name: &str) -> bool {
+        if !matches!(self, TargetFs::Vfat | TargetFs::Ntfs) {
+            return false;
+        }
+
+        let stem = name.split('.').next().unwrap_or(name).trim_end();
+        let stem = stem.to_ascii_uppercase();
+
+        match stem.as_bytes() {
+            [b'C', b'O', b'M', b'1'..=b'9'] | [b'L', b'P', b'T', b'1'..=b'9'] => true,
+            _ => matches!(stem.as_str(), "CON" | "PRN" | "AUX" | "NUL"),
+        }
+    }
+}
+
+impl FromStr for TargetFs {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "posix" => Ok(TargetFs::Posix),
+            "vfat" => Ok(TargetFs::Vfat),
+            "ntfs" => Ok(TargetFs::Ntfs),
+            "exfat" => Ok(TargetFs::ExFat),
+            _ => Err(Error::InvalidTargetFs { fs: s.into() }),
+        }
+    }
+}
+
+/// Options that tweak how a path is built from a [`ParsedFormat`].
+#[derive(Debug, Clone, Copy, Default)]
+pub struct FormatOptions<'a> {
+    /// Filesystem whose forbidden characters (and reserved names) are avoided.
+    pub target_fs: TargetFs,
+
+    /// Custom replacements for characters found in tag values, consulted before the
+    /// default replacement.
+    pub char_map: Option<&'a HashMap<char, String>>,
+
+    /// Original path of the file (relative to the sorted folder), used by `{pathN}`.
+    pub source_path: Option<&'a Path>,
+
+    /// Omit directories whose placeholders are all optional and missing, instead of
+    /// failing with [`Error::OptionalInDir`].
+    pub collapse_empty_dirs: bool,
+
+    /// Use the sort variants of artist and album (e.g. `Beatles, The`) when present.
+    pub prefer_sort_tags: bool,
+
+    /// Preferred extensions for `{ext}` (e.g. `ogg` to `oga`), this is only cosmetic as
+    /// the file contents are left untouched.
+    pub ext_map: Option<&'a HashMap<String, String>>,
+
+    /// Lowercase `{ext}` (e.g. `MP3` to `mp3`), applied before `ext_map`.
+    pub lowercase_ext: bool,
+
+    /// Separator of multiple artists, `{artist}` in directories keeps only the first one
+    /// (file names keep them all).
+    pub split_artist: Option<&'a str>,
+
+    /// Literal used for missing required tags, instead of failing with
+    /// [`Error::MissingTag`]. Conditional groups are still omitted.
+    pub missing_tag: Option<&'a str>,
+
+    /// Don't fall back to the release year when `{originalyear}` is missing.
+    pub strict_original_year: bool,
+}
+
+/// A built path along with some details about how it was built.
+#[derive(Debug, Clone, PartialEq)]
+pub struct BuildOutcome {
+    pub path: PathBuf,
+
+    /// Optional placeholders (including the ones inside conditional groups) that were
+    /// left empty because their tag was missing.
+    pub empty_optionals: Vec<Tag>,
+}
+
 #[derive(Debug, Clone)]
 pub struct ParsedFormat {
     fs_components: Vec<FsComponent>,
@@ -40,7 +158,7 @@ impl FromStr for ParsedFormat {
     type Err = Error;
 
     fn from_str(s: &str) -> Result<Self> {
-        let basic_components = parse_format_string(s)?;
+        let basic_components = Self::expand_vars(parse_format_string(s)?)?;
 
         let mut fs_component = Vec::new();
         let mut fs_components = Vec::new();
@@ -64,6 +182,14 @@ impl FromStr for ParsedFormat {
                     }
                 }
 
+                BasicComponent::Optional(group) => {
+                    if Self::has_separator(&group) {
+                        return Err(Error::SeparatorInGroup);
+                    }
+
+                    fs_component.push(BasicComponent::Optional(group));
+                }
+
                 placeholder => fs_component.push(placeholder),
             }
         }
@@ -79,6 +205,13 @@ impl FromStr for ParsedFormat {
     }
 }
 
+impl Default for ParsedFormat {
+    /// The [`DEFAULT_FORMAT`].
+    fn default() -> Self {
+        Self::from_str(DEFAULT_FORMAT).unwrap()
+    }
+}
+
 struct ParsedFormatVisitor;
 
 impl<'d> Visitor<'d> for ParsedFormatVisitor {
@@ -115,31 +248,207 @@ impl Serialize for ParsedFormat {
 }
 
 impl ParsedFormat {
-    pub fn build_path(&self, metadata: &Metadata, exfat_compat: bool) -> Result<PathBuf> {
+    /// All the placeholders of the format in order, including those inside conditional
+    /// groups.
+    pub fn placeholders(&self) -> Vec<Placeholder> {
+        fn flatten(components: &[BasicComponent], placeholders: &mut Vec<Placeholder>) {
+            for component in components {
+                match component {
+                    BasicComponent::String(_) => {}
+                    BasicComponent::Placeholder(p) => placeholders.push(*p),
+                    BasicComponent::Optional(group) => flatten(group, placeholders),
+                }
+            }
+        }
+
+        let mut placeholders = Vec::new();
+        for fs_component in &self.fs_components {
+            match fs_component {
+                FsComponent::Dir(components) | FsComponent::File(components) => {
+                    flatten(components, &mut placeholders)
+                }
+            }
+        }
+
+        placeholders
+    }
+
+    /// Tags a file must have to be sorted with this format (without duplicates), that is
+    /// those of required placeholders outside of conditional groups.
+    pub fn required_tags(&self) -> Vec<Tag> {
+        let mut tags = Vec::new();
+
+        for fs_component in &self.fs_components {
+            let components = match fs_component {
+                FsComponent::Dir(components) | FsComponent::File(components) => components,
+            };
+
+            for component in components {
+                if let BasicComponent::Placeholder(Placeholder::Required(tag)) = component {
+                    if !tags.contains(tag) {
+                        tags.push(*tag);
+                    }
+                }
+            }
+        }
+
+        tags
+    }
+
+    /// Checks upfront the rules that would otherwise only fail when building a path, i.e.
+    /// optionals in directories and the required placeholder of the file name.
+    pub fn validate(&self, collapse_empty_dirs: bool) -> Result<()> {
+        for component in &self.fs_components {
+            match component {
+                FsComponent::Dir(dir) => {
+                    let placeholders: Vec<_> = dir
+                        .iter()
+                        .filter_map(|component| match component {
+                            BasicComponent::Placeholder(p) => Some(p),
+                            _ => None,
+                        })
+                        .collect();
+
+                    // Only directories made of optionals can be collapsed
+                    let optionals = placeholders.iter().filter(|p| p.is_optional()).count();
+                    if optionals > 0 && (!collapse_empty_dirs || optionals < placeholders.len()) {
+                        return Err(Error::OptionalInDir);
+                    }
+                }
+
+                FsComponent::File(file) => {
+                    let has_required = file.iter().any(|component| {
+                        matches!(component, BasicComponent::Placeholder(p) if !p.is_optional() && !p.is_tag(Tag::Ext))
+                    });
+
+                    if !has_required {
+                        return Err(Error::RequiredInFile);
+                    }
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    pub fn build_path(&self, metadata: &Metadata, options: &FormatOptions) -> Result<PathBuf> {
+        self.build_path_detailed(metadata, options)
+            .map(|outcome| outcome.path)
+    }
+
+    pub fn build_path_detailed(
+        &self,
+        metadata: &Metadata,
+        options: &FormatOptions,
+    ) -> Result<BuildOutcome> {
+        self.build(metadata, options, true)
+    }
+
+    /// Builds only the directories of the path (e.g. the album folder), the file name
+    /// isn't rendered so its placeholders don't need to be present.
+    pub fn build_dir(&self, metadata: &Metadata, options: &FormatOptions) -> Result<PathBuf> {
+        self.build(metadata, options, false)
+            .map(|outcome| outcome.path)
+    }
+
+    fn build(
+        &self,
+        metadata: &Metadata,
+        options: &FormatOptions,
+        with_file: bool,
+    ) -> Result<BuildOutcome> {
         let mut path = String::with_capacity(128);
+        let mut empty_optionals = Vec::new();
 
         for fs_component in &self.fs_components {
             match fs_component {
                 FsComponent::Dir(dir) => {
+                    // Tag values are kept apart (as `true`) until the end of the whole
+                    // component is trimmed, only then are their characters replaced
+                    let mut parts: Vec<(String, bool)> = Vec::new();
+                    let (mut placeholders, mut missing) = (0, 0);
+                    let mut from_tags = false;
+
                     for component in dir {
                         match component {
                             BasicComponent::String(s) => {
-                                path.push_str(s);
+                                parts.push((s.clone(), false));
                             }
 
                             BasicComponent::Placeholder(p) => {
-                                let s = Self::get_from_metadata(metadata, *p)?
-                                    .ok_or(Error::OptionalInDir)?;
+                                placeholders += 1;
+
+                                match Self::get_from_metadata(metadata, *p, options)? {
+                                    Some(s) => {
+                                        parts.push((s, true));
+                                        from_tags = true;
+                                    }
+
+                                    None => {
+                                        missing += 1;
+                                        empty_optionals.push(p.into_tag());
+                                    }
+                                }
+                            }
 
-                                path.push_str(&Self::replace(s, exfat_compat));
+                            BasicComponent::Optional(group) => {
+                                if let Some(s) = Self::build_group(
+                                    metadata,
+                                    group,
+                                    options,
+                                    &mut empty_optionals,
+                                )? {
+                                    parts.push((s, false));
+                                    from_tags = true;
+                                }
                             }
                         }
                     }
 
+                    if missing > 0 {
+                        // Only a directory left without any of its placeholders can go away
+                        if !options.collapse_empty_dirs || missing < placeholders {
+                            return Err(Error::OptionalInDir);
+                        }
+
+                        continue;
+                    }
+
+                    while let Some((last, _)) = parts.last_mut() {
+                        last.truncate(Self::trim_dir_name(last).len());
+                        if !last.is_empty() {
+                            break;
+                        }
+
+                        parts.pop();
+                    }
+
+                    let built: String = parts
+                        .into_iter()
+                        .map(|(part, is_value)| match is_value {
+                            true => Self::replace(part, options),
+                            false => part,
+                        })
+                        .collect();
+
+                    // Literal only directories are kept (e.g. the leading root of `$VAR`)
+                    match Self::trim_dir_name(&built) {
+                        trimmed if from_tags && Self::is_blank_name(trimmed) => path.push('_'),
+                        trimmed => path.push_str(&Self::avoid_reserved(trimmed, options)),
+                    }
+
                     path.push('/');
                 }
 
+                FsComponent::File(_) if !with_file => break,
+
                 FsComponent::File(file) => {
+                    let options = &FormatOptions {
+                        split_artist: None,
+                        ..*options
+                    };
+
+                    let start = path.len();
                     let mut required_founds = 0;
                     for component in file {
                         match component {
@@ -152,8 +461,20 @@ impl ParsedFormat {
                                     required_founds += 1;
                                 }
 
-                                if let Some(s) = Self::get_from_metadata(metadata, *p)? {
-                                    path.push_str(&Self::replace(s, exfat_compat));
+                                match Self::get_from_metadata(metadata, *p, options)? {
+                                    Some(s) => path.push_str(&Self::replace(s, options)),
+                                    None => empty_optionals.push(p.into_tag()),
+                                }
+                            }
+
+                            BasicComponent::Optional(group) => {
+                                if let Some(s) = Self::build_group(
+                                    metadata,
+                                    group,
+                                    options,
+                                    &mut empty_optionals,
+                                )? {
+                                    path.push_str(&s);
                                 }
                             }
                         }
@@ -162,66 +483,411 @@ impl ParsedFormat {
                     if required_founds < 1 {
                         return Err(Error::RequiredInFile);
                     }
+
+                    let name = match &path[start..] {
+                        name if Self::is_blank_name(name) => "_".to_owned(),
+                        name => Self::avoid_reserved(name, options),
+                    };
+
+                    path.replace_range(start.., &name);
+                }
+            }
+        }
+
+        Ok(BuildOutcome {
+            path: PathBuf::from(path),
+            empty_optionals,
+        })
+    }
+
+    /// Renders a conditional group, returning `None` (so nothing is emitted, not even its
+    /// literals) if any required placeholder inside it is missing, or if all of its
+    /// placeholders are optional and left empty.
+    fn build_group(
+        metadata: &Metadata,
+        group: &[BasicComponent],
+        options: &FormatOptions,
+        empty_optionals: &mut Vec<Tag>,
+    ) -> Result<Option<String>> {
+        // A group is emitted only if its tags are actually there
+        let options = &FormatOptions {
+            missing_tag: None,
+            ..*options
+        };
+
+        let mut built = String::new();
+        let (mut placeholders, mut empty) = (0, 0);
+
+        for component in group {
+            match component {
+                BasicComponent::String(s) => {
+                    built.push_str(s);
+                }
+
+                BasicComponent::Placeholder(p) => {
+                    placeholders += 1;
+
+                    match Self::get_from_metadata(metadata, *p, options) {
+                        Ok(Some(s)) => built.push_str(&Self::replace(s, options)),
+                        Ok(None) => {
+                            empty += 1;
+                            empty_optionals.push(p.into_tag());
+                        }
+                        Err(Error::MissingTag { .. }) => {
+                            empty_optionals.push(p.into_tag());
+                            return Ok(None);
+                        }
+                        Err(e) => return Err(e),
+                    }
+                }
+
+                BasicComponent::Optional(group) => {
+                    if let Some(s) = Self::build_group(metadata, group, options, empty_optionals)? {
+                        built.push_str(&s);
+                    }
+                }
+            }
+        }
+
+        if placeholders > 0 && empty == placeholders {
+            return Ok(None);
+        }
+
+        Ok(Some(built))
+    }
+
+    /// Treats the format as a pattern and pulls tag values out of an existing path
+    /// (relative to the folder the format applies to). Literals act as delimiters and
+    /// placeholders match as few characters as possible, except that `{disc}` and `{track}`
+    /// only match digits and `{ext}` never matches a dot.
+    pub fn extract(&self, path: &Path) -> Option<Metadata> {
+        let parts: Vec<&str> = path
+            .iter()
+            .map(|part| part.to_str())
+            .collect::<Option<_>>()?;
+
+        if parts.len() != self.fs_components.len() {
+            return None;
+        }
+
+        let mut metadata = Metadata::default();
+
+        for (fs_component, part) in self.fs_components.iter().zip(parts) {
+            let components: Vec<_> = match fs_component {
+                FsComponent::Dir(components) | FsComponent::File(components) => {
+                    components.iter().collect()
+                }
+            };
+
+            let mut captures = Vec::new();
+            if !Self::match_components(&components, part, &mut captures) {
+                return None;
+            }
+
+            for (tag, value) in captures {
+                Self::set_in_metadata(&mut metadata, tag, value)?;
+            }
+        }
+
+        Some(metadata)
+    }
+
+    /// Backtracking matcher used by [`ParsedFormat::extract`], on failure `captures` is
+    /// left as it was.
+    fn match_components(
+        pattern: &[&BasicComponent],
+        input: &str,
+        captures: &mut Vec<(Tag, String)>,
+    ) -> bool {
+        let (first, rest) = match pattern.split_first() {
+            Some(split) => split,
+            None => return input.is_empty(),
+        };
+
+        match first {
+            BasicComponent::String(s) => match input.strip_prefix(s.as_str()) {
+                Some(input) => Self::match_components(rest, input, captures),
+                None => false,
+            },
+
+            BasicComponent::Optional(group) => {
+                let with_group: Vec<_> = group.iter().chain(rest.iter().copied()).collect();
+
+                let len = captures.len();
+                if Self::match_components(&with_group, input, captures) {
+                    return true;
+                }
+
+                captures.truncate(len);
+                Self::match_components(rest, input, captures)
+            }
+
+            BasicComponent::Placeholder(p) => {
+                if p.is_optional() && Self::match_components(rest, input, captures) {
+                    return true;
+                }
+
+                let tag = p.into_tag();
+                for (start, c) in input.char_indices() {
+                    let end = start + c.len_utf8();
+                    let accepted = match tag {
+                        Tag::Disc { .. }
+                        | Tag::Track { .. }
+                        | Tag::Bpm { .. }
+                        | Tag::SampleRate
+                        | Tag::BitDepth
+                        | Tag::Channels
+                        | Tag::Year
+                        | Tag::OriginalYear => c.is_ascii_digit(),
+                        Tag::Ext => c != '.',
+                        _ => true,
+                    };
+
+                    // A longer value would contain this same character
+                    if !accepted {
+                        break;
+                    }
+
+                    captures.push((tag, input[..end].to_string()));
+                    if Self::match_components(rest, &input[end..], captures) {
+                        return true;
+                    }
+
+                    captures.pop();
                 }
+
+                false
             }
         }
+    }
 
-        Ok(PathBuf::from(path))
+    /// Returns `None` if the tag was already set to a different value.
+    fn set_in_metadata(metadata: &mut Metadata, tag: Tag, value: String) -> Option<()> {
+        fn set<T: PartialEq>(field: &mut Option<T>, value: T) -> Option<()> {
+            match field {
+                Some(old) if *old != value => None,
+                _ => {
+                    *field = Some(value);
+                    Some(())
+                }
+            }
+        }
+
+        match tag {
+            Tag::Artist | Tag::Artists { .. } => set(&mut metadata.artist, value),
+            Tag::Album => set(&mut metadata.album, value),
+            Tag::Disc { .. } => set(&mut metadata.disc, value.parse().ok()?),
+            Tag::Track { .. } => set(&mut metadata.track, value.parse().ok()?),
+            Tag::Bpm { .. } => set(&mut metadata.bpm, value.parse().ok()?),
+            Tag::SampleRate => set(&mut metadata.sample_rate, value.parse().ok()?),
+            Tag::BitDepth => set(&mut metadata.bit_depth, value.parse().ok()?),
+            Tag::Channels => set(&mut metadata.channels, value.parse().ok()?),
+            Tag::Year => set(&mut metadata.year, value.parse().ok()?),
+            Tag::OriginalYear => set(&mut metadata.original_year, value.parse().ok()?),
+            Tag::Codec => set(&mut metadata.codec, value),
+            Tag::Title => set(&mut metadata.title, value),
+            Tag::DiscSubtitle => set(&mut metadata.disc_subtitle, value),
+            Tag::Ext => {
+                metadata.ext = value;
+                Some(())
+            }
+
+            // The bucket can't be turned back into a gain, and neither the original path
+            // nor the cover art are part of the name
+            Tag::Loudness | Tag::Path { .. } | Tag::HasArt => Some(()),
+            Tag::HasLyrics => {
+                metadata.has_lyrics = value == "lyrics";
+                Some(())
+            }
+        }
+    }
+
+    /// Expands `$VAR` and `${VAR}` in literals (never in placeholders), `$$` stands for a
+    /// literal `$`.
+    fn expand_vars(components: Vec<BasicComponent>) -> Result<Vec<BasicComponent>> {
+        components
+            .into_iter()
+            .map(|component| match component {
+                BasicComponent::String(s) => {
+                    let expanded = s
+                        .split("$$")
+                        .map(|part| {
+                            shellexpand::env(part)
+                                .map(|part| part.into_owned())
+                                .map_err(|e| Error::UndefinedVariable { name: e.var_name })
+                        })
+                        .collect::<Result<Vec<_>>>()?;
+
+                    Ok(BasicComponent::String(expanded.join("$")))
+                }
+
+                BasicComponent::Optional(group) => {
+                    Ok(BasicComponent::Optional(Self::expand_vars(group)?))
+                }
+
+                placeholder => Ok(placeholder),
+            })
+            .collect()
+    }
+
+    fn has_separator(group: &[BasicComponent]) -> bool {
+        group.iter().any(|component| match component {
+            BasicComponent::String(s) => s.contains('/'),
+            BasicComponent::Optional(group) => Self::has_separator(group),
+            BasicComponent::Placeholder(_) => false,
+        })
     }
 
-    fn replace(string: String, exfat_compat: bool) -> String {
-        if exfat_compat {
-            string
-                .replace('/', "_")
-                .replace('"', "_")
-                .replace('*', "_")
-                .replace(':', "_")
-                .replace('<', "_")
-                .replace('>', "_")
-                .replace('\\', "_")
-                .replace('?', "_")
-                .replace('|', "_")
-                .replace('.', "_")
-        } else {
-            string.replace('/', "_").replace('.', "_")
+    fn replace(string: String, options: &FormatOptions) -> String {
+        let mut replaced = String::with_capacity(string.len());
+        let sanitize = |c: char| match options.target_fs.forbids(c) {
+            true => '_',
+            false => c,
+        };
+
+        for c in string.chars() {
+            match options.char_map.and_then(|map| map.get(&c)) {
+                // Mapped values follow the same rules, so they can't introduce a new
+                // directory level or a character the filesystem forbids
+                Some(mapped) => replaced.extend(mapped.chars().map(sanitize)),
+                None => replaced.push(sanitize(c)),
+            }
+        }
+
+        replaced
+    }
+
+    /// Windows (and some network shares) silently strip trailing dots and spaces from
+    /// directory names, so they're never produced.
+    fn trim_dir_name(name: &str) -> &str {
+        name.trim_end_matches(['.', ' '])
+    }
+
+    /// Names that are empty or made only of dots and whitespace (e.g. `..`) would point
+    /// elsewhere or be invisible, so they're replaced by `_`.
+    fn is_blank_name(name: &str) -> bool {
+        name.chars().all(|c| c == '.' || c.is_whitespace())
+    }
+
+    /// Reserved names (e.g. `CON.flac`) get an underscore right before the extension.
+    fn avoid_reserved(name: &str, options: &FormatOptions) -> String {
+        let mut name = name.to_owned();
+        if options.target_fs.reserves(&name) {
+            let stem_len = name.find('.').unwrap_or(name.len());
+            name.insert(stem_len, '_');
+        }
+
+        name
+    }
+
+    /// Pads a number with zeros up to `leading` digits, zeros it already had are dropped
+    /// first so they're never doubled. Anything that isn't a number is left as it is.
+    fn add_leading_zeros(string: String, leading: u8, name: &str) -> String {
+        let number: u32 = match string.parse() {
+            Ok(number) => number,
+            Err(_) => {
+                log::warn!("{} \"{}\" isn't a number, it won't be padded", name, string);
+                return string;
+            }
+        };
+
+        let padded = format!("{:0width$}", number, width = leading as usize);
+        if leading > 0 && padded.len() > leading as usize {
+            log::warn!(
+                "{} {} is wider than its padding ({}), files may not sort properly (consider a wider padding)",
+                name,
+                padded,
+                leading
+            );
         }
+
+        padded
     }
 
-    fn add_leading_zeros(string: String, leading: u8) -> String {
-        if (leading as usize) > string.len() {
-            let mut res: String = vec!['0'; leading as usize - string.len()].iter().collect();
-            res.push_str(&string);
-            res
-        } else {
-            string
+    fn primary_artist(artist: String, options: &FormatOptions) -> String {
+        match options.split_artist {
+            Some(separator) => metadata::primary_artist(&artist, separator).to_owned(),
+            None => artist,
         }
     }
 
-    fn get_from_metadata(metadata: &Metadata, pholder: Placeholder) -> Result<Option<String>> {
+    fn get_from_metadata(
+        metadata: &Metadata,
+        pholder: Placeholder,
+        options: &FormatOptions,
+    ) -> Result<Option<String>> {
+        match (
+            Self::get_tag(metadata, pholder, options),
+            options.missing_tag,
+        ) {
+            (Err(Error::MissingTag { .. }), Some(missing_tag)) => Ok(Some(missing_tag.to_owned())),
+            (value, _) => value,
+        }
+    }
+
+    fn get_tag(
+        metadata: &Metadata,
+        pholder: Placeholder,
+        options: &FormatOptions,
+    ) -> Result<Option<String>> {
         let is_optional = pholder.is_optional();
         let tag = pholder.into_tag();
 
         match tag {
+            Tag::Artist if options.prefer_sort_tags => match metadata.get_artist_sort() {
+                Ok(artist) => Ok(Some(Self::primary_artist(artist, options))),
+                Err(_) if is_optional => Ok(None),
+                Err(e) => Err(e),
+            },
+
             Tag::Artist => match metadata.get_artist() {
+                Ok(artist) => Ok(Some(Self::primary_artist(artist, options))),
+                Err(_) if is_optional => Ok(None),
+                Err(e) => Err(e),
+            },
+
+            // Files whose artists weren't read (e.g. Matroska) use the single one
+            Tag::Artists { join } if !metadata.artists.is_empty() => {
+                Ok(Some(metadata.artists.join(join.as_str())))
+            }
+
+            Tag::Artists { .. } => match metadata.get_artist() {
                 Ok(artist) => Ok(Some(artist)),
                 Err(_) if is_optional => Ok(None),
                 Err(e) => Err(e),
             },
 
+            Tag::Album if options.prefer_sort_tags => match metadata.get_album_sort() {
+                Ok(album) => Ok(Some(album)),
+                Err(_) if is_optional => Ok(None),
+                Err(e) => Err(e),
+            },
+
             Tag::Album => match metadata.get_album() {
                 Ok(album) => Ok(Some(album)),
                 Err(_) if is_optional => Ok(None),
                 Err(e) => Err(e),
             },
 
+            // An optional disc is left out of single-disc albums too
+            Tag::Disc { .. } if is_optional && metadata.total_discs.is_some_and(|n| n <= 1) => {
+                Ok(None)
+            }
+
             Tag::Disc { leading } => match metadata.get_disc() {
-                Ok(disc) => Ok(Some(Self::add_leading_zeros(disc, leading))),
+                Ok(disc) => Ok(Some(Self::add_leading_zeros(disc, leading, "Disc"))),
                 Err(_) if is_optional => Ok(None),
                 Err(e) => Err(e),
             },
 
             Tag::Track { leading } => match metadata.get_track() {
-                Ok(track) => Ok(Some(Self::add_leading_zeros(track, leading))),
+                Ok(track) => Ok(Some(Self::add_leading_zeros(track, leading, "Track"))),
+                Err(_) if is_optional => Ok(None),
+                Err(e) => Err(e),
+            },
+
+            Tag::Bpm { leading } => match metadata.get_bpm() {
+                Ok(bpm) => Ok(Some(Self::add_leading_zeros(bpm, leading, "BPM"))),
                 Err(_) if is_optional => Ok(None),
                 Err(e) => Err(e),
             },
@@ -232,7 +898,97 @@ impl ParsedFormat {
                 Err(e) => Err(e),
             },
 
-            Tag::Ext => Ok(Some(metadata.get_ext())),
+            Tag::Ext => {
+                let mut ext = metadata.get_ext();
+                if options.lowercase_ext {
+                    ext = ext.to_lowercase();
+                }
+
+                let mapped = options.ext_map.and_then(|map| map.get(&ext)).cloned();
+                Ok(Some(mapped.unwrap_or(ext)))
+            }
+
+            Tag::DiscSubtitle => match metadata.get_disc_subtitle() {
+                Ok(subtitle) => Ok(Some(subtitle)),
+                Err(_) if is_optional => Ok(None),
+                Err(e) => Err(e),
+            },
+
+            Tag::Loudness => match metadata.get_loudness() {
+                Ok(loudness) => Ok(Some(loudness)),
+                Err(_) if is_optional => Ok(None),
+                Err(e) => Err(e),
+            },
+
+            Tag::HasArt => Ok(Some(metadata.get_has_art())),
+            Tag::Codec => Ok(Some(metadata.get_codec())),
+            Tag::HasLyrics => Ok(Some(metadata.get_has_lyrics())),
+
+            Tag::SampleRate => match metadata.get_sample_rate() {
+                Ok(sample_rate) => Ok(Some(sample_rate)),
+                Err(_) if is_optional => Ok(None),
+                Err(e) => Err(e),
+            },
+
+            Tag::BitDepth => match metadata.get_bit_depth() {
+                Ok(bit_depth) => Ok(Some(bit_depth)),
+                Err(_) if is_optional => Ok(None),
+                Err(e) => Err(e),
+            },
+
+            Tag::Channels => match metadata.get_channels() {
+                Ok(channels) => Ok(Some(channels)),
+                Err(_) if is_optional => Ok(None),
+                Err(e) => Err(e),
+            },
+
+            Tag::Year => match metadata.get_year() {
+                Ok(year) => Ok(Some(year)),
+                Err(_) if is_optional => Ok(None),
+                Err(e) => Err(e),
+            },
+
+            Tag::OriginalYear => match metadata.get_original_year() {
+                Ok(year) => Ok(Some(year)),
+                // Most files only have the release year
+                Err(_) if !options.strict_original_year && metadata.year.is_some() => {
+                    metadata.get_year().map(Some)
+                }
+                Err(_) if is_optional => Ok(None),
+                Err(e) => Err(e),
+            },
+
+            Tag::Path { index } => {
+                let component = options
+                    .source_path
+                    .and_then(Path::parent)
+                    .and_then(|parent| parent.iter().nth(index as usize))
+                    .map(|component| component.to_string_lossy().into_owned());
+
+                match component {
+                    Some(component) => Ok(Some(component)),
+                    None if is_optional => Ok(None),
+                    None => Err(Error::MissingTag {
+                        tag: format!("path{}", index),
+                    }),
+                }
+            }
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn add_leading_zeros() {
+        let pad = |s: &str, leading| ParsedFormat::add_leading_zeros(s.into(), leading, "Track");
+
+        assert_eq!(pad("1", 2), "01");
+        assert_eq!(pad("01", 2), "01");
+        assert_eq!(pad("01", 0), "1");
+        assert_eq!(pad("123", 2), "123");
+        assert_eq!(pad("1a", 3), "1a");
+    }
+}
@@ -1,21 +1,77 @@
 use nom::branch::alt;
-use nom::bytes::complete::{tag, take_till1};
+use nom::bytes::complete::{tag, take_till, take_till1};
 use nom::character::complete::{char, digit1};
-use nom::combinator::{map, opt};
+use nom::combinator::{map, map_opt, map_res, opt, recognize};
 use nom::multi::many1;
 use nom::sequence::{delimited, tuple};
 use nom::IResult;
 
 use crate::{Error, Result};
 
+/// Longest separator (in bytes) accepted by `join(...)`.
+const MAX_SEPARATOR_LEN: usize = 16;
+
+/// Separator of a `join(...)` modifier, stored inline so tags stay `Copy`.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct Separator {
+    bytes: [u8; MAX_SEPARATOR_LEN],
+    len: u8,
+}
+
+impl Separator {
+    /// `None` if `separator` is longer than 16 bytes.
+    pub fn new(separator: &str) -> Option<Self> {
+        if separator.len() > MAX_SEPARATOR_LEN {
+            return None;
+        }
+
+        let mut bytes = [0; MAX_SEPARATOR_LEN];
+        bytes[..separator.len()].copy_from_slice(separator.as_bytes());
+
+        Some(Separator {
+            bytes,
+            len: separator.len() as u8,
+        })
+    }
+
+    pub fn as_str(&self) -> &str {
+        // Always copied whole from a `&str`
+        std::str::from_utf8(&self.bytes[..self.len as usize]).unwrap()
+    }
+}
+
 #[derive(Debug, Copy, Clone, PartialEq)]
 pub enum Tag {
     Artist,
+    /// Every artist joined with a separator, written as `{artist:join(sep)}`.
+    Artists {
+        join: Separator,
+    },
     Album,
-    Disc { leading: u8 },
-    Track { leading: u8 },
+    Disc {
+        leading: u8,
+    },
+    Track {
+        leading: u8,
+    },
     Title,
     Ext,
+    Loudness,
+    DiscSubtitle,
+    Path {
+        index: u8,
+    },
+    Bpm {
+        leading: u8,
+    },
+    HasArt,
+    SampleRate,
+    BitDepth,
+    Channels,
+    Year,
+    OriginalYear,
+    Codec,
+    HasLyrics,
 }
 
 impl From<&str> for Tag {
@@ -27,11 +83,124 @@ impl From<&str> for Tag {
             "track" => Tag::Track { leading: 0 },
             "title" => Tag::Title,
             "ext" => Tag::Ext,
+            "loudness" => Tag::Loudness,
+            "discsubtitle" => Tag::DiscSubtitle,
+            "path" => Tag::Path { index: 0 },
+            "bpm" => Tag::Bpm { leading: 0 },
+            "hasart" => Tag::HasArt,
+            "samplerate" => Tag::SampleRate,
+            "bitdepth" => Tag::BitDepth,
+            "channels" => Tag::Channels,
+            "year" => Tag::Year,
+            "originalyear" => Tag::OriginalYear,
+            "codec" => Tag::Codec,
+            "haslyrics" => Tag::HasLyrics,
             _ => unreachable!(),
         }
     }
 }
 
+impl Tag {
+    /// Every tag, in the order they're listed to users.
+    pub const ALL: &'static [Tag] = &[
+        Tag::Artist,
+        Tag::Album,
+        Tag::Disc { leading: 0 },
+        Tag::DiscSubtitle,
+        Tag::Track { leading: 0 },
+        Tag::Title,
+        Tag::Bpm { leading: 0 },
+        Tag::Year,
+        Tag::OriginalYear,
+        Tag::Ext,
+        Tag::Codec,
+        Tag::Path { index: 0 },
+        Tag::HasArt,
+        Tag::HasLyrics,
+        Tag::SampleRate,
+        Tag::BitDepth,
+        Tag::Channels,
+        Tag::Loudness,
+    ];
+
+    /// Name used in format strings, `{pathN}` takes the index of the folder after it.
+    pub fn name(self) -> &'static str {
+        match self {
+            Tag::Artist | Tag::Artists { .. } => "artist",
+            Tag::Album => "album",
+            Tag::Disc { .. } => "disc",
+            Tag::Track { .. } => "track",
+            Tag::Title => "title",
+            Tag::Ext => "ext",
+            Tag::Codec => "codec",
+            Tag::Loudness => "loudness",
+            Tag::DiscSubtitle => "discsubtitle",
+            Tag::Path { .. } => "path",
+            Tag::Bpm { .. } => "bpm",
+            Tag::HasArt => "hasart",
+            Tag::HasLyrics => "haslyrics",
+            Tag::SampleRate => "samplerate",
+            Tag::BitDepth => "bitdepth",
+            Tag::Channels => "channels",
+            Tag::Year => "year",
+            Tag::OriginalYear => "originalyear",
+        }
+    }
+
+    /// Whether the tag can be padded with leading zeros (e.g. `{track:2}`).
+    pub fn is_paddable(self) -> bool {
+        matches!(self, Tag::Disc { .. } | Tag::Track { .. } | Tag::Bpm { .. })
+    }
+
+    pub fn description(self) -> &'static str {
+        match self {
+            Tag::Artist => "Album artist, or artist if missing",
+            Tag::Artists { .. } => "Every album artist (or artist), joined",
+            Tag::Album => "Album name",
+            Tag::Disc { .. } => "Disc number",
+            Tag::Track { .. } => "Track number",
+            Tag::Title => "Song title",
+            Tag::Ext => "File extension",
+            Tag::Codec => "Audio codec, or extension if unknown",
+            Tag::Loudness => "Bucket of the ReplayGain track gain",
+            Tag::DiscSubtitle => "Disc subtitle",
+            Tag::Path { .. } => "N-th folder of the original path",
+            Tag::Bpm { .. } => "Tempo in beats per minute",
+            Tag::HasArt => "Whether there's embedded cover art",
+            Tag::HasLyrics => "Whether there are embedded lyrics",
+            Tag::SampleRate => "Sample rate in Hz",
+            Tag::BitDepth => "Bits per sample",
+            Tag::Channels => "Number of channels",
+            Tag::Year => "Release year",
+            Tag::OriginalYear => "Original release year, or release year if missing",
+        }
+    }
+
+    pub fn example(self) -> &'static str {
+        match self {
+            Tag::Artist => "The Beatles",
+            Tag::Artists { .. } => "Simon, Garfunkel",
+            Tag::Album => "Abbey Road",
+            Tag::Disc { .. } => "1",
+            Tag::Track { .. } => "7",
+            Tag::Title => "Here Comes the Sun",
+            Tag::Ext => "flac",
+            Tag::Codec => "alac",
+            Tag::Loudness => "quiet, normal or loud",
+            Tag::DiscSubtitle => "Studio Outtakes",
+            Tag::Path { .. } => "Downloads",
+            Tag::Bpm { .. } => "128",
+            Tag::HasArt => "with-art or no-art",
+            Tag::HasLyrics => "lyrics or no-lyrics",
+            Tag::SampleRate => "44100",
+            Tag::BitDepth => "16",
+            Tag::Channels => "2",
+            Tag::Year => "2019",
+            Tag::OriginalYear => "1969",
+        }
+    }
+}
+
 #[derive(Debug, Copy, Clone, PartialEq)]
 pub enum Placeholder {
     Required(Tag),
@@ -59,6 +228,7 @@ impl Placeholder {
 pub enum BasicComponent {
     String(String),
     Placeholder(Placeholder),
+    Optional(Vec<BasicComponent>),
 }
 
 #[derive(Debug, Clone, PartialEq)]
@@ -70,12 +240,24 @@ pub enum FsComponent {
 fn tag_ident(input: &str) -> IResult<&str, &str> {
     alt((
         tag("ext"),
+        tag("discsubtitle"),
         tag("disc"),
         tag("disk"),
         tag("track"),
         tag("title"),
         tag("album"),
         tag("artist"),
+        tag("loudness"),
+        tag("path"),
+        tag("bpm"),
+        tag("hasart"),
+        tag("haslyrics"),
+        tag("samplerate"),
+        tag("bitdepth"),
+        tag("channels"),
+        tag("year"),
+        tag("originalyear"),
+        tag("codec"),
     ))(input)
 }
 
@@ -88,10 +270,26 @@ fn tag_leading(input: &str) -> IResult<&str, u8> {
     ))
 }
 
+/// A `:join(sep)` modifier, the separator can't contain `)`.
+fn tag_join(input: &str) -> IResult<&str, Option<Separator>> {
+    opt(map_opt(
+        delimited(tag(":join("), take_till(|c: char| c == ')'), char(')')),
+        Separator::new,
+    ))(input)
+}
+
 fn tag_complete(input: &str) -> IResult<&str, Tag> {
     let (input, output) = tag_ident(input)?;
 
     let (input, tag) = match Tag::from(output) {
+        Tag::Artist => {
+            let (input, join) = tag_join(input)?;
+            match join {
+                Some(join) => (input, Tag::Artists { join }),
+                None => (input, Tag::Artist),
+            }
+        }
+
         Tag::Disc { .. } => {
             let (input, leading) = tag_leading(input)?;
             (input, Tag::Disc { leading })
@@ -102,6 +300,16 @@ fn tag_complete(input: &str) -> IResult<&str, Tag> {
             (input, Tag::Track { leading })
         }
 
+        Tag::Bpm { .. } => {
+            let (input, leading) = tag_leading(input)?;
+            (input, Tag::Bpm { leading })
+        }
+
+        Tag::Path { .. } => {
+            let (input, index) = map_res(digit1, |n: &str| n.parse())(input)?;
+            (input, Tag::Path { index })
+        }
+
         placeholder => (input, placeholder),
     };
 
@@ -128,23 +336,73 @@ fn placeholder(input: &str) -> IResult<&str, Placeholder> {
     Ok((input, component))
 }
 
+/// Literals may contain `${VAR}`, which are expanded later on.
+fn literal(input: &str) -> IResult<&str, &str> {
+    recognize(many1(alt((
+        take_till1(|c: char| c == '{' || c == '[' || c == ']' || c == '$'),
+        tag("$$"),
+        recognize(tuple((
+            tag("${"),
+            take_till1(|c: char| c == '}'),
+            char('}'),
+        ))),
+        tag("$"),
+    ))))(input)
+}
+
+/// Groups without placeholders (e.g. `[FLAC]`) are kept as literals, brackets included.
+fn group(input: &str) -> IResult<&str, BasicComponent> {
+    map(
+        delimited(char('['), opt(components), char(']')),
+        |group| match group.unwrap_or_default().as_slice() {
+            [] => BasicComponent::String("[]".into()),
+            [BasicComponent::String(s)] => BasicComponent::String(format!("[{}]", s)),
+            group => BasicComponent::Optional(group.to_vec()),
+        },
+    )(input)
+}
+
 fn component(input: &str) -> IResult<&str, BasicComponent> {
     alt((
-        map(take_till1(|c: char| c == '{'), |s: &str| {
-            BasicComponent::String(s.into())
-        }),
+        map(literal, |s: &str| BasicComponent::String(s.into())),
         map(delimited(char('{'), placeholder, char('}')), |p| {
             BasicComponent::Placeholder(p)
         }),
+        group,
     ))(input)
 }
 
+/// Adjacent literals (e.g. a literal group between two literals) are merged into one.
+fn merge_literals(parsed: Vec<BasicComponent>) -> Vec<BasicComponent> {
+    let mut merged: Vec<BasicComponent> = Vec::with_capacity(parsed.len());
+
+    for component in parsed {
+        match (merged.last_mut(), component) {
+            (Some(BasicComponent::String(last)), BasicComponent::String(s)) => last.push_str(&s),
+            (_, component) => merged.push(component),
+        }
+    }
+
+    merged
+}
+
 fn components(input: &str) -> IResult<&str, Vec<BasicComponent>> {
-    many1(component)(input)
+    map(many1(component), merge_literals)(input)
+}
+
+/// Outside of groups a `]` closes nothing, so it's taken as a literal.
+fn top_level_components(input: &str) -> IResult<&str, Vec<BasicComponent>> {
+    map(
+        many1(alt((
+            component,
+            map(tag("]"), |s: &str| BasicComponent::String(s.into())),
+        ))),
+        merge_literals,
+    )(input)
 }
 
 pub(crate) fn parse_format_string(input: &str) -> Result<Vec<BasicComponent>> {
-    let (rest, parsed) = components(input).map_err(|_| Error::FailedToParse)?;
+    let (rest, parsed) = top_level_components(input).map_err(|_| Error::FailedToParse)?;
 
     if !rest.is_empty() {
         Err(Error::FailedToParse)
@@ -174,6 +432,35 @@ mod tests {
             Ok(("?}", Tag::Track { leading: 3 }))
         );
         assert_eq!(tag_complete("disk"), Ok(("", Tag::Disc { leading: 0 })));
+        assert_eq!(
+            tag_complete("discsubtitle?}"),
+            Ok(("?}", Tag::DiscSubtitle))
+        );
+        assert_eq!(tag_complete("path1}"), Ok(("}", Tag::Path { index: 1 })));
+        assert_eq!(tag_complete("bpm:3"), Ok(("", Tag::Bpm { leading: 3 })));
+        assert_eq!(
+            tag_complete("artist:join(, )?}"),
+            Ok((
+                "?}",
+                Tag::Artists {
+                    join: Separator::new(", ").unwrap()
+                }
+            ))
+        );
+        assert_eq!(tag_complete("artist:join(x"), Ok((":join(x", Tag::Artist)));
+        assert!(tag_complete("path}").is_err());
+    }
+
+    #[test]
+    fn all_tags_parse() {
+        for tag in Tag::ALL {
+            let name = match tag {
+                Tag::Path { index } => format!("{}{}", tag.name(), index),
+                _ => tag.name().to_owned(),
+            };
+
+            assert_eq!(tag_complete(&name), Ok(("", *tag)));
+        }
     }
 
     #[test]
@@ -242,6 +529,65 @@ mod tests {
         assert_eq!(parsed, Ok(("", expected)));
     }
 
+    #[test]
+    fn group_parse() {
+        assert_eq!(
+            component("[{disc}.]{track}"),
+            Ok((
+                "{track}",
+                BasicComponent::Optional(vec![
+                    BasicComponent::Placeholder(Placeholder::Required(Tag::Disc { leading: 0 })),
+                    BasicComponent::String(".".into()),
+                ])
+            ))
+        );
+
+        assert_eq!(
+            component("[a[{album}]]"),
+            Ok((
+                "",
+                BasicComponent::Optional(vec![
+                    BasicComponent::String("a".into()),
+                    BasicComponent::Optional(vec![BasicComponent::Placeholder(
+                        Placeholder::Required(Tag::Album)
+                    )]),
+                ])
+            ))
+        );
+
+        assert!(parse_format_string("[{disc}.").is_err());
+    }
+
+    #[test]
+    fn literal_brackets() {
+        let expected = vec![
+            BasicComponent::Placeholder(Placeholder::Required(Tag::Album)),
+            BasicComponent::String(" [FLAC]/[]".into()),
+            BasicComponent::Placeholder(Placeholder::Required(Tag::Title)),
+        ];
+        assert_eq!(
+            parse_format_string("{album} [FLAC]/[]{title}").ok(),
+            Some(expected)
+        );
+
+        assert_eq!(
+            component("[[FLAC] {disc}]"),
+            Ok((
+                "",
+                BasicComponent::Optional(vec![
+                    BasicComponent::String("[FLAC] ".into()),
+                    BasicComponent::Placeholder(Placeholder::Required(Tag::Disc { leading: 0 })),
+                ])
+            ))
+        );
+
+        let expected = vec![
+            BasicComponent::Placeholder(Placeholder::Required(Tag::Disc { leading: 0 })),
+            BasicComponent::String("].]".into()),
+        ];
+        assert_eq!(parse_format_string("{disc}].]").ok(), Some(expected));
+    }
+
     #[test]
     fn without_placeholders() {
         let expected = vec![BasicComponent::String("hello world".into())];
@@ -1,3 +1,4 @@
+pub mod catalog;
 pub mod config;
 pub mod format;
 pub mod metadata;
@@ -31,6 +32,12 @@ pub enum Error {
     #[error("Resource \"{path}\" was not found!")]
     ResourceNotFound { path: String },
 
+    #[error("Couldn't find the user's config folder (is $HOME set?)")]
+    ConfigDirNotFound,
+
+    #[error("Couldn't find the user's data folder (is $HOME set?)")]
+    DataDirNotFound,
+
     #[error("Invalid config file: {reason}")]
     InvalidConfig { reason: String },
 
@@ -43,6 +50,44 @@ pub enum Error {
     #[error("File component must have one required placeholder (except from {{ext}})")]
     RequiredInFile,
 
+    #[error("Conditional groups in format string can't contain directory separators")]
+    SeparatorInGroup,
+
+    #[error("Variable \"{name}\" in format string isn't defined")]
+    UndefinedVariable { name: String },
+
+    #[error("Destination \"{path}\" is already taken")]
+    DestinationTaken { path: String },
+
+    #[error("Invalid conflict policy \"{policy}\" (expected \"skip\" or \"rename\")")]
+    InvalidConflictPolicy { policy: String },
+
+    #[error("Invalid catalog format \"{format}\" (expected \"json\" or \"csv\")")]
+    InvalidCatalogFormat { format: String },
+
+    #[error("Invalid missing tag policy \"{policy}\" (expected \"error\", \"skip\" or \"placeholder=<text>\")")]
+    InvalidMissingTagPolicy { policy: String },
+
+    #[error(
+        "Invalid target filesystem \"{fs}\" (expected \"posix\", \"vfat\", \"ntfs\" or \"exfat\")"
+    )]
+    InvalidTargetFs { fs: String },
+
+    #[error("Device sentinel \"{path}\" wasn't found (is it mounted?), nothing was sorted")]
+    SentinelNotFound { path: String },
+
+    #[error("\"{dest}\" isn't on the same device as \"{sentinel}\" (is it mounted?), nothing was sorted")]
+    WrongDevice { dest: String, sentinel: String },
+
+    #[error("Copy of \"{path}\" doesn't match the original (source kept)")]
+    VerificationFailed { path: String },
+
+    #[error("Skipping \"{path}\", it's smaller than the minimum size")]
+    TooSmall { path: String },
+
+    #[error("Hook exited with {status}")]
+    HookFailed { status: String },
+
     #[error("Invalid sha256 sum found while parsing")]
     InvalidSha256,
 
@@ -52,30 +97,40 @@ pub enum Error {
         source: io::Error,
     },
 
+    #[cfg(feature = "mp3")]
     #[error("Id3 error (source: {source})")]
     Id3Error {
         #[from]
         source: id3::Error,
     },
 
+    #[cfg(feature = "flac")]
     #[error("Metaflac error (source: {source})")]
     MetaflacError {
         #[from]
         source: metaflac::Error,
     },
 
+    #[cfg(feature = "ogg")]
     #[error("Vorbis error (source: {source})")]
     VorbisError {
         #[from]
         source: lewton::VorbisError,
     },
 
+    #[cfg(feature = "m4a")]
     #[error("M4aMeta error (source: {source})")]
     M4aMetaError {
         #[from]
         source: mp4ameta::Error,
     },
 
+    #[error("Ape error (source: {source})")]
+    ApeError {
+        #[from]
+        source: ape::Error,
+    },
+
     #[error("Notify error (source: {source})")]
     NotifyError {
         #[from]
@@ -15,21 +15,114 @@
 // You should have received a copy of the GNU General Public License
 // along with muso.  If not, see <http://www.gnu.org/licenses/>.
 
+mod matroska;
+#[cfg(feature = "m4a")]
+mod mp4;
+
 use std::collections::HashMap;
 use std::fs::File;
 use std::io::Read;
+#[cfg(any(feature = "flac", feature = "mp3"))]
+use std::io::{Seek, SeekFrom};
 use std::path::Path;
 
+use serde::{Deserialize, Serialize};
+
+use self::matroska::{ALBUM_LEVEL, PART_LEVEL, TRACK_LEVEL};
 use crate::{Error, Result};
 
-#[derive(Debug)]
+/// Track gains (in dB) at or below this value are considered loud.
+const LOUD_GAIN_THRESHOLD: f64 = -9.0;
+
+/// Track gains (in dB) at or above this value are considered quiet.
+const QUIET_GAIN_THRESHOLD: f64 = -3.0;
+
+/// Bytes read from a FLAC picture block, enough for the image header.
+#[cfg(feature = "flac")]
+const FLAC_PICTURE_PREFIX: u32 = 64 * 1024;
+
+/// Metadata blocks of a FLAC file that are actually read.
+#[cfg(feature = "flac")]
+#[derive(Default)]
+struct FlacBlocks {
+    stream_info: Option<metaflac::block::StreamInfo>,
+    comments: Option<Vec<u8>>,
+    cover: Option<CoverArt>,
+}
+
+/// The first picture embedded in a file.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct CoverArt {
+    /// Width and height, `None` if the image header couldn't be decoded.
+    pub dimensions: Option<(u32, u32)>,
+}
+
+impl CoverArt {
+    /// Only the image header is decoded, never the whole image.
+    #[cfg(feature = "cover-size")]
+    pub fn from_data(data: &[u8]) -> Self {
+        let dimensions = image::io::Reader::new(std::io::Cursor::new(data))
+            .with_guessed_format()
+            .ok()
+            .and_then(|reader| reader.into_dimensions().ok());
+
+        CoverArt { dimensions }
+    }
+
+    /// Without `cover-size` images aren't decoded, so their dimensions are unknown.
+    #[cfg(not(feature = "cover-size"))]
+    pub fn from_data(_data: &[u8]) -> Self {
+        CoverArt { dimensions: None }
+    }
+}
+
+/// Tags and properties read from a file, (de)serializable for exporting catalogs (fields
+/// missing when deserializing are left empty).
+#[derive(Debug, Default, Serialize, Deserialize)]
+#[serde(default)]
 pub struct Metadata {
     pub artist: Option<String>,
+
+    /// Every value of the tag `artist` was read from (e.g. several `ARTIST` comments),
+    /// empty for Matroska files.
+    pub artists: Vec<String>,
+
     pub album: Option<String>,
     pub disc: Option<u32>,
     pub track: Option<u32>,
+
+    /// Number of discs in the album, from the `n/total` form or a dedicated tag.
+    pub total_discs: Option<u32>,
+
     pub title: Option<String>,
     pub ext: String,
+    pub track_gain: Option<f64>,
+    pub disc_subtitle: Option<String>,
+
+    /// Variants meant for filing (e.g. `Beatles, The`).
+    pub artist_sort: Option<String>,
+    pub album_sort: Option<String>,
+
+    pub bpm: Option<u32>,
+
+    /// Years of the release and of the original release (e.g. of a reissue).
+    pub year: Option<u32>,
+    pub original_year: Option<u32>,
+
+    /// Codec of the audio stream (e.g. `alac`), not read from AIFF, APE, WavPack and
+    /// Matroska files.
+    pub codec: Option<String>,
+
+    /// Embedded cover art, not read from OGG and Matroska files.
+    pub cover: Option<CoverArt>,
+
+    /// Whether there are embedded (synced or unsynced) lyrics, not read from Matroska files.
+    pub has_lyrics: bool,
+
+    /// Stream properties, only read from FLAC and OGG (which has no bit depth) files.
+    pub sample_rate: Option<u32>,
+    pub bit_depth: Option<u32>,
+    pub channels: Option<u32>,
 }
 
 macro_rules! impl_tag_getter {
@@ -44,121 +137,853 @@ macro_rules! impl_tag_getter {
     };
 }
 
+/// Non-standard vorbis comment keys found in the wild, along with the standard key each
+/// one stands for.
+#[cfg(any(feature = "flac", feature = "ogg"))]
+const VORBIS_ALIASES: &[(&str, &str)] = &[
+    ("ALBUM ARTIST", "ALBUMARTIST"),
+    ("BAND", "ALBUMARTIST"),
+    ("TRACK", "TRACKNUMBER"),
+];
+
+type Reader = fn(&Path, &HashMap<String, String>) -> Result<Metadata>;
+
+// NOTE(erichdongubler): This could be smaller if media types with larger magic bytes
+// length requirements for `infer` get removed, so let's keep a table below of length
+// required for each.
+const MAGIC_BYTES_LEN: usize = 12;
+
+/// Supported mime types (as reported by `infer`) and the reader used for each one.
+const READERS: &[(&str, Reader)] = &[
+    // Minimum: 4 bytes
+    #[cfg(feature = "flac")]
+    ("audio/x-flac", |path, aliases| {
+        Metadata::from_flac_vorbis(path, aliases)
+    }),
+    // Minimum: 4 bytes
+    #[cfg(feature = "mp3")]
+    ("audio/mpeg", |path, _| Metadata::from_id3(path)),
+    // Minimum: 4 bytes
+    #[cfg(feature = "ogg")]
+    ("audio/ogg", |path, aliases| {
+        Metadata::from_ogg_vorbis(path, aliases)
+    }),
+    // Minimum: 11 bytes (4 normally, 11 to include `m4p`)
+    #[cfg(feature = "m4a")]
+    ("audio/m4a", |path, _| Metadata::from_m4a(path)),
+    // Minimum: 12 bytes (audiobooks and m4a files with a generic brand)
+    #[cfg(feature = "m4a")]
+    ("audio/mp4", |path, _| Metadata::from_m4a(path)),
+    #[cfg(feature = "m4a")]
+    ("audio/x-m4a", |path, _| Metadata::from_m4a(path)),
+    // Minimum: 12 bytes
+    #[cfg(feature = "mp3")]
+    ("audio/x-aiff", |path, _| Metadata::from_aiff(path)),
+    // Minimum: 4 bytes
+    ("audio/x-ape", |path, _| Metadata::from_ape(path)),
+    // Minimum: 4 bytes
+    ("audio/x-wavpack", |path, _| Metadata::from_ape(path)),
+    // Minimum: 4 bytes
+    ("audio/x-matroska", |path, _| Metadata::from_matroska(path)),
+];
+
+type Writer = fn(&Metadata, &Path) -> Result<bool>;
+
+/// Mime types of the files whose tags can be written, and the writer used for each one.
+const WRITERS: &[(&str, Writer)] = &[
+    #[cfg(feature = "flac")]
+    ("audio/x-flac", |metadata, path| metadata.write_flac(path)),
+    #[cfg(feature = "mp3")]
+    ("audio/mpeg", |metadata, path| metadata.write_id3(path)),
+    #[cfg(feature = "m4a")]
+    ("audio/m4a", |metadata, path| metadata.write_m4a(path)),
+    #[cfg(feature = "m4a")]
+    ("audio/mp4", |metadata, path| metadata.write_m4a(path)),
+    #[cfg(feature = "m4a")]
+    ("audio/x-m4a", |metadata, path| metadata.write_m4a(path)),
+    ("audio/x-ape", |metadata, path| metadata.write_ape(path)),
+    ("audio/x-wavpack", |metadata, path| metadata.write_ape(path)),
+];
+
+/// Mime types of the files that can be read, mirroring the dispatch in
+/// [`Metadata::from_path`].
+pub fn supported_mime_types() -> impl Iterator<Item = &'static str> {
+    READERS.iter().map(|(mime, _)| *mime)
+}
+
+/// Cheaply checks (only reading a few magic bytes) whether the file can be read.
+pub fn is_supported_path(path: impl AsRef<Path>) -> bool {
+    reader_for(path.as_ref()).is_ok()
+}
+
+fn reader_for(path: &Path) -> Result<Reader> {
+    let mime = mime_type_of(path)?;
+
+    READERS
+        .iter()
+        .find(|(supported, _)| *supported == mime)
+        .map(|(_, reader)| *reader)
+        .ok_or(Error::NotSupported)
+}
+
+fn writer_for(path: &Path) -> Result<Writer> {
+    let mime = mime_type_of(path)?;
+
+    WRITERS
+        .iter()
+        .find(|(supported, _)| *supported == mime)
+        .map(|(_, writer)| *writer)
+        .ok_or(Error::NotSupported)
+}
+
+/// Mime type of a supported file (one of [`READERS`]), judging by its magic bytes.
+fn mime_type_of(path: &Path) -> Result<&'static str> {
+    let mut file = File::open(path)?;
+    let mut magic_bytes = [0; MAGIC_BYTES_LEN];
+    file.read_exact(&mut magic_bytes)
+        .map_err(|_| Error::NotSupported)?;
+
+    let mut infer = infer::Infer::new();
+    infer.add("audio/x-aiff", "aiff", is_aiff);
+    infer.add("audio/x-ape", "ape", |buf| buf.starts_with(b"MAC "));
+    infer.add("audio/x-wavpack", "wv", |buf| buf.starts_with(b"wvpk"));
+    infer.add("audio/mp4", "m4b", |buf| {
+        buf.len() >= 12 && &buf[4..12] == b"ftypM4B "
+    });
+    let ftype = infer.get(&magic_bytes).ok_or(Error::NotSupported)?;
+
+    // Some m4a files use a generic brand (e.g. `isom`) and are reported as videos, the
+    // same goes for Matroska audio (which is told apart from videos by its extension)
+    let mime = match ftype.mime_type() {
+        "video/mp4" if has_ext(path, &["m4a", "m4b"]) => "audio/mp4",
+        "video/webm" | "video/x-matroska" if has_ext(path, &["mka"]) => "audio/x-matroska",
+        mime => mime,
+    };
+
+    supported_mime_types()
+        .find(|supported| *supported == mime)
+        .ok_or(Error::NotSupported)
+}
+
+fn has_ext(path: &Path, exts: &[&str]) -> bool {
+    match path.extension() {
+        Some(ext) => exts.iter().any(|other| ext.eq_ignore_ascii_case(other)),
+        None => false,
+    }
+}
+
+/// First of the artists joined by `separator`, the whole string if there's no separator.
+pub(crate) fn primary_artist<'a>(artist: &'a str, separator: &str) -> &'a str {
+    if separator.is_empty() {
+        return artist;
+    }
+
+    artist.split(separator).next().unwrap_or(artist).trim()
+}
+
+fn is_aiff(buf: &[u8]) -> bool {
+    buf.len() >= 12 && &buf[0..4] == b"FORM" && matches!(&buf[8..12], b"AIFF" | b"AIFC")
+}
+
 impl Metadata {
     pub fn from_path(path: impl AsRef<Path>) -> Result<Self> {
-        let mut file = File::open(&path)?;
-        // NOTE(erichdongubler): This could be smaller if media types with larger magic bytes
-        // length requirements for `infer` get removed, so let's keep a table below of length
-        // required for each.
-        let mut magic_bytes = [0; 11];
-        file.read_exact(&mut magic_bytes)
-            .map_err(|_| Error::NotSupported)?;
-
-        let infer = infer::Infer::new();
-        let ftype = infer.get(&magic_bytes).ok_or(Error::NotSupported)?;
-        match ftype.mime_type() {
-            // Minimum: 4 bytes
-            "audio/x-flac" => Metadata::from_flac_vorbis(&path),
-            // Minimum: 4 bytes
-            "audio/mpeg" => Metadata::from_id3(&path),
-            // Minimum: 4 bytes
-            "audio/ogg" => Metadata::from_ogg_vorbis(&path),
-            // Minimum: 11 bytes (4 normally, 11 to include `m4p`)
-            "audio/m4a" => Metadata::from_m4a(&path),
-            // Unsupported file
-            _ => Err(Error::NotSupported),
+        Self::from_path_with_aliases(path, &HashMap::new())
+    }
+
+    /// Same as [`Metadata::from_path`], also reading vorbis comments under the given
+    /// aliases (e.g. `ALBUM ARTIST` for `ALBUMARTIST`) when their standard key is missing.
+    pub fn from_path_with_aliases(
+        path: impl AsRef<Path>,
+        aliases: &HashMap<String, String>,
+    ) -> Result<Self> {
+        let path = path.as_ref();
+        reader_for(path)?(path, aliases)
+    }
+
+    /// Fills the missing fields with the ones from `other`, fields already present in
+    /// `self` are never overwritten.
+    pub fn merge(&mut self, other: Metadata) {
+        let Metadata {
+            artist,
+            artists,
+            album,
+            disc,
+            track,
+            total_discs,
+            title,
+            ext,
+            track_gain,
+            disc_subtitle,
+            artist_sort,
+            album_sort,
+            bpm,
+            year,
+            original_year,
+            codec,
+            cover,
+            has_lyrics,
+            sample_rate,
+            bit_depth,
+            channels,
+        } = other;
+
+        self.artist = self.artist.take().or(artist);
+        if self.artists.is_empty() {
+            self.artists = artists;
+        }
+
+        self.album = self.album.take().or(album);
+        self.disc = self.disc.or(disc);
+        self.track = self.track.or(track);
+        self.total_discs = self.total_discs.or(total_discs);
+        self.title = self.title.take().or(title);
+        self.track_gain = self.track_gain.or(track_gain);
+        self.disc_subtitle = self.disc_subtitle.take().or(disc_subtitle);
+        self.artist_sort = self.artist_sort.take().or(artist_sort);
+        self.album_sort = self.album_sort.take().or(album_sort);
+        self.bpm = self.bpm.or(bpm);
+        self.year = self.year.or(year);
+        self.original_year = self.original_year.or(original_year);
+        self.codec = self.codec.take().or(codec);
+        self.cover = self.cover.or(cover);
+        self.has_lyrics |= has_lyrics;
+        self.sample_rate = self.sample_rate.or(sample_rate);
+        self.bit_depth = self.bit_depth.or(bit_depth);
+        self.channels = self.channels.or(channels);
+
+        if self.ext.is_empty() {
+            self.ext = ext;
         }
     }
 
+    #[cfg(feature = "mp3")]
     fn from_id3(path: impl AsRef<Path>) -> Result<Self> {
         let tag = match id3::Tag::read_from_path(path) {
             Ok(tag) => tag,
             Err(err) => err.partial_tag.clone().ok_or(err)?,
         };
 
-        let artist = if let Some(artist) = tag.album_artist() {
-            Some(artist.to_owned())
+        let mut metadata = Self::from_id3_tag(tag, "mp3")?;
+        metadata.codec = Some("mp3".into());
+
+        Ok(metadata)
+    }
+
+    /// AIFF files are IFF containers, tags are stored as an id3 tag inside the `ID3 ` chunk.
+    #[cfg(feature = "mp3")]
+    fn from_aiff(path: impl AsRef<Path>) -> Result<Self> {
+        let mut file = File::open(path)?;
+        file.seek(SeekFrom::Start(12))?;
+
+        let mut header = [0; 8];
+        while file.read_exact(&mut header).is_ok() {
+            let mut size = [0; 4];
+            size.copy_from_slice(&header[4..8]);
+            let size = u32::from_be_bytes(size);
+
+            if let b"ID3 " | b"id3 " = &header[0..4] {
+                // The size isn't trusted, it only bounds what's read from the file
+                let chunk = (&mut file).take(u64::from(size));
+                let tag = match id3::Tag::read_from(chunk) {
+                    Ok(tag) => tag,
+                    Err(err) => err.partial_tag.clone().ok_or(err)?,
+                };
+
+                return Self::from_id3_tag(tag, "aiff");
+            }
+
+            // Chunks are padded to an even size
+            file.seek(SeekFrom::Current(
+                (u64::from(size) + u64::from(size % 2)) as i64,
+            ))?;
+        }
+
+        Ok(Metadata {
+            ext: "aiff".to_owned(),
+            ..Default::default()
+        })
+    }
+
+    /// Matroska tags apply to a target level, the artist is taken from the album level
+    /// and the title from the track level (files with only album level tags, as written
+    /// by most muxers, use them for the title instead).
+    fn from_matroska(path: impl AsRef<Path>) -> Result<Self> {
+        let tags = matroska::read_tags(path.as_ref())?;
+
+        let find = |level: u64, name: &str| {
+            tags.iter()
+                .find(|tag| tag.level == level && tag.name.eq_ignore_ascii_case(name))
+                .map(|tag| tag.value.clone())
+        };
+
+        let (album, title) = if tags.iter().any(|tag| tag.level == TRACK_LEVEL) {
+            (
+                find(ALBUM_LEVEL, "ALBUM").or_else(|| find(ALBUM_LEVEL, "TITLE")),
+                find(TRACK_LEVEL, "TITLE"),
+            )
         } else {
-            tag.artist().map(|s| s.to_owned())
+            (find(ALBUM_LEVEL, "ALBUM"), find(ALBUM_LEVEL, "TITLE"))
+        };
+
+        Ok(Metadata {
+            artist: find(ALBUM_LEVEL, "ALBUM_ARTIST")
+                .or_else(|| find(ALBUM_LEVEL, "ARTIST"))
+                .or_else(|| find(TRACK_LEVEL, "ARTIST")),
+            album,
+            disc: find(PART_LEVEL, "PART_NUMBER").and_then(|n| n.trim().parse().ok()),
+            track: find(TRACK_LEVEL, "PART_NUMBER").and_then(|n| n.trim().parse().ok()),
+            // Parts of a level are counted by the level above
+            total_discs: find(ALBUM_LEVEL, "TOTAL_PARTS").and_then(|n| n.trim().parse().ok()),
+            title,
+            ext: "mka".to_owned(),
+            year: find(ALBUM_LEVEL, "DATE_RELEASED").and_then(|d| Self::parse_year(&d)),
+            ..Default::default()
+        })
+    }
+
+    /// Monkey's Audio and WavPack files are both commonly tagged with APEv2 tags.
+    fn from_ape(path: impl AsRef<Path>) -> Result<Self> {
+        let tag = ape::read(path.as_ref())?;
+
+        // APEv2 keys are case insensitive
+        let text = |key: &str| {
+            tag.iter()
+                .find(|item| item.key.eq_ignore_ascii_case(key))
+                .and_then(|item| match &item.value {
+                    ape::ItemValue::Text(text) => Some(text.to_owned()),
+                    _ => None,
+                })
         };
 
+        // Numbers may be stored as `n/total`
+        let number = |key: &str| {
+            text(key)
+                .and_then(|n| n.split('/').next().map(|n| n.trim().to_owned()))
+                .and_then(|n| n.parse::<u32>().ok())
+        };
+
+        let total = |key: &str| {
+            text(key)
+                .and_then(|n| n.split('/').nth(1).map(|n| n.trim().to_owned()))
+                .and_then(|n| n.parse::<u32>().ok())
+        };
+
+        let artists = text("Album Artist")
+            .or_else(|| text("Artist"))
+            .map(|artists| Self::split_values(&artists))
+            .unwrap_or_default();
+
+        // Binary cover items hold the image after its (NUL terminated) file name
+        let cover = tag
+            .iter()
+            .find(|item| item.key.eq_ignore_ascii_case("Cover Art (Front)"))
+            .and_then(|item| match &item.value {
+                ape::ItemValue::Binary(data) => Some(data),
+                _ => None,
+            })
+            .map(|data| match data.iter().position(|b| *b == 0) {
+                Some(nul) => CoverArt::from_data(&data[nul + 1..]),
+                None => CoverArt::from_data(data),
+            });
+
+        let ext = path
+            .as_ref()
+            .extension()
+            .map(|s| s.to_string_lossy().to_string())
+            .unwrap_or_else(|| "ape".to_string());
+
+        Ok(Metadata {
+            artist: artists.first().cloned(),
+            artists,
+            album: text("Album"),
+            disc: number("Disc"),
+            track: number("Track"),
+            total_discs: total("Disc"),
+            title: text("Title"),
+            ext,
+            track_gain: text("REPLAYGAIN_TRACK_GAIN").and_then(|g| Self::parse_gain(&g)),
+            disc_subtitle: text("DiscSubtitle"),
+            artist_sort: text("AlbumArtistSort").or_else(|| text("ArtistSort")),
+            album_sort: text("AlbumSort"),
+            bpm: text("BPM").and_then(|bpm| Self::parse_bpm(&bpm)),
+            year: text("Year").and_then(|year| Self::parse_year(&year)),
+            original_year: text("OriginalDate")
+                .or_else(|| text("OriginalYear"))
+                .and_then(|year| Self::parse_year(&year)),
+            codec: None,
+            cover,
+            has_lyrics: tag
+                .iter()
+                .any(|item| item.key.eq_ignore_ascii_case("Lyrics")),
+            sample_rate: None,
+            bit_depth: None,
+            channels: None,
+        })
+    }
+
+    #[cfg(feature = "mp3")]
+    fn from_id3_tag(tag: id3::Tag, ext: &str) -> Result<Self> {
+        let artists = tag
+            .album_artist()
+            .or_else(|| tag.artist())
+            .map(Self::split_values)
+            .unwrap_or_default();
+        let artist = artists.first().cloned();
+
         let album = tag.album().map(|s| s.to_owned());
         let disc = tag.disc();
         let track = tag.track();
+        let total_discs = tag.total_discs();
         let title = tag.title().map(|s| s.to_owned());
+        let track_gain = tag
+            .extended_texts()
+            .find(|text| {
+                text.description
+                    .eq_ignore_ascii_case("REPLAYGAIN_TRACK_GAIN")
+            })
+            .and_then(|text| Self::parse_gain(&text.value));
+
+        let text = |id: &str| {
+            tag.get(id)
+                .and_then(|frame| frame.content().text())
+                .map(|s| s.to_owned())
+        };
+
+        let disc_subtitle = text("TSST");
+        let artist_sort = text("TSO2").or_else(|| text("TSOP"));
+        let album_sort = text("TSOA");
+        let bpm = text("TBPM").and_then(|bpm| Self::parse_bpm(&bpm));
+
+        // id3v2.4 frames first, then their id3v2.3 counterparts
+        let year = text("TDRC")
+            .or_else(|| text("TYER"))
+            .and_then(|year| Self::parse_year(&year));
+
+        let original_year = text("TDOR")
+            .or_else(|| text("TORY"))
+            .and_then(|year| Self::parse_year(&year));
+
+        let cover = tag
+            .pictures()
+            .next()
+            .map(|picture| CoverArt::from_data(&picture.data));
+
+        // Only the presence of the frames matters, the lyrics themselves aren't kept
+        let has_lyrics = tag.get("USLT").is_some() || tag.get("SYLT").is_some();
 
         Ok(Metadata {
             artist,
+            artists,
             album,
             disc,
             track,
+            total_discs,
             title,
-            ext: "mp3".to_owned(),
+            ext: ext.to_owned(),
+            track_gain,
+            disc_subtitle,
+            artist_sort,
+            album_sort,
+            bpm,
+            year,
+            original_year,
+            codec: None,
+            cover,
+            has_lyrics,
+            sample_rate: None,
+            bit_depth: None,
+            channels: None,
         })
     }
 
-    fn from_flac_vorbis(path: impl AsRef<Path>) -> Result<Self> {
-        let tag = metaflac::Tag::read_from_path(path)?;
-        let comments = tag
-            .vorbis_comments()
-            .ok_or(Error::EmptyComments)?
-            .comments
-            .to_owned();
+    /// Only the stream info, the vorbis comment block and the start of the first picture
+    /// are read, every other metadata block (e.g. padding, which may be several MiB) is
+    /// skipped over.
+    #[cfg(feature = "flac")]
+    fn from_flac_vorbis(path: impl AsRef<Path>, aliases: &HashMap<String, String>) -> Result<Self> {
+        let path = path.as_ref();
+        let blocks = Self::read_flac_blocks(path)?;
+        let block = blocks.comments.ok_or(Error::EmptyComments)?;
+
+        let comments = match metaflac::block::VorbisComment::from_bytes(&block) {
+            Ok(vorbis) => vorbis.comments,
+            Err(err) => {
+                let comments = Self::parse_comment_block(&block).ok_or(err)?;
+                log::warn!("Recovered tags from malformed file: \"{}\"", path.display());
+                comments
+            }
+        };
+
+        let mut metadata = Self::from_vorbis_comments(comments, aliases, "flac")?;
+        metadata.codec = Some("flac".into());
+        metadata.cover = blocks.cover;
+
+        if let Some(info) = blocks.stream_info {
+            metadata.sample_rate = Some(info.sample_rate);
+            metadata.bit_depth = Some(u32::from(info.bits_per_sample));
+            metadata.channels = Some(u32::from(info.num_channels));
+        }
 
-        Self::from_vorbis_comments(comments, "flac")
+        Ok(metadata)
     }
 
-    fn from_ogg_vorbis(path: impl AsRef<Path>) -> Result<Self> {
-        let file = File::open(path)?;
-        let mut reader = ogg::reading::PacketReader::new(file);
-        let ((_, comments, _), _) = lewton::inside_ogg::read_headers(&mut reader)?;
-        let comments = Self::ogg_comment_map(comments.comment_list);
+    /// Walks the metadata blocks by hand stopping once the vorbis comment block and the
+    /// first picture are found, so problems in any other block don't matter either.
+    #[cfg(feature = "flac")]
+    fn read_flac_blocks(path: &Path) -> Result<FlacBlocks> {
+        let mut file = File::open(path)?;
+        let mut magic = [0; 4];
+        file.read_exact(&mut magic)?;
+        if &magic != b"fLaC" {
+            return Err(Error::NotSupported);
+        }
+
+        let mut blocks = FlacBlocks::default();
+
+        loop {
+            let mut header = [0; 4];
+            file.read_exact(&mut header)?;
+
+            let is_last = header[0] & 0x80 != 0;
+            let length = u32::from_be_bytes([0, header[1], header[2], header[3]]);
+
+            match header[0] & 0x7f {
+                0 if blocks.stream_info.is_none() => {
+                    let mut block = Vec::new();
+                    (&mut file)
+                        .take(u64::from(length))
+                        .read_to_end(&mut block)?;
 
-        Self::from_vorbis_comments(comments, "ogg")
+                    // `StreamInfo::from_bytes` panics with short blocks
+                    if block.len() >= 34 {
+                        blocks.stream_info = Some(metaflac::block::StreamInfo::from_bytes(&block));
+                    }
+                }
+
+                4 if blocks.comments.is_none() => {
+                    let mut block = Vec::new();
+                    (&mut file)
+                        .take(u64::from(length))
+                        .read_to_end(&mut block)?;
+                    blocks.comments = Some(block);
+                }
+
+                6 if blocks.cover.is_none() => {
+                    let mut block = Vec::new();
+                    (&mut file)
+                        .take(u64::from(length.min(FLAC_PICTURE_PREFIX)))
+                        .read_to_end(&mut block)?;
+
+                    file.seek(SeekFrom::Current(i64::from(length) - block.len() as i64))?;
+                    blocks.cover = Some(Self::parse_flac_picture(&block));
+                }
+
+                _ => {
+                    file.seek(SeekFrom::Current(i64::from(length)))?;
+                }
+            }
+
+            // The stream info is always the first block
+            if is_last || (blocks.comments.is_some() && blocks.cover.is_some()) {
+                return Ok(blocks);
+            }
+        }
     }
 
-    fn from_vorbis_comments(comments: HashMap<String, Vec<String>>, ext: &str) -> Result<Self> {
-        let artist = if let Some(artist) = comments.get("ALBUMARTIST").and_then(|a| a.get(0)) {
-            Some(artist.to_owned())
-        } else {
-            comments
-                .get("ARTIST")
-                .map(|a| a.get(0).map(|s| s.to_owned()))
-                .flatten()
+    /// Picture blocks record the image dimensions, the image header is only decoded if
+    /// they're missing (i.e. zero).
+    #[cfg(feature = "flac")]
+    fn parse_flac_picture(block: &[u8]) -> CoverArt {
+        let field = |offset: usize| {
+            block
+                .get(offset..offset + 4)
+                .map(|b| u32::from_be_bytes([b[0], b[1], b[2], b[3]]))
         };
 
+        // Dimensions come right after the (variable length) mime type and description
+        let offset = match field(4)
+            .map(|mime_len| 8 + mime_len as usize)
+            .and_then(|desc_offset| Some(desc_offset + 4 + field(desc_offset)? as usize))
+        {
+            Some(offset) => offset,
+            None => return CoverArt { dimensions: None },
+        };
+
+        match (field(offset), field(offset + 4)) {
+            (Some(width), Some(height)) if width > 0 && height > 0 => CoverArt {
+                dimensions: Some((width, height)),
+            },
+
+            _ => CoverArt::from_data(block.get(offset + 20..).unwrap_or(&[])),
+        }
+    }
+
+    /// Leniently parses a vorbis comment block (without the framing bit), keeping the
+    /// comments read before the first malformed one.
+    #[cfg(any(feature = "flac", feature = "ogg"))]
+    fn parse_comment_block(block: &[u8]) -> Option<HashMap<String, Vec<String>>> {
+        fn take<'a>(block: &mut &'a [u8], n: usize) -> Option<&'a [u8]> {
+            if block.len() < n {
+                return None;
+            }
+
+            let (taken, rest) = block.split_at(n);
+            *block = rest;
+            Some(taken)
+        }
+
+        fn take_len(block: &mut &[u8]) -> Option<usize> {
+            let bytes = take(block, 4)?;
+            Some(u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]) as usize)
+        }
+
+        let mut block = block;
+        let vendor_len = take_len(&mut block)?;
+        take(&mut block, vendor_len)?;
+        let count = take_len(&mut block)?;
+
+        let mut comments = Vec::new();
+        for _ in 0..count {
+            let comment = take_len(&mut block).and_then(|len| take(&mut block, len));
+            let comment = match comment.map(String::from_utf8_lossy) {
+                Some(comment) => comment,
+                None => break,
+            };
+
+            if let Some((key, value)) = comment.split_once('=') {
+                comments.push((key.to_ascii_uppercase(), value.to_owned()));
+            }
+        }
+
+        Some(Self::ogg_comment_map(comments))
+    }
+
+    #[cfg(feature = "ogg")]
+    fn from_ogg_vorbis(path: impl AsRef<Path>, aliases: &HashMap<String, String>) -> Result<Self> {
+        let file = File::open(&path)?;
+        let mut reader = ogg::reading::PacketReader::new(file);
+        let (comments, ident, codec) = match lewton::inside_ogg::read_headers(&mut reader) {
+            Ok(((ident, comments, _), _)) => (
+                Self::ogg_comment_map(comments.comment_list),
+                Some(ident),
+                "vorbis",
+            ),
+            Err(err) => {
+                let (comments, codec) = Self::read_ogg_comments(path.as_ref()).ok_or(err)?;
+                (comments, None, codec)
+            }
+        };
+
+        let ext = if codec == "opus" { "opus" } else { "ogg" };
+        let mut metadata = Self::from_vorbis_comments(comments, aliases, ext)?;
+        metadata.codec = Some(codec.into());
+        if let Some(ident) = ident {
+            metadata.sample_rate = Some(ident.audio_sample_rate);
+            metadata.channels = Some(u32::from(ident.audio_channels));
+        }
+
+        Ok(metadata)
+    }
+
+    /// Recovery for malformed OGG files (and the only way Opus streams, which lewton can't
+    /// decode, are read), only the comment header (the second packet) is parsed, so
+    /// problems in the setup header don't matter. The codec is returned along the tags.
+    #[cfg(feature = "ogg")]
+    fn read_ogg_comments(path: &Path) -> Option<(HashMap<String, Vec<String>>, &'static str)> {
+        let file = File::open(path).ok()?;
+        let mut reader = ogg::reading::PacketReader::new(file);
+        let head = reader.read_packet().ok()??;
+        let packet = reader.read_packet().ok()??;
+
+        if head.data.starts_with(b"OpusHead") {
+            let block = packet.data.strip_prefix(b"OpusTags")?;
+            return Some((Self::parse_comment_block(block)?, "opus"));
+        }
+
+        let block = packet.data.strip_prefix(b"\x03vorbis")?;
+        log::warn!("Recovered tags from malformed file: \"{}\"", path.display());
+        Some((Self::parse_comment_block(block)?, "vorbis"))
+    }
+
+    /// Copies the values of aliased keys (custom aliases first, then the built-in ones) to
+    /// their standard key, unless it's already present.
+    #[cfg(any(feature = "flac", feature = "ogg"))]
+    fn resolve_vorbis_aliases(
+        comments: &mut HashMap<String, Vec<String>>,
+        aliases: &HashMap<String, String>,
+    ) {
+        let custom = aliases
+            .iter()
+            .map(|(alias, key)| (alias.to_ascii_uppercase(), key.to_ascii_uppercase()));
+        let builtin = VORBIS_ALIASES
+            .iter()
+            .map(|(alias, key)| (alias.to_string(), key.to_string()));
+
+        for (alias, key) in custom.chain(builtin) {
+            if comments.contains_key(&key) {
+                continue;
+            }
+
+            if let Some(values) = comments.get(&alias).cloned() {
+                comments.insert(key, values);
+            }
+        }
+    }
+
+    #[cfg(any(feature = "flac", feature = "ogg"))]
+    fn from_vorbis_comments(
+        mut comments: HashMap<String, Vec<String>>,
+        aliases: &HashMap<String, String>,
+        ext: &str,
+    ) -> Result<Self> {
+        Self::resolve_vorbis_aliases(&mut comments, aliases);
+
+        let artists = comments
+            .get("ALBUMARTIST")
+            .filter(|a| !a.is_empty())
+            .or_else(|| comments.get("ARTIST"))
+            .cloned()
+            .unwrap_or_default();
+        let artist = artists.first().cloned();
+
         let album = comments
             .get("ALBUM")
-            .map(|a| a.get(0).map(|s| s.to_owned()))
-            .flatten();
+            .and_then(|a| a.first().map(|s| s.to_owned()));
 
+        // Numbers may be stored as `n/total`
         let disc = comments
             .get("DISCNUMBER")
-            .map(|d| d.get(0).map(|s| s.parse::<u32>().ok()))
-            .flatten()
-            .flatten();
+            .and_then(|d| d.first())
+            .and_then(|s| s.split('/').next())
+            .and_then(|s| s.trim().parse::<u32>().ok());
+
+        let total_discs = comments
+            .get("DISCTOTAL")
+            .or_else(|| comments.get("TOTALDISCS"))
+            .and_then(|d| d.first())
+            .map(|s| s.as_str())
+            .or_else(|| {
+                comments
+                    .get("DISCNUMBER")
+                    .and_then(|d| d.first())
+                    .and_then(|s| s.split('/').nth(1))
+            })
+            .and_then(|s| s.trim().parse::<u32>().ok());
 
         let track = comments
             .get("TRACKNUMBER")
-            .map(|t| t.get(0).map(|s| s.parse::<u32>().ok()))
-            .flatten()
-            .flatten();
+            .and_then(|t| t.first())
+            .and_then(|s| s.split('/').next())
+            .and_then(|s| s.trim().parse::<u32>().ok());
 
         let title = comments
             .get("TITLE")
-            .map(|t| t.get(0).map(|s| s.to_owned()))
-            .flatten();
+            .and_then(|t| t.first().map(|s| s.to_owned()));
+
+        let track_gain = comments
+            .get("REPLAYGAIN_TRACK_GAIN")
+            .and_then(|g| g.first())
+            .and_then(|s| Self::parse_gain(s));
+
+        let disc_subtitle = comments
+            .get("DISCSUBTITLE")
+            .and_then(|d| d.first().map(|s| s.to_owned()));
+
+        let artist_sort = comments
+            .get("ALBUMARTISTSORT")
+            .or_else(|| comments.get("ARTISTSORT"))
+            .and_then(|a| a.first().map(|s| s.to_owned()));
+
+        let album_sort = comments
+            .get("ALBUMSORT")
+            .and_then(|a| a.first().map(|s| s.to_owned()));
+
+        let bpm = comments
+            .get("BPM")
+            .and_then(|b| b.first())
+            .and_then(|s| Self::parse_bpm(s));
+
+        let year = comments
+            .get("DATE")
+            .or_else(|| comments.get("YEAR"))
+            .and_then(|d| d.first())
+            .and_then(|s| Self::parse_year(s));
+
+        let original_year = comments
+            .get("ORIGINALDATE")
+            .or_else(|| comments.get("ORIGINALYEAR"))
+            .and_then(|d| d.first())
+            .and_then(|s| Self::parse_year(s));
 
         Ok(Metadata {
             artist,
+            artists,
             album,
             disc,
             track,
+            total_discs,
             title,
             ext: ext.to_owned(),
+            track_gain,
+            disc_subtitle,
+            artist_sort,
+            album_sort,
+            bpm,
+            year,
+            original_year,
+            codec: None,
+            cover: None,
+            has_lyrics: comments.contains_key("LYRICS") || comments.contains_key("UNSYNCEDLYRICS"),
+            sample_rate: None,
+            bit_depth: None,
+            channels: None,
         })
     }
 
+    /// Parses a replaygain value, formatted like `-6.35 dB`.
+    fn parse_gain(gain: &str) -> Option<f64> {
+        let gain = gain.trim();
+        let gain = match gain.get(gain.len().saturating_sub(2)..) {
+            Some(unit) if unit.eq_ignore_ascii_case("db") => &gain[..gain.len() - 2],
+            _ => gain,
+        };
+
+        gain.trim().parse().ok()
+    }
+
+    /// Splits the values of an id3v2.4 text frame or an APEv2 item, which are separated
+    /// by NUL characters.
+    fn split_values(text: &str) -> Vec<String> {
+        text.split('\0')
+            .filter(|value| !value.is_empty())
+            .map(|value| value.to_owned())
+            .collect()
+    }
+
+    /// Parses a BPM, rounding values like `128.00`. Zero means unknown.
+    fn parse_bpm(bpm: &str) -> Option<u32> {
+        let bpm: f64 = bpm.trim().parse().ok()?;
+        let bpm = bpm.round();
+
+        if bpm >= 1.0 && bpm <= f64::from(u32::MAX) {
+            Some(bpm as u32)
+        } else {
+            None
+        }
+    }
+
+    /// Takes the year out of a date like `1969-09-26` (or just `1969`).
+    fn parse_year(date: &str) -> Option<u32> {
+        let year = date.trim().get(..4)?;
+
+        if year.bytes().all(|b| b.is_ascii_digit()) {
+            year.parse().ok()
+        } else {
+            None
+        }
+    }
+
+    #[cfg(any(feature = "flac", feature = "ogg"))]
     fn ogg_comment_map(list: Vec<(String, String)>) -> HashMap<String, Vec<String>> {
         let mut map = HashMap::new();
 
@@ -170,6 +995,7 @@ impl Metadata {
         map
     }
 
+    #[cfg(feature = "m4a")]
     fn from_m4a(path: impl AsRef<Path>) -> Result<Self> {
         let tag = mp4ameta::Tag::read_from_path(path.as_ref())?;
 
@@ -178,22 +1004,303 @@ impl Metadata {
             .or_else(|| tag.artist())
             .map(|a| a.to_string());
 
+        let mut artists: Vec<_> = tag.album_artists().map(|a| a.to_owned()).collect();
+        if artists.is_empty() {
+            artists = tag.artists().map(|a| a.to_owned()).collect();
+        }
+
+        // The codec is only informative, a file whose boxes can't be walked still sorts
+        let codec = match mp4::read_codec(path.as_ref()) {
+            Ok(codec) => codec.map(|codec| codec.to_owned()),
+            Err(e) => {
+                log::debug!(
+                    "Couldn't read codec of \"{}\" ({})",
+                    path.as_ref().display(),
+                    e
+                );
+                None
+            }
+        };
+
         let ext = path
             .as_ref()
             .extension()
             .map(|s| s.to_string_lossy().to_string())
             .unwrap_or_else(|| "m4a".to_string());
 
+        let string = |ident: &[u8; 4]| {
+            tag.string(mp4ameta::Ident(*ident))
+                .next()
+                .map(|s| s.to_owned())
+        };
+
         Ok(Metadata {
             artist,
+            artists,
             album: tag.album().map(|a| a.to_owned()),
             disc: tag.disc_number().map(|this_disk| this_disk.into()),
             track: tag.track_number().map(|this_track| this_track.into()),
+            total_discs: tag.total_discs().map(|total| total.into()),
             title: tag.title().map(|a| a.to_owned()),
             ext,
+            track_gain: None,
+            disc_subtitle: None,
+            artist_sort: string(b"soaa").or_else(|| string(b"soar")),
+            album_sort: string(b"soal"),
+            bpm: tag.bpm().map(u32::from).filter(|bpm| *bpm > 0),
+            year: tag.year().and_then(Self::parse_year),
+            original_year: None,
+            codec,
+            cover: tag.artwork().map(|artwork| match artwork {
+                mp4ameta::Data::Jpeg(data) | mp4ameta::Data::Png(data) => CoverArt::from_data(data),
+                _ => CoverArt { dimensions: None },
+            }),
+            has_lyrics: tag.lyrics().is_some(),
+            sample_rate: None,
+            bit_depth: None,
+            channels: None,
         })
     }
 
+    /// Writes the fields used for sorting (artists as album artists, album, disc, track and
+    /// title) back to the tags of `path`, missing fields are left untouched and so are the
+    /// totals of discs and tracks (unless the total of discs is known). The file is only
+    /// rewritten if something changed, which is what's returned.
+    pub fn write_to_path(&self, path: impl AsRef<Path>) -> Result<bool> {
+        let path = path.as_ref();
+        writer_for(path)?(self, path)
+    }
+
+    /// Values written by [`Metadata::write_to_path`], in the order of the docs. Artists are
+    /// separated by NUL if the tag has `multi_values` (only the artist used for sorting is
+    /// written otherwise), disc and track numbers keep the total of their `existing` value
+    /// (`n/total`), the one of discs is replaced by `total_discs` if given.
+    fn fields_to_write(
+        &self,
+        multi_values: bool,
+        total_discs: Option<u32>,
+        existing: impl Fn(usize) -> Option<String>,
+    ) -> [Option<String>; 5] {
+        let artist = match self.artists.is_empty() || !multi_values {
+            true => self.artist.clone(),
+            false => Some(self.artists.join("\0")),
+        };
+
+        let numbered = |index: usize, number: Option<u32>, total: Option<u32>| {
+            let total = total.map(|total| total.to_string()).or_else(|| {
+                let existing = existing(index)?;
+                let (_, total) = existing.split_once('/')?;
+                Some(total.trim().to_owned()).filter(|total| !total.is_empty())
+            });
+
+            number.map(|number| match total {
+                Some(total) => format!("{}/{}", number, total),
+                None => number.to_string(),
+            })
+        };
+
+        [
+            artist,
+            self.album.clone(),
+            numbered(2, self.disc, total_discs),
+            numbered(3, self.track, None),
+            self.title.clone(),
+        ]
+    }
+
+    #[cfg(feature = "flac")]
+    fn write_flac(&self, path: &Path) -> Result<bool> {
+        const KEYS: [&str; 5] = ["ALBUMARTIST", "ALBUM", "DISCNUMBER", "TRACKNUMBER", "TITLE"];
+
+        let mut tag = metaflac::Tag::read_from_path(path)?;
+        let comments = tag.vorbis_comments_mut();
+        let mut changed = false;
+
+        // The total of discs has its own comment, the one of tracks is left as is
+        let fields = self.fields_to_write(true, None, |index| {
+            comments.get(KEYS[index])?.first().cloned()
+        });
+
+        for (key, value) in KEYS.iter().zip(fields) {
+            if let Some(value) = value {
+                let values: Vec<_> = value.split('\0').map(|value| value.to_owned()).collect();
+                if comments.get(key) != Some(&values) {
+                    comments.set(*key, values);
+                    changed = true;
+                }
+            }
+        }
+
+        if let Some(total) = self.total_discs.map(|total| total.to_string()) {
+            let existing = comments
+                .get("DISCTOTAL")
+                .or_else(|| comments.get("TOTALDISCS"))
+                .and_then(|values| values.first());
+
+            if existing != Some(&total) {
+                comments.remove("TOTALDISCS");
+                comments.set("DISCTOTAL", vec![total]);
+                changed = true;
+            }
+        }
+
+        if changed {
+            tag.save()?;
+        }
+
+        Ok(changed)
+    }
+
+    #[cfg(feature = "mp3")]
+    fn write_id3(&self, path: &Path) -> Result<bool> {
+        const IDS: [&str; 5] = ["TPE2", "TALB", "TPOS", "TRCK", "TIT2"];
+
+        let mut tag = match id3::Tag::read_from_path(path) {
+            Ok(tag) => tag,
+            Err(e) if matches!(e.kind, id3::ErrorKind::NoTag) => id3::Tag::new(),
+            Err(e) => return Err(e.into()),
+        };
+
+        // Only v2.4 defines several values per frame (separated by NUL)
+        let version = Self::id3_version(path)?;
+        let multi_values = version == id3::Version::Id3v24;
+
+        let text = |id: &str| tag.get(id).and_then(|frame| frame.content().text());
+        let fields = self.fields_to_write(multi_values, self.total_discs, |index| {
+            text(IDS[index]).map(|text| text.to_owned())
+        });
+
+        let mut changed = false;
+
+        for (id, value) in IDS.iter().zip(fields) {
+            if let Some(value) = value {
+                if tag.get(id).and_then(|frame| frame.content().text()) != Some(&value) {
+                    tag.set_text(*id, value);
+                    changed = true;
+                }
+            }
+        }
+
+        if changed {
+            tag.write_to_path(path, version)?;
+        }
+
+        Ok(changed)
+    }
+
+    #[cfg(feature = "m4a")]
+    fn write_m4a(&self, path: &Path) -> Result<bool> {
+        use std::convert::TryFrom;
+
+        let mut tag = mp4ameta::Tag::read_from_path(path)?;
+        let mut changed = false;
+
+        let strings = [
+            (b"aART", &self.artist),
+            (b"\xa9alb", &self.album),
+            (b"\xa9nam", &self.title),
+        ];
+        for (ident, value) in strings.iter() {
+            let ident = mp4ameta::Ident(**ident);
+            if let Some(value) = value {
+                if tag.string(ident).next() != Some(value) {
+                    tag.set_data(ident, mp4ameta::Data::Utf8(value.clone()));
+                    changed = true;
+                }
+            }
+        }
+
+        // Numbers are stored as 16 bits, larger ones can't be written
+        let disc = self.disc.and_then(|disc| u16::try_from(disc).ok());
+        if let Some(disc) = disc.filter(|disc| tag.disc_number() != Some(*disc)) {
+            tag.set_disc_number(disc);
+            changed = true;
+        }
+
+        let total_discs = self.total_discs.and_then(|total| u16::try_from(total).ok());
+        if let Some(total) = total_discs.filter(|total| tag.total_discs() != Some(*total)) {
+            tag.set_total_discs(total);
+            changed = true;
+        }
+
+        let track = self.track.and_then(|track| u16::try_from(track).ok());
+        if let Some(track) = track.filter(|track| tag.track_number() != Some(*track)) {
+            tag.set_track_number(track);
+            changed = true;
+        }
+
+        if changed {
+            tag.write_to_path(path)?;
+        }
+
+        Ok(changed)
+    }
+
+    /// Version of the id3 tag at the start of `path`, files without one get a v2.4 tag.
+    #[cfg(feature = "mp3")]
+    fn id3_version(path: &Path) -> Result<id3::Version> {
+        let mut header = [0; 4];
+        if File::open(path)?.read_exact(&mut header).is_err() {
+            return Ok(id3::Version::Id3v24);
+        }
+
+        Ok(match header {
+            [b'I', b'D', b'3', 2] => id3::Version::Id3v22,
+            [b'I', b'D', b'3', 3] => id3::Version::Id3v23,
+            _ => id3::Version::Id3v24,
+        })
+    }
+
+    fn write_ape(&self, path: &Path) -> Result<bool> {
+        const KEYS: [&str; 5] = ["Album Artist", "Album", "Disc", "Track", "Title"];
+
+        let mut tag = match ape::read(path) {
+            Ok(tag) => tag,
+            Err(ape::Error::TagNotFound) => ape::Tag::new(),
+            Err(e) => return Err(e.into()),
+        };
+
+        let fields = self.fields_to_write(true, self.total_discs, |index| {
+            let item = tag
+                .iter()
+                .find(|item| item.key.eq_ignore_ascii_case(KEYS[index]))?;
+            match &item.value {
+                ape::ItemValue::Text(text) => Some(text.clone()),
+                _ => None,
+            }
+        });
+
+        let mut changed = false;
+
+        for (key, value) in KEYS.iter().zip(fields) {
+            let value = match value {
+                Some(value) => value,
+                None => continue,
+            };
+
+            // Keys are case insensitive, the existing one is replaced whatever its case
+            let existing = tag.iter().find(|item| item.key.eq_ignore_ascii_case(key));
+
+            if let Some(existing) = existing {
+                if matches!(&existing.value, ape::ItemValue::Text(text) if *text == value) {
+                    continue;
+                }
+
+                let existing = existing.key.to_string();
+                tag.remove_item(&existing);
+            }
+
+            tag.set_item(ape::Item::from_text(*key, value)?);
+            changed = true;
+        }
+
+        if changed {
+            ape::write(&tag, path)?;
+        }
+
+        Ok(changed)
+    }
+
     pub fn get_artist(&self) -> Result<String> {
         impl_tag_getter!(self, artist)
     }
@@ -202,6 +1309,30 @@ impl Metadata {
         impl_tag_getter!(self, album)
     }
 
+    /// Same as [`Metadata::get_artist`] but keeps only the first artist when there are
+    /// several joined by `separator` (e.g. `;` in `Artist A; Artist B`).
+    pub fn get_primary_artist(&self, separator: &str) -> Result<String> {
+        self.get_artist()
+            .map(|artist| primary_artist(&artist, separator).to_owned())
+    }
+
+    /// Same as [`Metadata::get_artist`] but prefers the sort variant if present.
+    pub fn get_artist_sort(&self) -> Result<String> {
+        match &self.artist_sort {
+            Some(artist) => Ok(artist.to_owned()),
+            None => self.get_artist(),
+        }
+    }
+
+    /// Same as [`Metadata::get_album`] but prefers the sort variant if present.
+    pub fn get_album_sort(&self) -> Result<String> {
+        match &self.album_sort {
+            Some(album) => Ok(album.to_owned()),
+            None => self.get_album(),
+        }
+    }
+
+    /// Always digits, like [`Metadata::get_track`], as both are stored as numbers.
     pub fn get_disc(&self) -> Result<String> {
         impl_tag_getter!(self, disc)
     }
@@ -214,13 +1345,238 @@ impl Metadata {
         impl_tag_getter!(self, title)
     }
 
+    pub fn get_bpm(&self) -> Result<String> {
+        impl_tag_getter!(self, bpm)
+    }
+
+    pub fn get_sample_rate(&self) -> Result<String> {
+        impl_tag_getter!(self, sample_rate)
+    }
+
+    pub fn get_bit_depth(&self) -> Result<String> {
+        impl_tag_getter!(self, bit_depth)
+    }
+
+    pub fn get_channels(&self) -> Result<String> {
+        impl_tag_getter!(self, channels)
+    }
+
+    pub fn get_year(&self) -> Result<String> {
+        impl_tag_getter!(self, year)
+    }
+
+    pub fn get_original_year(&self) -> Result<String> {
+        impl_tag_getter!(self, original_year)
+    }
+
+    pub fn get_disc_subtitle(&self) -> Result<String> {
+        impl_tag_getter!(self, disc_subtitle)
+    }
+
     pub fn get_ext(&self) -> String {
         self.ext.clone()
     }
+
+    /// The codec of the audio stream, or the (lowercased) extension when it's unknown.
+    pub fn get_codec(&self) -> String {
+        self.codec
+            .clone()
+            .unwrap_or_else(|| self.ext.to_lowercase())
+    }
+
+    /// Either `with-art` or `no-art`, depending on whether there's embedded cover art.
+    pub fn get_has_art(&self) -> String {
+        match self.cover {
+            Some(_) => "with-art".into(),
+            None => "no-art".into(),
+        }
+    }
+
+    /// Either `lyrics` or `no-lyrics`, depending on whether there are embedded lyrics.
+    pub fn get_has_lyrics(&self) -> String {
+        match self.has_lyrics {
+            true => "lyrics".into(),
+            false => "no-lyrics".into(),
+        }
+    }
+
+    /// Coarse loudness bucket (`quiet`, `normal` or `loud`) based on the replaygain
+    /// track gain.
+    pub fn get_loudness(&self) -> Result<String> {
+        let gain = self.track_gain.ok_or_else(|| Error::MissingTag {
+            tag: "loudness".into(),
+        })?;
+
+        let bucket = if gain <= LOUD_GAIN_THRESHOLD {
+            "loud"
+        } else if gain >= QUIET_GAIN_THRESHOLD {
+            "quiet"
+        } else {
+            "normal"
+        };
+
+        Ok(bucket.to_owned())
+    }
 }
 
 #[cfg(test)]
 mod tests {
+    use super::*;
+
+    #[test]
+    #[cfg(all(feature = "flac", feature = "m4a"))]
+    fn supported_paths() {
+        assert!(supported_mime_types().any(|mime| mime == "audio/x-flac"));
+        assert!(is_supported_path("test_files/complete.flac"));
+        assert!(is_supported_path("test_files/partial.m4p"));
+        assert!(!is_supported_path("Cargo.toml"));
+        assert!(!is_supported_path("test_files/missing.mp3"));
+    }
+
+    #[test]
+    fn parse_gain() {
+        assert_eq!(Metadata::parse_gain("-6.35 dB"), Some(-6.35));
+        assert_eq!(Metadata::parse_gain("+1.20 db"), Some(1.2));
+        assert_eq!(Metadata::parse_gain("-10"), Some(-10.0));
+        assert_eq!(Metadata::parse_gain("loud"), None);
+    }
+
+    #[test]
+    fn parse_bpm() {
+        assert_eq!(Metadata::parse_bpm("128"), Some(128));
+        assert_eq!(Metadata::parse_bpm("127.60"), Some(128));
+        assert_eq!(Metadata::parse_bpm(" 90.00 "), Some(90));
+        assert_eq!(Metadata::parse_bpm("0"), None);
+        assert_eq!(Metadata::parse_bpm("fast"), None);
+    }
+
+    #[test]
+    fn parse_year() {
+        assert_eq!(Metadata::parse_year("1969"), Some(1969));
+        assert_eq!(Metadata::parse_year("1969-09-26"), Some(1969));
+        assert_eq!(Metadata::parse_year(" 2003-01 "), Some(2003));
+        assert_eq!(Metadata::parse_year("69"), None);
+        assert_eq!(Metadata::parse_year("Sept 1969"), None);
+    }
+
+    #[test]
+    #[cfg(any(feature = "flac", feature = "ogg"))]
+    fn vorbis_aliases() -> crate::Result<()> {
+        let comments = |pairs: &[(&str, &str)]| {
+            pairs
+                .iter()
+                .map(|(key, value)| (key.to_string(), vec![value.to_string()]))
+                .collect::<HashMap<_, _>>()
+        };
+
+        let mut aliases = HashMap::new();
+        aliases.insert("album_artist".to_string(), "albumartist".to_string());
+
+        let metadata = Metadata::from_vorbis_comments(
+            comments(&[("ALBUM ARTIST", "Band"), ("TRACK", "3/12")]),
+            &aliases,
+            "flac",
+        )?;
+        assert_eq!(metadata.artist.as_deref(), Some("Band"));
+        assert_eq!(metadata.track, Some(3));
+
+        let metadata = Metadata::from_vorbis_comments(
+            comments(&[("ALBUM_ARTIST", "Custom"), ("BAND", "Built-in")]),
+            &aliases,
+            "flac",
+        )?;
+        assert_eq!(metadata.artist.as_deref(), Some("Custom"));
+
+        let metadata = Metadata::from_vorbis_comments(
+            comments(&[("ALBUMARTIST", "Standard"), ("BAND", "Alias")]),
+            &aliases,
+            "flac",
+        )?;
+        assert_eq!(metadata.artist.as_deref(), Some("Standard"));
+
+        Ok(())
+    }
+
+    #[test]
+    fn merge_keeps_present_fields() {
+        let mut metadata = Metadata {
+            artist: Some("Artist".into()),
+            ext: "mp3".into(),
+            ..Default::default()
+        };
+
+        metadata.merge(Metadata {
+            artist: Some("Other".into()),
+            album: Some("Album".into()),
+            track: Some(2),
+            ext: "nfo".into(),
+            ..Default::default()
+        });
+
+        assert_eq!(metadata.artist.as_deref(), Some("Artist"));
+        assert_eq!(metadata.album.as_deref(), Some("Album"));
+        assert_eq!(metadata.track, Some(2));
+        assert_eq!(metadata.ext, "mp3");
+    }
+
+    #[test]
+    fn serde_round_trip() {
+        let metadata = Metadata {
+            artist: Some("Artist".into()),
+            track: Some(2),
+            total_discs: Some(1),
+            ext: "flac".into(),
+            has_lyrics: true,
+            ..Default::default()
+        };
+
+        let serialized = toml::to_string(&metadata).unwrap();
+        let deserialized: Metadata = toml::from_str(&serialized).unwrap();
+
+        assert_eq!(deserialized.artist.as_deref(), Some("Artist"));
+        assert_eq!(deserialized.track, Some(2));
+        assert_eq!(deserialized.total_discs, Some(1));
+        assert_eq!(deserialized.ext, "flac");
+        assert!(deserialized.has_lyrics);
+        assert!(deserialized.album.is_none());
+
+        let partial: Metadata = toml::from_str("title = 'Title'").unwrap();
+        assert_eq!(partial.title.as_deref(), Some("Title"));
+        assert!(partial.ext.is_empty());
+    }
+
+    #[test]
+    fn loudness_buckets() {
+        let bucket = |gain| {
+            Metadata {
+                track_gain: Some(gain),
+                ..Default::default()
+            }
+            .get_loudness()
+            .unwrap()
+        };
+
+        assert_eq!(bucket(-11.2), "loud");
+        assert_eq!(bucket(-6.35), "normal");
+        assert_eq!(bucket(-1.0), "quiet");
+        assert!(Metadata::default().get_loudness().is_err());
+    }
+
+    #[test]
+    #[cfg(all(feature = "flac", feature = "mp3", feature = "ogg", feature = "m4a"))]
+    fn codecs() -> crate::Result<()> {
+        let codec =
+            |file| Metadata::from_path(format!("test_files/{}", file)).map(|m| m.get_codec());
+
+        assert_eq!(codec("complete.flac")?, "flac");
+        assert_eq!(codec("complete.mp3")?, "mp3");
+        assert_eq!(codec("complete.ogg")?, "vorbis");
+        assert_eq!(codec("complete.m4a")?, "aac");
+        assert_eq!(codec("complete.ape")?, "ape");
+
+        Ok(())
+    }
+
     macro_rules! define_unit_test_for {
         ($ext:ident) => {
             #[cfg(test)]
@@ -267,9 +1623,273 @@ mod tests {
         };
     }
 
+    #[test]
+    #[cfg(feature = "flac")]
+    fn malformed_flac() -> crate::Result<()> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("malformed.flac");
+
+        // Invalid UTF-8 inside the first comment ("COMMENT=Processed by SoX")
+        let mut bytes = std::fs::read("test_files/complete.flac")?;
+        bytes[120] = 0xff;
+        std::fs::write(&path, &bytes)?;
+
+        assert!(metaflac::Tag::read_from_path(&path).is_err());
+
+        let metadata = Metadata::from_path(&path)?;
+        assert_eq!("Album Artist", &metadata.get_artist()?);
+        assert_eq!("Title", &metadata.get_title()?);
+        assert_eq!("1", &metadata.get_track()?);
+
+        Ok(())
+    }
+
+    #[test]
+    #[cfg(all(feature = "flac", feature = "cover-size"))]
+    fn flac_cover_art() -> crate::Result<()> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("cover.flac");
+
+        let mut png = Vec::new();
+        image::DynamicImage::new_rgb8(4, 3)
+            .write_to(&mut png, image::ImageOutputFormat::Png)
+            .unwrap();
+
+        // Picture block without recorded dimensions, so the header has to be decoded
+        let mut picture = Vec::new();
+        picture.extend_from_slice(&3u32.to_be_bytes());
+        picture.extend_from_slice(&9u32.to_be_bytes());
+        picture.extend_from_slice(b"image/png");
+        picture.extend_from_slice(&[0; 20]);
+        picture.extend_from_slice(&(png.len() as u32).to_be_bytes());
+        picture.extend_from_slice(&png);
+
+        let mut block = vec![6];
+        block.extend_from_slice(&(picture.len() as u32).to_be_bytes()[1..]);
+        block.extend_from_slice(&picture);
+
+        // Right after the stream info block
+        let mut bytes = std::fs::read("test_files/complete.flac")?;
+        bytes.splice(42..42, block);
+        std::fs::write(&path, &bytes)?;
+
+        let metadata = Metadata::from_path(&path)?;
+        assert_eq!("Title", &metadata.get_title()?);
+        assert_eq!(
+            metadata.cover.and_then(|cover| cover.dimensions),
+            Some((4, 3))
+        );
+        assert_eq!("with-art", &metadata.get_has_art());
+
+        let metadata = Metadata::from_path("test_files/complete.flac")?;
+        assert_eq!("no-art", &metadata.get_has_art());
+
+        Ok(())
+    }
+
+    #[test]
+    #[cfg(all(feature = "flac", feature = "ogg"))]
+    fn stream_properties() -> crate::Result<()> {
+        let metadata = Metadata::from_path("test_files/complete.flac")?;
+        assert_eq!("48000", &metadata.get_sample_rate()?);
+        assert_eq!("16", &metadata.get_bit_depth()?);
+        assert_eq!("2", &metadata.get_channels()?);
+
+        let metadata = Metadata::from_path("test_files/complete.ogg")?;
+        assert_eq!("44100", &metadata.get_sample_rate()?);
+        assert_eq!("1", &metadata.get_channels()?);
+        assert!(metadata.get_bit_depth().is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    #[cfg(all(feature = "flac", feature = "mp3", feature = "m4a"))]
+    fn write_to_path() -> crate::Result<()> {
+        let dir = tempfile::tempdir()?;
+
+        for file in &[
+            "complete.flac",
+            "complete.mp3",
+            "complete.m4a",
+            "complete.ape",
+        ] {
+            let path = dir.path().join(file);
+            std::fs::copy(Path::new("test_files").join(file), &path)?;
+
+            let mut metadata = Metadata::from_path(&path)?;
+            metadata.title = Some("Fixed".into());
+            metadata.disc = Some(2);
+            assert!(metadata.write_to_path(&path)?, "{}", file);
+
+            let written = Metadata::from_path(&path)?;
+            assert_eq!(written.title.as_deref(), Some("Fixed"), "{}", file);
+            assert_eq!(written.disc, Some(2), "{}", file);
+            assert_eq!(written.artist, metadata.artist, "{}", file);
+
+            // Nothing left to fix
+            assert!(!written.write_to_path(&path)?, "{}", file);
+        }
+
+        let ogg = dir.path().join("complete.ogg");
+        std::fs::copy("test_files/complete.ogg", &ogg)?;
+        assert!(matches!(
+            Metadata::default().write_to_path(&ogg),
+            Err(Error::NotSupported)
+        ));
+
+        Ok(())
+    }
+
+    #[test]
+    #[cfg(all(feature = "flac", feature = "mp3", feature = "m4a"))]
+    fn write_keeps_totals_and_artists() -> crate::Result<()> {
+        let dir = tempfile::tempdir()?;
+
+        for file in &[
+            "complete.flac",
+            "complete.mp3",
+            "complete.m4a",
+            "complete.ape",
+        ] {
+            let path = dir.path().join(file);
+            std::fs::copy(Path::new("test_files").join(file), &path)?;
+
+            let mut metadata = Metadata::from_path(&path)?;
+            metadata.artists = vec!["First".into(), "Second".into()];
+            metadata.artist = Some("First".into());
+            metadata.total_discs = Some(3);
+            assert!(metadata.write_to_path(&path)?, "{}", file);
+
+            let written = Metadata::from_path(&path)?;
+            assert_eq!(written.total_discs, Some(3), "{}", file);
+            assert_eq!(written.artist.as_deref(), Some("First"), "{}", file);
+            // The id3 reader only keeps the first value of a frame
+            if *file != "complete.m4a" && *file != "complete.mp3" {
+                assert_eq!(written.artists, metadata.artists, "{}", file);
+            }
+        }
+
+        // The total of tracks is kept and so is the version of the tag (v2.3)
+        let mp3 = dir.path().join("complete.mp3");
+        let mut tag = id3::Tag::read_from_path(&mp3)?;
+        tag.set_text("TRCK", "1/12");
+        tag.write_to_path(&mp3, id3::Version::Id3v23)?;
+
+        let mut metadata = Metadata::from_path(&mp3)?;
+        metadata.track = Some(2);
+        assert!(metadata.write_to_path(&mp3)?);
+
+        let tag = id3::Tag::read_from_path(&mp3)?;
+        assert_eq!(
+            tag.get("TRCK").and_then(|f| f.content().text()),
+            Some("2/12")
+        );
+        assert_eq!(&std::fs::read(&mp3)?[..4], b"ID3\x03");
+
+        // v2.3 has no multiple values, only the artist used for sorting is written
+        metadata.artists = vec!["Third".into(), "Fourth".into()];
+        metadata.artist = Some("Third".into());
+        assert!(metadata.write_to_path(&mp3)?);
+
+        let tag = id3::Tag::read_from_path(&mp3)?;
+        assert_eq!(
+            tag.get("TPE2").and_then(|f| f.content().text()),
+            Some("Third")
+        );
+        let bytes = std::fs::read(&mp3)?;
+        let utf16 = |be: bool| -> Vec<u8> {
+            "Fourth"
+                .encode_utf16()
+                .flat_map(|unit| match be {
+                    true => unit.to_be_bytes(),
+                    false => unit.to_le_bytes(),
+                })
+                .collect()
+        };
+        for needle in &[b"Fourth".to_vec(), utf16(false), utf16(true)] {
+            assert!(!bytes
+                .windows(needle.len())
+                .any(|bytes| bytes == &needle[..]));
+        }
+
+        let written = Metadata::from_path(&mp3)?;
+        assert_eq!(written.artists, vec!["Third".to_owned()]);
+        assert!(!metadata.write_to_path(&mp3)?);
+
+        tag.write_to_path(&mp3, id3::Version::Id3v24)?;
+        metadata.artists = vec!["First".into(), "Second".into()];
+        metadata.artist = Some("First".into());
+        assert!(metadata.write_to_path(&mp3)?);
+
+        let written = std::fs::read(&mp3)?;
+        assert!(written.windows(12).any(|bytes| bytes == b"First\0Second"));
+
+        Ok(())
+    }
+
+    #[test]
+    #[cfg(feature = "mp3")]
+    fn aiff_bogus_chunk_sizes() -> crate::Result<()> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("bogus.aiff");
+
+        // A chunk claiming the largest size is skipped past the end of the file
+        let mut aiff = b"FORM\0\0\0\x1cAIFFCOMM\xff\xff\xff\xff".to_vec();
+        std::fs::write(&path, &aiff)?;
+        assert_eq!(Metadata::from_aiff(&path)?.ext, "aiff");
+
+        // An id3 chunk claiming more than the file has fails without reading past it
+        aiff.truncate(12);
+        aiff.extend_from_slice(b"ID3 \xff\xff\xff\xffID3");
+        std::fs::write(&path, &aiff)?;
+        assert!(Metadata::from_aiff(&path).is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    #[cfg(feature = "m4a")]
+    fn generic_mp4_brand() -> crate::Result<()> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("generic.m4a");
+
+        let mut bytes = std::fs::read("test_files/complete.m4a")?;
+        bytes[8..12].copy_from_slice(b"isom");
+        std::fs::write(&path, &bytes)?;
+
+        assert_eq!(
+            infer::get(&bytes).map(|ftype| ftype.mime_type()),
+            Some("video/mp4")
+        );
+
+        let metadata = Metadata::from_path(&path)?;
+        assert_eq!("Album Artist", &metadata.get_artist()?);
+        assert_eq!("m4a", &metadata.get_ext());
+
+        // Actual videos are still left alone
+        let video = dir.path().join("video.mp4");
+        std::fs::write(&video, &bytes)?;
+        assert!(!is_supported_path(&video));
+
+        Ok(())
+    }
+
+    #[cfg(feature = "flac")]
     define_unit_test_for!(flac);
+    #[cfg(feature = "mp3")]
     define_unit_test_for!(mp3);
+    #[cfg(feature = "ogg")]
     define_unit_test_for!(ogg);
+    #[cfg(feature = "m4a")]
     define_unit_test_for!(m4a);
+    #[cfg(feature = "m4a")]
     define_unit_test_for!(m4p);
+    #[cfg(feature = "m4a")]
+    define_unit_test_for!(m4b);
+    define_unit_test_for!(mka);
+    #[cfg(feature = "mp3")]
+    define_unit_test_for!(aiff);
+    define_unit_test_for!(ape);
+    define_unit_test_for!(wv);
 }
@@ -0,0 +1,238 @@
+// Copyright (C) 2020 kevin
+//
+// This file is part of muso.
+//
+// muso is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// muso is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with muso.  If not, see <http://www.gnu.org/licenses/>.
+
+//! Just enough of EBML to read the tags out of Matroska files.
+
+use std::fs::File;
+use std::io::{self, BufReader, ErrorKind, Read, Seek, SeekFrom};
+use std::path::Path;
+
+const SEGMENT: u32 = 0x1853_8067;
+const CLUSTER: u32 = 0x1F43_B675;
+const TAGS: u32 = 0x1254_C367;
+const TAG: u32 = 0x7373;
+const TARGETS: u32 = 0x63C0;
+const TARGET_TYPE_VALUE: u32 = 0x68CA;
+const SIMPLE_TAG: u32 = 0x67C8;
+const TAG_NAME: u32 = 0x45A3;
+const TAG_STRING: u32 = 0x4487;
+
+/// Target levels (as defined by Matroska) tags may apply to.
+pub const TRACK_LEVEL: u64 = 30;
+pub const PART_LEVEL: u64 = 40;
+pub const ALBUM_LEVEL: u64 = 50;
+
+#[derive(Debug, Clone)]
+pub struct SimpleTag {
+    pub level: u64,
+    pub name: String,
+    pub value: String,
+}
+
+/// Reads every simple tag in the file, along with the level of its target.
+pub fn read_tags(path: &Path) -> io::Result<Vec<SimpleTag>> {
+    let file = File::open(path)?;
+    let file_len = file.metadata()?.len();
+    let mut file = BufReader::new(file);
+    let mut tags = Vec::new();
+
+    loop {
+        let header = match read_header(&mut file) {
+            Ok(header) => header,
+            Err(e) if e.kind() == ErrorKind::UnexpectedEof => break,
+            Err(e) => return Err(e),
+        };
+
+        match header {
+            // Elements are read as a flat stream, so live recordings (whose segment and
+            // clusters have an unknown size) can still be walked
+            (SEGMENT, _) | (CLUSTER, None) => continue,
+
+            (TAGS, Some(size)) => {
+                // Sizes can claim up to 2^56 bytes, they're never trusted beyond the file
+                if size > file_len {
+                    return Err(io::Error::new(
+                        ErrorKind::InvalidData,
+                        "tags larger than the file",
+                    ));
+                }
+
+                let mut buf = vec![0; size as usize];
+                file.read_exact(&mut buf)?;
+                read_tag_elements(&buf, &mut tags);
+            }
+
+            (_, Some(size)) => {
+                file.seek(SeekFrom::Current(size as i64))?;
+            }
+
+            (_, None) => break,
+        }
+    }
+
+    Ok(tags)
+}
+
+fn read_tag_elements(buf: &[u8], tags: &mut Vec<SimpleTag>) {
+    for (id, tag) in children(buf) {
+        if id != TAG {
+            continue;
+        }
+
+        let elements = children(tag);
+
+        // Tags without a target type apply to the whole album
+        let level = elements
+            .iter()
+            .filter(|(id, _)| *id == TARGETS)
+            .flat_map(|(_, targets)| children(targets))
+            .find(|(id, _)| *id == TARGET_TYPE_VALUE)
+            .map(|(_, value)| read_uint(value))
+            .unwrap_or(ALBUM_LEVEL);
+
+        for (id, simple_tag) in elements {
+            if id != SIMPLE_TAG {
+                continue;
+            }
+
+            let mut name = None;
+            let mut value = None;
+
+            for (id, body) in children(simple_tag) {
+                match id {
+                    TAG_NAME => name = Some(read_string(body)),
+                    TAG_STRING => value = Some(read_string(body)),
+                    _ => {}
+                }
+            }
+
+            if let (Some(name), Some(value)) = (name, value) {
+                tags.push(SimpleTag { level, name, value });
+            }
+        }
+    }
+}
+
+/// Splits `buf` into its (sized) child elements, stopping at the first malformed one.
+fn children(mut buf: &[u8]) -> Vec<(u32, &[u8])> {
+    let mut children = Vec::new();
+
+    while let Ok((id, Some(size))) = read_header(&mut buf) {
+        if size > buf.len() as u64 {
+            break;
+        }
+
+        let (body, rest) = buf.split_at(size as usize);
+        children.push((id, body));
+        buf = rest;
+    }
+
+    children
+}
+
+/// Reads an element ID and its size (`None` when unknown).
+fn read_header(reader: &mut impl Read) -> io::Result<(u32, Option<u64>)> {
+    let (id, _) = read_vint(reader, true)?;
+    let (size, len) = read_vint(reader, false)?;
+
+    if size == (1 << (7 * len)) - 1 {
+        Ok((id as u32, None))
+    } else {
+        Ok((id as u32, Some(size)))
+    }
+}
+
+/// Reads a variable length integer, IDs keep their length marker.
+fn read_vint(reader: &mut impl Read, keep_marker: bool) -> io::Result<(u64, usize)> {
+    let mut first = [0; 1];
+    reader.read_exact(&mut first)?;
+
+    let len = first[0].leading_zeros() as usize + 1;
+    if len > 8 {
+        return Err(io::Error::new(
+            ErrorKind::InvalidData,
+            "invalid EBML integer",
+        ));
+    }
+
+    let mut value = u64::from(first[0]);
+    if !keep_marker {
+        value &= 0xff >> len;
+    }
+
+    let mut rest = [0; 7];
+    reader.read_exact(&mut rest[..len - 1])?;
+
+    for byte in &rest[..len - 1] {
+        value = value << 8 | u64::from(*byte);
+    }
+
+    Ok((value, len))
+}
+
+fn read_uint(buf: &[u8]) -> u64 {
+    buf.iter()
+        .fold(0, |value, byte| value << 8 | u64::from(*byte))
+}
+
+fn read_string(buf: &[u8]) -> String {
+    String::from_utf8_lossy(buf)
+        .trim_end_matches('\0')
+        .to_owned()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn unknown_segment_size() -> io::Result<()> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("live.mka");
+
+        // Live recordings are written without knowing the segment size beforehand
+        let bytes = std::fs::read("test_files/complete.mka")?;
+        assert_eq!(&bytes[0x28..0x2c], &SEGMENT.to_be_bytes());
+
+        let mut live = bytes[..0x2c].to_vec();
+        live.extend_from_slice(&[0x01, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff]);
+        live.extend_from_slice(&bytes[0x2e..]);
+        std::fs::write(&path, live)?;
+
+        let tags = read_tags(&path)?;
+        assert!(tags
+            .iter()
+            .any(|tag| tag.level == TRACK_LEVEL && tag.name == "TITLE" && tag.value == "Title"));
+
+        Ok(())
+    }
+
+    #[test]
+    fn tags_larger_than_the_file() -> io::Result<()> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("bogus.mka");
+
+        let mut bogus = TAGS.to_be_bytes().to_vec();
+        bogus.extend_from_slice(&[0x01, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xfe]);
+        std::fs::write(&path, bogus)?;
+
+        let err = read_tags(&path).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidData);
+
+        Ok(())
+    }
+}
@@ -0,0 +1,85 @@
+// Copyright (C) 2020 kevin
+//
+// This file is part of muso.
+//
+// muso is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// muso is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with muso.  If not, see <http://www.gnu.org/licenses/>.
+
+//! Just enough of the MP4 box structure to tell the codec of an audio track.
+
+use std::fs::File;
+use std::io::{self, BufReader, Read, Seek, SeekFrom};
+use std::path::Path;
+
+/// Boxes leading to the sample descriptions of the first track.
+const STSD_PATH: [&[u8; 4]; 6] = [b"moov", b"trak", b"mdia", b"minf", b"stbl", b"stsd"];
+
+/// Reads the codec of the first track out of its sample description, only AAC and ALAC
+/// are recognized.
+pub fn read_codec(path: &Path) -> io::Result<Option<&'static str>> {
+    let mut file = BufReader::new(File::open(path)?);
+    let mut end = file.get_ref().metadata()?.len();
+
+    for name in STSD_PATH.iter() {
+        match find_box(&mut file, name, end)? {
+            Some(box_end) => end = box_end,
+            None => return Ok(None),
+        }
+    }
+
+    // Version, flags and entry count come before the first sample entry, whose type is
+    // right after its size
+    let mut buf = [0; 16];
+    file.read_exact(&mut buf)?;
+
+    Ok(match &buf[12..16] {
+        b"mp4a" => Some("aac"),
+        b"alac" => Some("alac"),
+        _ => None,
+    })
+}
+
+/// Looks for a box named `name` among the ones between the current position and `end`,
+/// leaving the reader at its contents and returning where it ends.
+fn find_box<R: Read + Seek>(reader: &mut R, name: &[u8; 4], end: u64) -> io::Result<Option<u64>> {
+    let mut start = reader.stream_position()?;
+
+    while start + 8 <= end {
+        let mut header = [0; 8];
+        reader.read_exact(&mut header)?;
+
+        let size = u32::from_be_bytes([header[0], header[1], header[2], header[3]]);
+        let (size, header_len) = match size {
+            0 => (end - start, 8),
+            1 => {
+                let mut large = [0; 8];
+                reader.read_exact(&mut large)?;
+                (u64::from_be_bytes(large), 16)
+            }
+            size => (u64::from(size), 8),
+        };
+
+        if size < header_len {
+            return Ok(None);
+        }
+
+        if &header[4..8] == name {
+            return Ok(Some(start + size));
+        }
+
+        start += size;
+        reader.seek(SeekFrom::Start(start))?;
+    }
+
+    Ok(None)
+}
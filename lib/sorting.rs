@@ -1,13 +1,76 @@
+mod journal;
+
 use std::borrow::Borrow;
+use std::collections::{HashMap, HashSet};
+use std::io::{self, Read};
 use std::path::Path;
+use std::process::Command;
+use std::str::FromStr;
 use std::{fs, path::PathBuf};
 
-use crate::format::ParsedFormat;
-use crate::metadata::Metadata;
+use ignore::gitignore::{Gitignore, GitignoreBuilder};
+use ignore::Match;
+use serde::Deserialize;
+
+use crate::format::{FormatOptions, ParsedFormat, TargetFs};
+use crate::metadata::{self, Metadata};
 use crate::utils;
 use crate::{Error, Result};
 
-#[derive(Debug, Clone)]
+pub use self::journal::{Journal, JournalStatus};
+
+/// What to do when a file would end up in a destination that's already taken.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ConflictPolicy {
+    /// Leave the file where it is.
+    #[default]
+    Skip,
+    /// Append a counter to the file name, e.g. `Title (1).flac`.
+    Rename,
+}
+
+/// What to do with a file missing a tag required by the format.
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum MissingTagPolicy {
+    /// Leave the file where it is, counted as a failure.
+    #[default]
+    Error,
+    /// Leave the file where it is, without counting it as a failure.
+    Skip,
+    /// Use this literal instead (e.g. `Unknown`), so the file is still sorted.
+    Placeholder(String),
+}
+
+impl FromStr for MissingTagPolicy {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.split_once('=') {
+            Some(("placeholder", placeholder)) => {
+                Ok(MissingTagPolicy::Placeholder(placeholder.into()))
+            }
+            None if s == "error" => Ok(MissingTagPolicy::Error),
+            None if s == "skip" => Ok(MissingTagPolicy::Skip),
+            _ => Err(Error::InvalidMissingTagPolicy { policy: s.into() }),
+        }
+    }
+}
+
+impl FromStr for ConflictPolicy {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "skip" => Ok(ConflictPolicy::Skip),
+            "rename" => Ok(ConflictPolicy::Rename),
+            _ => Err(Error::InvalidConflictPolicy { policy: s.into() }),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default)]
 pub struct Options<P>
 where
     P: Borrow<ParsedFormat>,
@@ -15,35 +78,409 @@ where
     pub format: P,
     pub dryrun: bool,
     pub recursive: bool,
-    pub exfat_compat: bool,
+
     pub remove_empty: bool,
+    pub char_map: HashMap<char, String>,
+    pub clean_leftovers: Vec<String>,
+    pub conflict: ConflictPolicy,
+
+    /// Albums (in the same folder) with at least this many distinct artists are
+    /// considered compilations and filed under "Various Artists".
+    pub compilation_threshold: Option<usize>,
+
+    /// Permissions applied to the folders created while sorting (unix only).
+    pub dir_mode: Option<u32>,
+
+    /// Shell command run after every file is moved, with `MUSO_SRC` and `MUSO_DST` set to
+    /// its old and new path.
+    pub each_file_hook: Option<String>,
+
+    /// Compare source and destination after copying a file across filesystems, before
+    /// removing the source.
+    pub verify: bool,
+
+    /// Stop at the first error instead of skipping the failing file.
+    pub fail_fast: bool,
+
+    /// Omit directory levels whose optional placeholders are all missing.
+    pub collapse_empty_dirs: bool,
+
+    /// Prefer the sort variants of artist and album tags.
+    pub prefer_sort_tags: bool,
+
+    /// Files smaller than this many bytes are skipped.
+    pub min_size: Option<u64>,
+
+    /// Folder new paths are relative to, defaults to the `root` being sorted.
+    pub dest_root: Option<PathBuf>,
+
+    /// Create the destination folder (along with its parents) if it doesn't exist, instead
+    /// of failing. Only logged in dryruns.
+    pub create_root: bool,
+
+    /// Preferred extensions for `{ext}`, only cosmetic.
+    pub ext_map: HashMap<String, String>,
+
+    /// Lowercase `{ext}` (e.g. `MP3` to `mp3`).
+    pub lowercase_ext: bool,
+
+    /// Take artist and album from one track per folder and apply them to all of its
+    /// tracks, so a single mistagged track can't split an album.
+    pub album_mode: bool,
+
+    /// Separator of multiple artists (e.g. `;`), only the first one is used in folders.
+    pub split_artist: Option<String>,
+
+    /// Prefix destinations with the folder each file was found in (relative to the folder
+    /// being sorted), so distinct collections sorted together don't merge.
+    pub preserve_source_tree: bool,
+
+    /// Descend into symlinked folders while walking, folders reached more than once
+    /// (e.g. through a symlink loop) are only sorted the first time.
+    pub follow_symlinks: bool,
+
+    /// Leave files whose path already matches the format without reading their tags,
+    /// so files retagged in place aren't moved again.
+    pub skip_conforming: bool,
+
+    /// What to do with files missing a required tag.
+    pub missing_tag_policy: MissingTagPolicy,
+
+    /// Don't fall back to the release year when `{originalyear}` is missing.
+    pub strict_original_year: bool,
+
+    /// Append-only log of the moves (ignored in dryruns), so an interrupted run can be
+    /// resumed with [`resume_journal`] or undone with [`rollback_journal`].
+    pub journal: Option<PathBuf>,
+
+    /// Write the tags used for sorting back to the files (ignored in dryruns), e.g. the
+    /// artist as album artist, so players agree with the folders.
+    pub fix_tags: bool,
+
+    /// Also walk hidden files and folders (e.g. `.git` or `.DS_Store`), which are skipped
+    /// by default. The folder being sorted is walked even if it's hidden.
+    pub include_hidden: bool,
+
+    /// Filesystem the files are sorted into, which decides the characters (and names)
+    /// avoided in new paths.
+    pub target_fs: TargetFs,
+
+    /// Non-standard vorbis comment keys (e.g. `ALBUM ARTIST`) read in place of standard
+    /// ones (e.g. `ALBUMARTIST`) when those are missing, on top of the built-in aliases.
+    pub vorbis_aliases: HashMap<String, String>,
+
+    /// Refuse to sort unless the destination is on the same device as this file (e.g. one
+    /// kept on a network mount), so nothing ends up on the wrong disk while a mount is
+    /// missing. Only the existence of the file is checked on non-unix systems.
+    pub device_sentinel: Option<PathBuf>,
+}
+
+impl<P> Options<P>
+where
+    P: Borrow<ParsedFormat>,
+{
+    pub fn format_options(&self) -> FormatOptions<'_> {
+        FormatOptions {
+            target_fs: self.target_fs,
+            char_map: Some(&self.char_map),
+            source_path: None,
+            collapse_empty_dirs: self.collapse_empty_dirs,
+            prefer_sort_tags: self.prefer_sort_tags,
+            ext_map: Some(&self.ext_map),
+            lowercase_ext: self.lowercase_ext,
+            split_artist: self.split_artist.as_deref(),
+            missing_tag: match &self.missing_tag_policy {
+                MissingTagPolicy::Placeholder(placeholder) => Some(placeholder),
+                _ => None,
+            },
+            strict_original_year: self.strict_original_year,
+        }
+    }
+}
+
+impl Options<ParsedFormat> {
+    /// Starts building options with the defaults: the default format, not a dryrun, not
+    /// recursive and targeting a POSIX filesystem.
+    pub fn builder() -> OptionsBuilder {
+        OptionsBuilder {
+            options: Options::default(),
+        }
+    }
+}
+
+macro_rules! impl_builder_setters {
+    ($($field:ident: $ty:ty),* $(,)?) => {
+        $(
+            pub fn $field(mut self, $field: $ty) -> Self {
+                self.options.$field = $field;
+                self
+            }
+        )*
+    };
 }
 
+/// Builder for [`Options`], see [`Options::builder`].
 #[derive(Debug, Clone)]
+pub struct OptionsBuilder {
+    options: Options<ParsedFormat>,
+}
+
+impl OptionsBuilder {
+    impl_builder_setters! {
+        format: ParsedFormat,
+        dryrun: bool,
+        recursive: bool,
+        remove_empty: bool,
+        char_map: HashMap<char, String>,
+        clean_leftovers: Vec<String>,
+        conflict: ConflictPolicy,
+        compilation_threshold: Option<usize>,
+        dir_mode: Option<u32>,
+        each_file_hook: Option<String>,
+        verify: bool,
+        fail_fast: bool,
+        collapse_empty_dirs: bool,
+        prefer_sort_tags: bool,
+        min_size: Option<u64>,
+        dest_root: Option<PathBuf>,
+        create_root: bool,
+        ext_map: HashMap<String, String>,
+        lowercase_ext: bool,
+        album_mode: bool,
+        split_artist: Option<String>,
+        preserve_source_tree: bool,
+        follow_symlinks: bool,
+        skip_conforming: bool,
+        missing_tag_policy: MissingTagPolicy,
+        strict_original_year: bool,
+        journal: Option<PathBuf>,
+        fix_tags: bool,
+        include_hidden: bool,
+        target_fs: TargetFs,
+        vorbis_aliases: HashMap<String, String>,
+        device_sentinel: Option<PathBuf>,
+    }
+
+    /// Same as `target_fs(TargetFs::ExFat)`, or `TargetFs::Posix` when disabled. This is
+    /// the only place the old flag is still accepted, `Options` only has `target_fs`.
+    #[deprecated(note = "use `target_fs` instead")]
+    pub fn exfat_compat(self, exfat_compat: bool) -> Self {
+        self.target_fs(if exfat_compat {
+            TargetFs::ExFat
+        } else {
+            TargetFs::Posix
+        })
+    }
+
+    pub fn build(self) -> Options<ParsedFormat> {
+        self.options
+    }
+}
+
+/// Name of the file listing (gitignore-like) patterns to exclude from sorting, applies
+/// to the folder it's in and all of its subfolders.
+pub const IGNORE_FILE: &str = ".musoignore";
+
+/// Artist used for albums detected as compilations.
+pub const VARIOUS_ARTISTS: &str = "Various Artists";
+
+/// Log target of the line logged once per file sorted, so loggers can count files.
+pub const FILE_LOG_TARGET: &str = "muso::sorting::file";
+
+/// State shared between all the files sorted in the same run.
+#[derive(Debug, Default)]
+struct RunState {
+    /// Destinations already taken by other files, along with the file that took them.
+    claimed: HashMap<PathBuf, PathBuf>,
+    /// Destinations wanted by more than one file, along with all of those files.
+    conflicts: HashMap<PathBuf, Vec<PathBuf>>,
+    /// Albums (along with their source folder) detected as compilations.
+    compilations: HashSet<(PathBuf, String)>,
+    /// Number of times the per-file hook failed.
+    hook_failures: usize,
+    /// Number of files found already in place.
+    in_place: usize,
+    /// Whether the filesystem must be left untouched.
+    dryrun: bool,
+    /// Moves decided so far.
+    moves: Vec<Move>,
+    /// Album level tags of each source folder (only in album mode).
+    albums: HashMap<PathBuf, Metadata>,
+    /// Where moves are recorded, if anywhere.
+    journal: Option<Journal>,
+    /// Folder being sorted (the root when sorting single files), source trees are kept
+    /// relative to it.
+    source_dir: Option<PathBuf>,
+}
+
+/// A file move decided while sorting, both paths are absolute.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Move {
+    pub from: PathBuf,
+    pub to: PathBuf,
+}
+
+#[derive(Debug, Clone, Default)]
 pub struct SortReport {
     pub success: usize,
     pub total: usize,
+
+    /// Files that weren't even tried (not supported or filtered out), these don't count
+    /// towards `total`.
+    pub skipped: usize,
+    pub collisions: usize,
+    pub hook_failures: usize,
+    pub dirs_removed: usize,
     pub new_paths: Vec<PathBuf>,
+
+    /// Files found already at their destination, these count towards `success`.
+    pub in_place: usize,
+
+    /// Destinations wanted by more than one file in this run, along with all of those
+    /// files (in the order they were found).
+    pub conflicts: HashMap<PathBuf, Vec<PathBuf>>,
 }
 
+#[cfg_attr(
+    feature = "tracing",
+    tracing::instrument(
+        skip_all,
+        fields(root = %root.as_ref().display(), dir = %dir.as_ref().display())
+    )
+)]
 pub fn sort_folder<R, D, P>(root: R, dir: D, options: &Options<P>) -> Result<SortReport>
 where
     R: AsRef<Path>,
     D: AsRef<Path>,
     P: Borrow<ParsedFormat>,
 {
-    let mut report = SortReport {
-        success: 0,
-        total: 0,
-        new_paths: Vec::new(),
+    let mut state = RunState {
+        dryrun: options.dryrun,
+        journal: open_journal(options)?,
+        ..Default::default()
     };
 
-    let dir = dir.as_ref().to_path_buf();
-    let mut stack = vec![dir];
+    walk_folder(root, dir, options, &mut state)
+}
+
+fn open_journal<P>(options: &Options<P>) -> Result<Option<Journal>>
+where
+    P: Borrow<ParsedFormat>,
+{
+    match &options.journal {
+        Some(path) if !options.dryrun => Journal::open(path).map(Some),
+        _ => Ok(None),
+    }
+}
+
+/// Computes the moves needed to sort `dir` without touching the filesystem, files that
+/// are already in place or can't be sorted aren't part of the plan.
+pub fn plan_folder<R, D, P>(root: R, dir: D, options: &Options<P>) -> Result<Vec<Move>>
+where
+    R: AsRef<Path>,
+    D: AsRef<Path>,
+    P: Borrow<ParsedFormat>,
+{
+    plan_folder_detailed(root, dir, options).map(|(plan, _)| plan)
+}
+
+/// Same as [`plan_folder`], along with the report of the simulated run (e.g. to know how
+/// many files are already in place).
+pub fn plan_folder_detailed<R, D, P>(
+    root: R,
+    dir: D,
+    options: &Options<P>,
+) -> Result<(Vec<Move>, SortReport)>
+where
+    R: AsRef<Path>,
+    D: AsRef<Path>,
+    P: Borrow<ParsedFormat>,
+{
+    let mut state = RunState {
+        dryrun: true,
+        ..Default::default()
+    };
+
+    let report = walk_folder(root, dir, options, &mut state)?;
+
+    Ok((state.moves, report))
+}
+
+/// Renders `plan` as a POSIX shell script (`mkdir -p` and `mv` commands) doing the same
+/// moves, so it can be reviewed and run by hand.
+pub fn plan_script(plan: &[Move]) -> String {
+    let mut script = String::from("#!/bin/sh\nset -e\n");
+    let mut created = HashSet::new();
+
+    for Move { from, to } in plan {
+        if let Some(parent) = to.parent() {
+            if created.insert(parent) {
+                script.push_str(&format!("\nmkdir -p {}\n", shell_quote(parent)));
+            }
+        }
+
+        script.push_str(&format!(
+            "mv -n {} {}\n",
+            shell_quote(from),
+            shell_quote(to)
+        ));
+    }
+
+    script
+}
+
+fn shell_quote(path: &Path) -> String {
+    format!("'{}'", path.to_string_lossy().replace('\'', "'\\''"))
+}
+
+/// Identifies a folder no matter which path (e.g. a symlink) it was reached through.
+#[cfg(unix)]
+fn dir_id(_path: &Path, metadata: &fs::Metadata) -> (u64, u64) {
+    use std::os::unix::fs::MetadataExt;
+
+    (metadata.dev(), metadata.ino())
+}
+
+#[cfg(not(unix))]
+fn dir_id(path: &Path, _metadata: &fs::Metadata) -> PathBuf {
+    path.canonicalize().unwrap_or_else(|_| path.to_path_buf())
+}
+
+fn walk_folder<R, D, P>(
+    root: R,
+    dir: D,
+    options: &Options<P>,
+    state: &mut RunState,
+) -> Result<SortReport>
+where
+    R: AsRef<Path>,
+    D: AsRef<Path>,
+    P: Borrow<ParsedFormat>,
+{
+    let mut report = SortReport::default();
+
+    // New paths are joined onto the root, and folders compared against it, so neither
+    // may depend on how they were spelled (e.g. relative or through a symlink)
+    let root = utils::canonicalize_or_given(root);
+    let dir = utils::canonicalize_or_given(dir);
+    prepare_dest(&root, options, state.dryrun)?;
+    state.source_dir = Some(dir.clone());
+
+    if let Some(threshold) = options.compilation_threshold {
+        state.compilations = find_compilations(&dir, threshold, options);
+    }
+
+    let mut stack = vec![(dir.clone(), Vec::new())];
+    let mut visited_dirs = Vec::new();
+    let mut seen_dirs = HashSet::new();
+
+    while let Some((path, ignores)) = stack.pop() {
+        // Errors reading the folder being sorted can't be recovered from
+        let is_top = path == dir;
 
-    while let Some(path) = stack.pop() {
         let metadata = match fs::metadata(&path) {
             Ok(metadata) => metadata,
+            Err(e) if is_top || options.fail_fast => return Err(e.into()),
             Err(e) => {
                 log::error!(
                     "Couldn't read metadata from: \"{}\" ({})",
@@ -55,13 +492,44 @@ where
         };
 
         if metadata.is_file() {
-            match sort_file(&root, path, options) {
-                Ok(new_path) => {
+            // Anything else (e.g. covers or playlists) is left alone, it isn't a failure
+            if !metadata::is_supported_path(&path) {
+                log::debug!("Not supported: \"{}\"", path.display());
+                report.skipped += 1;
+                continue;
+            }
+
+            match place_file(&root, &path, options, state) {
+                Ok((new_path, collided)) => {
+                    if collided {
+                        report.collisions += 1;
+                    }
+
                     report.success += 1;
                     report.total += 1;
                     report.new_paths.push(new_path);
                 }
 
+                Err(e @ Error::TooSmall { .. }) | Err(e @ Error::NotSupported) => {
+                    log::debug!("{}", e);
+                    report.skipped += 1;
+                }
+
+                Err(e @ Error::MissingTag { .. })
+                    if options.missing_tag_policy == MissingTagPolicy::Skip =>
+                {
+                    log::info!("Skipping: \"{}\" ({})", path.display(), e);
+                    report.skipped += 1;
+                }
+
+                Err(e) if options.fail_fast => return Err(e),
+
+                Err(e @ Error::DestinationTaken { .. }) => {
+                    log::error!("{}", e);
+                    report.collisions += 1;
+                    report.total += 1;
+                }
+
                 Err(e) => {
                     log::error!("{}", e);
                     report.total += 1;
@@ -71,69 +539,897 @@ where
             continue;
         }
 
+        if !is_top && !options.follow_symlinks && path.is_symlink() {
+            log::debug!("Not following symlink: \"{}\"", path.display());
+            continue;
+        }
+
+        // Symlinks can lead back to a folder that was already walked (or to its parents)
+        if !seen_dirs.insert(dir_id(&path, &metadata)) {
+            log::debug!("Already visited: \"{}\"", path.display());
+            continue;
+        }
+
         match fs::read_dir(&path) {
             Ok(entries) => {
-                let mut len = 0;
+                visited_dirs.push(path.clone());
+                let ignores = with_ignore_file(&path, ignores);
+                let mut files = Vec::new();
 
                 for entry in entries {
                     match entry {
                         Ok(entry) => {
-                            len += 1;
-                            stack.push(entry.path());
+                            let is_dir = matches!(entry.file_type(), Ok(t) if t.is_dir());
+                            if is_skipped(&entry, is_dir, &ignores, options.include_hidden) {
+                                log::debug!("Ignoring: \"{}\"", entry.path().display());
+                                continue;
+                            }
+
+                            if !is_dir {
+                                files.push(entry.path());
+                            }
+
+                            stack.push((entry.path(), ignores.clone()));
                         }
 
+                        Err(e) if options.fail_fast => return Err(e.into()),
                         Err(e) => {
                             log::error!("{}", e);
                         }
                     }
                 }
 
-                if options.remove_empty && len == 0 {
-                    log::info!("Removing empty folder: \"{}\"", path.display());
-                    if let Err(e) = fs::remove_dir(path) {
-                        log::error!("Couldn't remove dir ({})", e);
+                if options.album_mode {
+                    if let Some(album) = album_tags(files, &options.vorbis_aliases) {
+                        state.albums.insert(path.clone(), album);
                     }
                 }
             }
 
+            Err(e) if is_top || options.fail_fast => return Err(e.into()),
             Err(e) => {
                 log::error!("{}", e);
             }
         }
     }
 
+    if !state.dryrun && !options.clean_leftovers.is_empty() {
+        // Children are always visited after their parents, so going backwards lets a
+        // parent be cleaned once its children are gone
+        for dir in visited_dirs.iter().rev() {
+            if *dir == root {
+                continue;
+            }
+
+            match remove_leftovers(dir, &options.clean_leftovers) {
+                Ok(true) => report.dirs_removed += 1,
+                Ok(false) => {}
+                Err(e) => log::error!("Couldn't remove leftovers ({})", e),
+            }
+        }
+    }
+
+    if options.remove_empty && !state.dryrun {
+        report.dirs_removed += remove_empty_dirs(&root, &visited_dirs);
+    }
+
+    report.hook_failures = state.hook_failures;
+    report.in_place = state.in_place;
+    report.conflicts = std::mem::take(&mut state.conflicts);
+
     Ok(report)
 }
 
+/// Removes the empty folders in `visited` (except `root`) once every file has been
+/// moved, visiting children before their parents so nested empty folders go away too.
+/// Returns how many folders were removed.
+fn remove_empty_dirs(root: &Path, visited: &[PathBuf]) -> usize {
+    let mut removed = 0;
+
+    for dir in visited.iter().rev() {
+        if dir == root {
+            continue;
+        }
+
+        let is_empty = match fs::read_dir(dir) {
+            Ok(mut entries) => entries.next().is_none(),
+            Err(_) => continue,
+        };
+
+        if is_empty {
+            log::info!("Removing empty folder: \"{}\"", dir.display());
+            match fs::remove_dir(dir) {
+                Ok(()) => removed += 1,
+                Err(e) => log::error!("Couldn't remove dir ({})", e),
+            }
+        }
+    }
+
+    removed
+}
+
+/// Adds the matcher for the ignore file in `dir` (if any) to the ones inherited from its
+/// parents.
+fn with_ignore_file(dir: &Path, mut ignores: Vec<Gitignore>) -> Vec<Gitignore> {
+    let ignore_file = dir.join(IGNORE_FILE);
+    if !ignore_file.is_file() {
+        return ignores;
+    }
+
+    let mut builder = GitignoreBuilder::new(dir);
+    if let Some(e) = builder.add(&ignore_file) {
+        log::warn!("Invalid line in \"{}\" ({})", ignore_file.display(), e);
+    }
+
+    match builder.build() {
+        Ok(ignore) => ignores.push(ignore),
+        Err(e) => log::error!("Couldn't read \"{}\" ({})", ignore_file.display(), e),
+    }
+
+    ignores
+}
+
+/// Entries left out of walks: ignore files themselves, hidden entries (unless included)
+/// and anything an ignore file matches.
+fn is_skipped(entry: &fs::DirEntry, is_dir: bool, ignores: &[Gitignore], hidden: bool) -> bool {
+    let is_hidden = entry.file_name().to_string_lossy().starts_with('.');
+
+    entry.file_name() == IGNORE_FILE
+        || (is_hidden && !hidden)
+        || is_ignored(ignores, &entry.path(), is_dir)
+}
+
+/// The innermost ignore file with a matching pattern decides, like in git.
+fn is_ignored(ignores: &[Gitignore], path: &Path, is_dir: bool) -> bool {
+    for ignore in ignores.iter().rev() {
+        match ignore.matched(path, is_dir) {
+            Match::Ignore(_) => return true,
+            Match::Whitelist(_) => return false,
+            Match::None => {}
+        }
+    }
+
+    false
+}
+
+/// Picks the album level tags of a folder from its first track (by name) with an album.
+fn album_tags(mut files: Vec<PathBuf>, aliases: &HashMap<String, String>) -> Option<Metadata> {
+    files.sort();
+    files
+        .iter()
+        .filter_map(|file| Metadata::from_path_with_aliases(file, aliases).ok())
+        .find(|metadata| metadata.album.is_some())
+}
+
+/// Reads every file under `dir` looking for albums (grouped by their folder) with at
+/// least `threshold` distinct artists. Folders are walked like when sorting them, with
+/// the same symlink, hidden and ignore file rules.
+fn find_compilations<P>(
+    dir: &Path,
+    threshold: usize,
+    options: &Options<P>,
+) -> HashSet<(PathBuf, String)>
+where
+    P: Borrow<ParsedFormat>,
+{
+    let mut artists: HashMap<(PathBuf, String), HashSet<String>> = HashMap::new();
+    let mut stack = vec![(dir.to_path_buf(), Vec::new())];
+    let mut seen_dirs = HashSet::new();
+
+    while let Some((path, ignores)) = stack.pop() {
+        let metadata = match fs::metadata(&path) {
+            Ok(metadata) => metadata,
+            Err(_) => continue,
+        };
+
+        if metadata.is_dir() {
+            let is_top = path == dir;
+            if (!is_top && !options.follow_symlinks && path.is_symlink())
+                || !seen_dirs.insert(dir_id(&path, &metadata))
+            {
+                continue;
+            }
+
+            if let Ok(entries) = fs::read_dir(&path) {
+                let ignores = with_ignore_file(&path, ignores);
+
+                for entry in entries.filter_map(|entry| entry.ok()) {
+                    let is_dir = matches!(entry.file_type(), Ok(t) if t.is_dir());
+                    if !is_skipped(&entry, is_dir, &ignores, options.include_hidden) {
+                        stack.push((entry.path(), ignores.clone()));
+                    }
+                }
+            }
+
+            continue;
+        }
+
+        if !metadata::is_supported_path(&path) {
+            continue;
+        }
+
+        let metadata = match Metadata::from_path_with_aliases(&path, &options.vorbis_aliases) {
+            Ok(metadata) => metadata,
+            Err(_) => continue,
+        };
+
+        if let (Some(parent), Some(album), Some(artist)) =
+            (path.parent(), metadata.album, metadata.artist)
+        {
+            artists
+                .entry((parent.to_path_buf(), album))
+                .or_default()
+                .insert(artist);
+        }
+    }
+
+    artists
+        .into_iter()
+        .filter(|(_, artists)| artists.len() >= threshold)
+        .map(|(album, _)| album)
+        .collect()
+}
+
+/// Removes `dir` if it only contains leftovers, returns whether it was removed.
+fn remove_leftovers(dir: &Path, leftovers: &[String]) -> Result<bool> {
+    let entries = fs::read_dir(dir)?
+        .map(|entry| entry.map(|entry| entry.path()))
+        .collect::<std::io::Result<Vec<_>>>()?;
+
+    let is_leftover = |path: &PathBuf| {
+        let ext = match path.extension() {
+            Some(ext) if path.is_file() => ext.to_string_lossy().to_lowercase(),
+            _ => return false,
+        };
+
+        leftovers
+            .iter()
+            .any(|leftover| leftover.trim_start_matches('.').to_lowercase() == ext)
+    };
+
+    if entries.is_empty() || !entries.iter().all(is_leftover) {
+        return Ok(false);
+    }
+
+    for entry in entries {
+        log::info!("Removing leftover file: \"{}\"", entry.display());
+        fs::remove_file(entry)?;
+    }
+
+    log::info!("Removing leftover folder: \"{}\"", dir.display());
+    fs::remove_dir(dir)?;
+
+    Ok(true)
+}
+
 pub fn sort_file<R, F, P>(root: R, file: F, options: &Options<P>) -> Result<PathBuf>
 where
     R: AsRef<Path>,
     F: AsRef<Path>,
     P: Borrow<ParsedFormat>,
 {
-    if options.dryrun {
-        log::info!("Working on (dryrun): \"{}\"", file.as_ref().display());
+    let mut state = RunState {
+        dryrun: options.dryrun,
+        ..Default::default()
+    };
+
+    // Only the parent is resolved, a symlinked file is sorted as the link itself
+    let root = utils::canonicalize_or_given(root);
+    let file = file.as_ref();
+    let file = match (file.parent(), file.file_name()) {
+        (Some(parent), Some(name)) => utils::canonicalize_or_given(parent).join(name),
+        _ => file.to_path_buf(),
+    };
+
+    prepare_dest(&root, options, state.dryrun)?;
+    place_file(root, file, options, &mut state).map(|(new_path, _)| new_path)
+}
+
+/// Creates the destination of the files sorted from `root` if `options` asks for it, then
+/// checks it's on the right device.
+fn prepare_dest<P>(root: &Path, options: &Options<P>, dryrun: bool) -> Result<()>
+where
+    P: Borrow<ParsedFormat>,
+{
+    let dest = options.dest_root.as_deref().unwrap_or(root);
+
+    if options.create_root && !dest.exists() {
+        if dryrun {
+            log::info!("Would create folder: \"{}\"", dest.display());
+        } else {
+            log::info!("Creating folder: \"{}\"", dest.display());
+            create_dir(dest, options.dir_mode)?;
+        }
+    }
+
+    check_device(dest, options)
+}
+
+/// Fails unless `dest` is on the same device as the sentinel in `options` (if any).
+fn check_device<P>(dest: &Path, options: &Options<P>) -> Result<()>
+where
+    P: Borrow<ParsedFormat>,
+{
+    let sentinel = match &options.device_sentinel {
+        Some(sentinel) => sentinel,
+        None => return Ok(()),
+    };
+
+    let expected = fs::metadata(sentinel).map_err(|_| Error::SentinelNotFound {
+        path: sentinel.to_string_lossy().into(),
+    })?;
+
+    if device_of(&fs::metadata(dest)?) != device_of(&expected) {
+        return Err(Error::WrongDevice {
+            dest: dest.to_string_lossy().into(),
+            sentinel: sentinel.to_string_lossy().into(),
+        });
+    }
+
+    Ok(())
+}
+
+#[cfg(unix)]
+fn device_of(metadata: &fs::Metadata) -> Option<u64> {
+    use std::os::unix::fs::MetadataExt;
+
+    Some(metadata.dev())
+}
+
+#[cfg(not(unix))]
+fn device_of(_metadata: &fs::Metadata) -> Option<u64> {
+    None
+}
+
+/// Returns the path of `file` relative to `dest_root` if the format would build that same
+/// path from the tags it spells out, judging only by the path (tags aren't read).
+fn conforming_path<P>(dest_root: &Path, file: &Path, options: &Options<P>) -> Option<PathBuf>
+where
+    P: Borrow<ParsedFormat>,
+{
+    let relative = file.strip_prefix(dest_root).ok()?;
+    let format = options.format.borrow();
+    let metadata = format.extract(relative)?;
+
+    let built = format
+        .build_path(
+            &metadata,
+            &FormatOptions {
+                source_path: Some(relative),
+                ..options.format_options()
+            },
+        )
+        .ok()?;
+
+    if built == relative {
+        Some(built)
+    } else {
+        None
+    }
+}
+
+/// Writes the tags used for sorting back to `file`, failing to do so isn't fatal.
+fn fix_tags(metadata: &Metadata, file: &Path) {
+    match metadata.write_to_path(file) {
+        Ok(true) => log::info!("Fixed tags: \"{}\"", file.display()),
+        Ok(false) => {}
+        Err(Error::NotSupported) => {
+            log::warn!(
+                "Can't write tags of this kind of file: \"{}\"",
+                file.display()
+            )
+        }
+        Err(e) => log::error!("Couldn't fix tags of \"{}\" ({})", file.display(), e),
+    }
+}
+
+/// Sorts a single file, keeping track of the destinations claimed in the current run so
+/// that two different files never end up in the same place. Returns the new path (relative
+/// to `root`) and whether a collision was solved by renaming.
+#[cfg_attr(
+    feature = "tracing",
+    tracing::instrument(
+        name = "sort_file",
+        skip_all,
+        fields(file = %file.as_ref().display(), destination = tracing::field::Empty)
+    )
+)]
+fn place_file<R, F, P>(
+    root: R,
+    file: F,
+    options: &Options<P>,
+    state: &mut RunState,
+) -> Result<(PathBuf, bool)>
+where
+    R: AsRef<Path>,
+    F: AsRef<Path>,
+    P: Borrow<ParsedFormat>,
+{
+    if let Some(min_size) = options.min_size {
+        if fs::metadata(&file)?.len() < min_size {
+            return Err(Error::TooSmall {
+                path: file.as_ref().to_string_lossy().into(),
+            });
+        }
+    }
+
+    // Files may be sorted into a different folder than the one they're found in
+    let dest_root = options
+        .dest_root
+        .as_deref()
+        .unwrap_or_else(|| root.as_ref());
+
+    if options.skip_conforming {
+        if let Some(new_path) = conforming_path(dest_root, file.as_ref(), options) {
+            log::info!("Already in place: \"{}\"", new_path.display());
+            state.in_place += 1;
+            state
+                .claimed
+                .insert(file.as_ref().to_path_buf(), file.as_ref().to_path_buf());
+            return Ok((new_path, false));
+        }
+    }
+
+    if state.dryrun {
+        log::info!(target: FILE_LOG_TARGET, "Working on (dryrun): \"{}\"", file.as_ref().display());
     } else {
-        log::info!("Working on: \"{}\"", file.as_ref().display());
+        log::info!(target: FILE_LOG_TARGET, "Working on: \"{}\"", file.as_ref().display());
     }
 
-    let metadata = Metadata::from_path(&file)?;
-    let new_path = options
-        .format
-        .borrow()
-        .build_path(&metadata, options.exfat_compat)?;
+    let mut metadata = Metadata::from_path_with_aliases(&file, &options.vorbis_aliases)?;
 
-    if !options.dryrun {
-        let new_path = root.as_ref().join(&new_path);
-        let new_path_parent = new_path.parent().ok_or(Error::InvalidParent {
-            child: new_path.to_string_lossy().into(),
-        })?;
+    if let Some(album) = file.as_ref().parent().and_then(|p| state.albums.get(p)) {
+        metadata.artist = album.artist.clone();
+        metadata.artists = album.artists.clone();
+        metadata.album = album.album.clone();
+        metadata.artist_sort = album.artist_sort.clone();
+        metadata.album_sort = album.album_sort.clone();
+    }
+
+    if let (Some(parent), Some(album)) = (file.as_ref().parent(), &metadata.album) {
+        if state
+            .compilations
+            .contains(&(parent.to_path_buf(), album.clone()))
+        {
+            metadata.artist = Some(VARIOUS_ARTISTS.to_owned());
+            metadata.artists = vec![VARIOUS_ARTISTS.to_owned()];
+        }
+    }
 
-        utils::maybe_create_dir(new_path_parent)?;
-        fs::rename(&file, &new_path)?;
+    let mut new_path = options.format.borrow().build_path(
+        &metadata,
+        &FormatOptions {
+            source_path: file.as_ref().strip_prefix(&root).ok(),
+            ..options.format_options()
+        },
+    )?;
+
+    if options.preserve_source_tree {
+        let sorted_dir = state.source_dir.as_deref().unwrap_or_else(|| root.as_ref());
+        let source_dir = file
+            .as_ref()
+            .parent()
+            .and_then(|parent| parent.strip_prefix(sorted_dir).ok());
+
+        if let Some(source_dir) = source_dir {
+            new_path = source_dir.join(new_path);
+        }
+    }
+
+    // Tags are only written once the file is where it belongs
+    let tags = Some(&metadata).filter(|_| options.fix_tags);
+
+    if is_same_file(&file, dest_root.join(&new_path)) {
+        log::info!("Already in place: \"{}\"", new_path.display());
+        if let (Some(metadata), false) = (tags, state.dryrun) {
+            fix_tags(metadata, file.as_ref());
+        }
+
+        state.in_place += 1;
+        state
+            .claimed
+            .insert(dest_root.join(&new_path), file.as_ref().to_path_buf());
+        return Ok((new_path, false));
+    }
+
+    let destination = dest_root.join(&new_path);
+    if let Some(first) = state.claimed.get(&destination) {
+        state
+            .conflicts
+            .entry(destination)
+            .or_insert_with(|| vec![first.clone()])
+            .push(file.as_ref().to_path_buf());
+    }
+
+    let is_taken = |path: &Path| {
+        let path = dest_root.join(path);
+        state.claimed.contains_key(&path) || path.exists()
+    };
+
+    let collided = is_taken(&new_path);
+    if collided {
+        match options.conflict {
+            ConflictPolicy::Skip => {
+                return Err(Error::DestinationTaken {
+                    path: new_path.to_string_lossy().into(),
+                });
+            }
+
+            ConflictPolicy::Rename => {
+                let renamed = (1..)
+                    .map(|n| with_counter(&new_path, n))
+                    .find(|path| !is_taken(path))
+                    .unwrap();
+
+                log::warn!(
+                    "Destination \"{}\" is already taken, using \"{}\"",
+                    new_path.display(),
+                    renamed.display()
+                );
+
+                new_path = renamed;
+            }
+        }
+    }
+
+    #[cfg(feature = "tracing")]
+    tracing::Span::current().record("destination", tracing::field::display(new_path.display()));
+
+    state
+        .claimed
+        .insert(dest_root.join(&new_path), file.as_ref().to_path_buf());
+
+    state.moves.push(Move {
+        from: file.as_ref().to_path_buf(),
+        to: dest_root.join(&new_path),
+    });
+
+    if !state.dryrun {
+        execute_move(
+            file.as_ref(),
+            &dest_root.join(&new_path),
+            tags,
+            options,
+            state,
+        )?;
     }
 
     log::info!("Item created: \"{}\"", new_path.display());
 
-    Ok(new_path)
+    Ok((new_path, collided))
+}
+
+/// Moves `from` to `to` (creating the missing folders), writes `tags` to it (if given),
+/// then runs the per-file hook.
+fn execute_move<P>(
+    from: &Path,
+    to: &Path,
+    tags: Option<&Metadata>,
+    options: &Options<P>,
+    state: &mut RunState,
+) -> Result<()>
+where
+    P: Borrow<ParsedFormat>,
+{
+    let parent = to.parent().ok_or(Error::InvalidParent {
+        child: to.to_string_lossy().into(),
+    })?;
+
+    let mv = Move {
+        from: from.to_path_buf(),
+        to: to.to_path_buf(),
+    };
+
+    if let Some(journal) = &mut state.journal {
+        journal.record(JournalStatus::Planned, &mv)?;
+    }
+
+    create_dir(parent, options.dir_mode)?;
+    move_file(from, to, options.verify)?;
+
+    if let Some(journal) = &mut state.journal {
+        journal.record(JournalStatus::Done, &mv)?;
+    }
+
+    if let Some(metadata) = tags {
+        fix_tags(metadata, to);
+    }
+
+    if let Some(hook) = &options.each_file_hook {
+        if let Err(e) = run_hook(hook, from, to) {
+            log::error!("Hook failed for \"{}\" ({})", to.display(), e);
+            state.hook_failures += 1;
+        }
+    }
+
+    Ok(())
+}
+
+/// Executes a plan computed by [`plan_folder`] (possibly after removing some of its
+/// moves). The filesystem may have changed in the meantime, so sources that no longer
+/// exist are skipped and destinations are checked again against the conflict policy.
+/// Unlike [`sort_folder`], the reported new paths are absolute.
+pub fn apply_plan<P>(plan: &[Move], options: &Options<P>) -> Result<SortReport>
+where
+    P: Borrow<ParsedFormat>,
+{
+    apply_moves(plan, options, open_journal(options)?)
+}
+
+fn apply_moves<P>(
+    plan: &[Move],
+    options: &Options<P>,
+    journal: Option<Journal>,
+) -> Result<SortReport>
+where
+    P: Borrow<ParsedFormat>,
+{
+    let mut report = SortReport::default();
+
+    let mut state = RunState {
+        dryrun: options.dryrun,
+        journal,
+        ..Default::default()
+    };
+
+    for Move { from, to } in plan {
+        if !from.exists() {
+            log::warn!(
+                "Source vanished since planning: \"{}\" (skipping)",
+                from.display()
+            );
+            continue;
+        }
+
+        report.total += 1;
+
+        let is_taken = |path: &Path| state.claimed.contains_key(path) || path.exists();
+
+        let mut to = to.clone();
+        if is_taken(&to) {
+            report.collisions += 1;
+
+            match options.conflict {
+                ConflictPolicy::Skip => {
+                    let err = Error::DestinationTaken {
+                        path: to.to_string_lossy().into(),
+                    };
+
+                    if options.fail_fast {
+                        return Err(err);
+                    }
+
+                    log::error!("{}", err);
+                    continue;
+                }
+
+                ConflictPolicy::Rename => {
+                    to = (1..)
+                        .map(|n| with_counter(&to, n))
+                        .find(|path| !is_taken(path))
+                        .unwrap();
+                }
+            }
+        }
+
+        state.claimed.insert(to.clone(), from.clone());
+
+        if !state.dryrun {
+            if let Err(e) = execute_move(from, &to, None, options, &mut state) {
+                if options.fail_fast {
+                    return Err(e);
+                }
+
+                log::error!("{}", e);
+                continue;
+            }
+        }
+
+        log::info!("Item created: \"{}\"", to.display());
+        report.success += 1;
+        report.new_paths.push(to);
+    }
+
+    report.hook_failures = state.hook_failures;
+
+    Ok(report)
+}
+
+/// Finishes the moves recorded in `journal` as planned but never done (e.g. because the
+/// run was interrupted), those whose source is already at its destination just count
+/// as done. Files that weren't reached at all are left to a new sort.
+pub fn resume_journal<P>(journal: impl AsRef<Path>, options: &Options<P>) -> Result<SortReport>
+where
+    P: Borrow<ParsedFormat>,
+{
+    let mut log = Journal::open(&journal)?;
+    let mut plan = Vec::new();
+    let mut finished = 0;
+
+    for (status, mv) in Journal::read(&journal)? {
+        if status != JournalStatus::Planned {
+            continue;
+        }
+
+        if !mv.from.exists() && mv.to.exists() {
+            if !options.dryrun {
+                log.record(JournalStatus::Done, &mv)?;
+            }
+
+            finished += 1;
+        } else {
+            plan.push(mv);
+        }
+    }
+
+    let log = if options.dryrun { None } else { Some(log) };
+    let mut report = apply_moves(&plan, options, log)?;
+    report.success += finished;
+    report.total += finished;
+
+    Ok(report)
+}
+
+/// Moves every file recorded in `journal` as done back to where it was, newest first.
+/// Files whose original path is taken again are left alone.
+pub fn rollback_journal<P>(journal: impl AsRef<Path>, options: &Options<P>) -> Result<SortReport>
+where
+    P: Borrow<ParsedFormat>,
+{
+    let mut report = SortReport::default();
+    let mut log = Journal::open(&journal)?;
+
+    let done = Journal::read(&journal)?
+        .into_iter()
+        .filter(|(status, _)| *status == JournalStatus::Done);
+
+    for (_, mv) in done.rev() {
+        report.total += 1;
+
+        let rolled_back = if options.dryrun {
+            Ok(())
+        } else {
+            roll_back(&mv, options.dir_mode, &mut log)
+        };
+
+        match rolled_back {
+            Ok(_) => {
+                log::info!("Moved back: \"{}\"", mv.from.display());
+                report.success += 1;
+                report.new_paths.push(mv.from);
+            }
+
+            Err(e) if options.fail_fast => return Err(e),
+            Err(e) => log::error!("{}", e),
+        }
+    }
+
+    Ok(report)
+}
+
+fn roll_back(mv: &Move, dir_mode: Option<u32>, journal: &mut Journal) -> Result<()> {
+    if mv.from.exists() {
+        return Err(Error::DestinationTaken {
+            path: mv.from.to_string_lossy().into(),
+        });
+    }
+
+    let parent = mv.from.parent().ok_or(Error::InvalidParent {
+        child: mv.from.to_string_lossy().into(),
+    })?;
+
+    create_dir(parent, dir_mode)?;
+    move_file(&mv.to, &mv.from, false)?;
+    journal.record(JournalStatus::RolledBack, mv)
+}
+
+/// Renames `src` to `dst`, falling back to copy and delete when they're on different
+/// filesystems.
+fn move_file(src: &Path, dst: &Path, verify: bool) -> Result<()> {
+    match fs::rename(src, dst) {
+        Err(e) if e.kind() == io::ErrorKind::CrossesDevices => {
+            log::debug!("Copying across filesystems: \"{}\"", src.display());
+            fs::copy(src, dst)?;
+
+            if verify && !same_contents(src, dst)? {
+                fs::remove_file(dst)?;
+                return Err(Error::VerificationFailed {
+                    path: dst.to_string_lossy().into(),
+                });
+            }
+
+            fs::remove_file(src)?;
+            Ok(())
+        }
+
+        result => Ok(result?),
+    }
+}
+
+fn same_contents(a: &Path, b: &Path) -> Result<bool> {
+    let (mut a, mut b) = (fs::File::open(a)?, fs::File::open(b)?);
+    if a.metadata()?.len() != b.metadata()?.len() {
+        return Ok(false);
+    }
+
+    let (mut buf_a, mut buf_b) = (vec![0; 64 * 1024], vec![0; 64 * 1024]);
+    loop {
+        let read = a.read(&mut buf_a)?;
+        if read == 0 {
+            return Ok(true);
+        }
+
+        b.read_exact(&mut buf_b[..read])?;
+        if buf_a[..read] != buf_b[..read] {
+            return Ok(false);
+        }
+    }
+}
+
+fn run_hook(hook: &str, src: &Path, dst: &Path) -> Result<()> {
+    let mut command = if cfg!(windows) {
+        let mut command = Command::new("cmd");
+        command.arg("/C");
+        command
+    } else {
+        let mut command = Command::new("sh");
+        command.arg("-c");
+        command
+    };
+
+    let status = command
+        .arg(hook)
+        .env("MUSO_SRC", src)
+        .env("MUSO_DST", dst)
+        .status()?;
+
+    if !status.success() {
+        return Err(Error::HookFailed {
+            status: status.to_string(),
+        });
+    }
+
+    Ok(())
+}
+
+/// Creates `dir` (along with its missing parents), applying `mode` to every folder that
+/// didn't exist before.
+fn create_dir(dir: &Path, mode: Option<u32>) -> Result<()> {
+    let missing: Vec<_> = dir.ancestors().take_while(|dir| !dir.exists()).collect();
+
+    utils::maybe_create_dir(dir)?;
+
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+
+        if let Some(mode) = mode {
+            for dir in missing {
+                fs::set_permissions(dir, fs::Permissions::from_mode(mode))?;
+            }
+        }
+    }
+
+    #[cfg(not(unix))]
+    let _ = (missing, mode);
+
+    Ok(())
+}
+
+/// Appends ` (n)` to the file stem, e.g. `Title.flac` becomes `Title (1).flac`.
+fn with_counter(path: &Path, n: usize) -> PathBuf {
+    let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+    let name = match path.extension() {
+        Some(ext) => format!("{} ({}).{}", stem, n, ext.to_string_lossy()),
+        None => format!("{} ({})", stem, n),
+    };
+
+    path.with_file_name(name)
+}
+
+fn is_same_file(a: impl AsRef<Path>, b: impl AsRef<Path>) -> bool {
+    match (fs::canonicalize(a), fs::canonicalize(b)) {
+        (Ok(a), Ok(b)) => a == b,
+        _ => false,
+    }
 }
@@ -0,0 +1,239 @@
+// Copyright (C) 2020 kevin
+//
+// This file is part of muso.
+//
+// muso is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// muso is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with muso.  If not, see <http://www.gnu.org/licenses/>.
+
+//! Append-only log of the moves done while sorting, so an interrupted run can be
+//! resumed or rolled back.
+
+use std::collections::HashMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+use super::Move;
+use crate::Result;
+
+/// Status of a move, only the last one recorded for each source matters.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum JournalStatus {
+    /// About to be moved.
+    Planned,
+    /// Moved to its destination.
+    Done,
+    /// Moved back to its source.
+    RolledBack,
+}
+
+impl JournalStatus {
+    fn as_str(self) -> &'static str {
+        match self {
+            JournalStatus::Planned => "planned",
+            JournalStatus::Done => "done",
+            JournalStatus::RolledBack => "rolledback",
+        }
+    }
+
+    fn parse(s: &str) -> Option<Self> {
+        match s {
+            "planned" => Some(JournalStatus::Planned),
+            "done" => Some(JournalStatus::Done),
+            "rolledback" => Some(JournalStatus::RolledBack),
+            _ => None,
+        }
+    }
+}
+
+/// Every line holds a status, a source and a destination separated by tabs (tabs,
+/// newlines and backslashes in paths are escaped, bytes that aren't UTF-8 are written
+/// as `\xHH`).
+#[derive(Debug)]
+pub struct Journal {
+    file: File,
+}
+
+impl Journal {
+    /// Opens (or creates) the journal at `path`, entries are always appended.
+    pub fn open(path: impl AsRef<Path>) -> Result<Self> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        Ok(Journal { file })
+    }
+
+    /// Records `status` for `mv`, it's already on disk once this returns.
+    pub fn record(&mut self, status: JournalStatus, mv: &Move) -> Result<()> {
+        let line = format!(
+            "{}\t{}\t{}\n",
+            status.as_str(),
+            escape(&mv.from),
+            escape(&mv.to)
+        );
+
+        self.file.write_all(line.as_bytes())?;
+        self.file.sync_data()?;
+
+        Ok(())
+    }
+
+    /// Reads the last status recorded for every source in the journal at `path`, in the
+    /// order they were first recorded. Malformed lines (e.g. a last line cut short by a
+    /// crash) are ignored.
+    pub fn read(path: impl AsRef<Path>) -> Result<Vec<(JournalStatus, Move)>> {
+        let contents = fs::read_to_string(path)?;
+        let mut order = Vec::new();
+        let mut latest = HashMap::new();
+
+        for line in contents.lines() {
+            let fields: Vec<_> = line.split('\t').collect();
+            let (status, mv) = match fields.as_slice() {
+                [status, from, to] => match JournalStatus::parse(status) {
+                    Some(status) => (
+                        status,
+                        Move {
+                            from: unescape(from),
+                            to: unescape(to),
+                        },
+                    ),
+                    None => {
+                        log::warn!("Ignoring malformed journal entry: \"{}\"", line);
+                        continue;
+                    }
+                },
+
+                _ => {
+                    log::warn!("Ignoring malformed journal entry: \"{}\"", line);
+                    continue;
+                }
+            };
+
+            if !latest.contains_key(&mv.from) {
+                order.push(mv.from.clone());
+            }
+
+            latest.insert(mv.from.clone(), (status, mv));
+        }
+
+        Ok(order
+            .into_iter()
+            .filter_map(|from| latest.remove(&from))
+            .collect())
+    }
+}
+
+fn escape_str(s: &str) -> String {
+    s.replace('\\', "\\\\")
+        .replace('\t', "\\t")
+        .replace('\n', "\\n")
+}
+
+/// Names on unix are arbitrary bytes, the ones that aren't UTF-8 are kept as `\xHH` so
+/// every path round trips.
+#[cfg(unix)]
+fn escape(path: &Path) -> String {
+    use std::os::unix::ffi::OsStrExt;
+
+    let mut bytes = path.as_os_str().as_bytes();
+    let mut escaped = String::with_capacity(bytes.len());
+
+    loop {
+        match std::str::from_utf8(bytes) {
+            Ok(valid) => {
+                escaped.push_str(&escape_str(valid));
+                return escaped;
+            }
+
+            Err(e) => {
+                let (valid, rest) = bytes.split_at(e.valid_up_to());
+                let invalid = e.error_len().unwrap_or(rest.len());
+
+                escaped.push_str(&escape_str(&String::from_utf8_lossy(valid)));
+                for byte in &rest[..invalid] {
+                    escaped.push_str(&format!("\\x{:02x}", byte));
+                }
+
+                bytes = &rest[invalid..];
+            }
+        }
+    }
+}
+
+#[cfg(not(unix))]
+fn escape(path: &Path) -> String {
+    escape_str(&path.to_string_lossy())
+}
+
+fn unescape(field: &str) -> PathBuf {
+    let bytes = field.as_bytes();
+    let mut unescaped = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+
+    // Escapes are ASCII, so they're never part of a multi-byte character
+    while i < bytes.len() {
+        let (byte, len) = match (bytes[i], bytes.get(i + 1)) {
+            (b'\\', Some(b't')) => (b'\t', 2),
+            (b'\\', Some(b'n')) => (b'\n', 2),
+            (b'\\', Some(b'\\')) => (b'\\', 2),
+            (b'\\', Some(b'x')) => match field
+                .get(i + 2..i + 4)
+                .and_then(|hex| u8::from_str_radix(hex, 16).ok())
+            {
+                Some(byte) => (byte, 4),
+                None => (b'\\', 1),
+            },
+            (byte, _) => (byte, 1),
+        };
+
+        unescaped.push(byte);
+        i += len;
+    }
+
+    path_from_bytes(unescaped)
+}
+
+#[cfg(unix)]
+fn path_from_bytes(bytes: Vec<u8>) -> PathBuf {
+    use std::ffi::OsString;
+    use std::os::unix::ffi::OsStringExt;
+
+    PathBuf::from(OsString::from_vec(bytes))
+}
+
+#[cfg(not(unix))]
+fn path_from_bytes(bytes: Vec<u8>) -> PathBuf {
+    PathBuf::from(String::from_utf8_lossy(&bytes).into_owned())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn escaped_paths_round_trip() {
+        let path = Path::new("Artist\\Name/Tab\there/New\nline.flac");
+        assert_eq!(unescape(&escape(path)), path);
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn non_utf8_paths_round_trip() {
+        use std::ffi::OsStr;
+        use std::os::unix::ffi::OsStrExt;
+
+        let path = Path::new(OsStr::from_bytes(b"Caf\xe9/\\x41\xff\xfe.flac"));
+        let escaped = escape(path);
+
+        assert_eq!(escaped, "Caf\\xe9/\\\\x41\\xff\\xfe.flac");
+        assert_eq!(unescape(&escaped), path);
+    }
+}
@@ -21,16 +21,34 @@ use std::path::{Path, PathBuf};
 
 use crate::{Error, Result};
 
+/// Fails in environments without a config folder (e.g. when `$HOME` isn't set).
 #[inline]
-pub fn default_config_path() -> PathBuf {
-    dirs::config_dir().unwrap().join("muso/config.toml")
+pub fn default_config_path() -> Result<PathBuf> {
+    config_dir().map(|dir| dir.join("muso/config.toml"))
 }
 
 #[inline]
-pub fn default_service_path() -> PathBuf {
-    dirs::config_dir()
-        .unwrap()
-        .join("systemd/user/muso.service")
+pub fn default_service_path() -> Result<PathBuf> {
+    config_dir().map(|dir| dir.join("systemd/user/muso.service"))
+}
+
+/// Folder meant for the state kept by sync mode.
+#[inline]
+pub fn default_data_dir() -> Result<PathBuf> {
+    dirs::data_dir()
+        .map(|dir| dir.join("muso"))
+        .ok_or(Error::DataDirNotFound)
+}
+
+fn config_dir() -> Result<PathBuf> {
+    dirs::config_dir().ok_or(Error::ConfigDirNotFound)
+}
+
+/// Resolves `..` and symlinks in `path`, paths that can't be resolved (e.g. missing ones)
+/// are returned as given.
+pub fn canonicalize_or_given(path: impl AsRef<Path>) -> PathBuf {
+    let path = path.as_ref();
+    path.canonicalize().unwrap_or_else(|_| path.to_path_buf())
 }
 
 pub fn maybe_create_dir(path: impl AsRef<Path>) -> std::io::Result<()> {
@@ -55,8 +73,8 @@ pub fn generate_resource(res: Resource, default: Option<&str>) -> Result<()> {
     };
 
     let dest = match res {
-        Resource::Config => default_config_path(),
-        Resource::Service => default_service_path(),
+        Resource::Config => default_config_path()?,
+        Resource::Service => default_service_path()?,
     };
 
     log::info!("Generating {} file", name);
@@ -1,21 +1,47 @@
-use std::borrow::Cow;
 use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::io;
 use std::path::{Path, PathBuf};
 use std::sync::mpsc::{self, Receiver};
+use std::sync::Arc;
+use std::thread;
 use std::time::Duration;
 
+use ignore::gitignore::{Gitignore, GitignoreBuilder};
 use notify::Watcher as _;
 use notify::{DebouncedEvent, RecursiveMode};
 
 use crate::config::Config;
-use crate::sorting::{sort_file, sort_folder, Options};
+use crate::sorting::{sort_file, sort_folder, Options, SortReport};
+use crate::utils;
 use crate::{Error, Result};
 
-#[derive(Debug, Clone)]
+/// Delay before the first retry of a sort, doubled on each retry.
+const RETRY_DELAY: Duration = Duration::from_millis(200);
+
+/// Called after each file or folder is sorted, with the sorted path (file or folder)
+/// and the report of its sorting (new paths are relative to the library root).
+pub type SortHandler = Arc<dyn Fn(&Path, &SortReport) + Send + Sync>;
+
+#[derive(Clone)]
 pub struct Watcher {
     config: Config,
     roots: HashMap<PathBuf, String>,
     ignore: HashSet<PathBuf>,
+    ignore_globs: Gitignore,
+    handler: Option<SortHandler>,
+}
+
+impl fmt::Debug for Watcher {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Watcher")
+            .field("config", &self.config)
+            .field("roots", &self.roots)
+            .field("ignore", &self.ignore)
+            .field("ignore_globs", &self.ignore_globs)
+            .field("handler", &self.handler.as_ref().map(|_| "Fn"))
+            .finish()
+    }
 }
 
 impl Watcher {
@@ -24,17 +50,42 @@ impl Watcher {
 
         for (name, library) in &config.libraries {
             for folder in &library.folders {
-                roots.insert(folder.to_owned(), name.to_owned());
+                // Events are reported under the watched path, which is resolved so
+                // symlinked or relative folders still match them
+                roots.insert(utils::canonicalize_or_given(folder), name.to_owned());
             }
         }
 
+        let mut builder = GitignoreBuilder::new("");
+        for glob in &config.watch.ignore {
+            if let Err(e) = builder.add_line(None, glob) {
+                log::warn!("Invalid ignore pattern \"{}\" ({})", glob, e);
+            }
+        }
+
+        let ignore_globs = builder.build().unwrap_or_else(|e| {
+            log::error!("Couldn't build ignore patterns ({})", e);
+            Gitignore::empty()
+        });
+
         Self {
             config,
             roots,
             ignore: HashSet::new(),
+            ignore_globs,
+            handler: None,
         }
     }
 
+    /// Sets a handler called after each successful sort, e.g. to send notifications.
+    pub fn with_handler<F>(mut self, handler: F) -> Self
+    where
+        F: Fn(&Path, &SortReport) + Send + Sync + 'static,
+    {
+        self.handler = Some(Arc::new(handler));
+        self
+    }
+
     pub fn watch(self) -> Result<()> {
         if self.config.libraries.is_empty() {
             log::info!("No directories to watch!");
@@ -42,11 +93,17 @@ impl Watcher {
         }
 
         let (tx, rx) = mpsc::channel();
-        let delay = Duration::from_secs(self.config.watch.every.unwrap_or(1));
+        let delay = self.config.watch.every.unwrap_or(Duration::from_secs(1));
         let mut watcher = notify::watcher(tx, delay)?;
 
-        for root in self.roots.keys() {
-            watcher.watch(root, RecursiveMode::Recursive)?;
+        for (root, library) in &self.roots {
+            let mode = if self.config.is_watch_recursive(library) {
+                RecursiveMode::Recursive
+            } else {
+                RecursiveMode::NonRecursive
+            };
+
+            watcher.watch(root, mode)?;
         }
 
         log::info!("Watching libraries");
@@ -72,27 +129,40 @@ impl Watcher {
                             continue;
                         }
 
+                        if self.matches_ignore_globs(&path) {
+                            log::debug!("Ignoring: \"{}\"", path.display());
+                            continue;
+                        }
+
                         if let Some(root) = self.root_for(&path) {
                             let library = &self.roots[&root];
 
                             let options = Options {
-                                format: Cow::Borrowed(self.config.format_of(library).unwrap()),
-                                dryrun: false,
                                 recursive: true,
-                                exfat_compat: self.config.is_exfat_compat(library),
                                 remove_empty: true,
+                                ..self.config.libraries[library].options()
                             };
 
+                            let retries = self.config.watch.retries;
+
                             if path.is_dir() {
-                                match sort_folder(&root, &path, &options) {
+                                let sorted =
+                                    with_retries(retries, || sort_folder(&root, &path, &options));
+
+                                match sorted {
                                     Ok(report) => {
                                         log::info!(
-                                            "Done: {} successful out of {} ({} failed)",
+                                            "Done: {} sorted, {} failed, {} skipped ({} folder(s) removed)",
                                             report.success,
-                                            report.total,
-                                            report.total - report.success
+                                            report.total - report.success,
+                                            report.skipped,
+                                            report.dirs_removed
                                         );
 
+                                        if let Some(handler) = &self.handler {
+                                            handler(&path, &report);
+                                        }
+
                                         for new_path in report.new_paths {
                                             self.ignore_path(new_path, &root)?;
                                         }
@@ -101,9 +171,24 @@ impl Watcher {
                                     Err(e) => log::error!("{}", e),
                                 }
                             } else {
-                                match sort_file(&root, &path, &options) {
+                                let sorted =
+                                    with_retries(retries, || sort_file(&root, &path, &options));
+
+                                match sorted {
                                     Ok(new_path) => {
-                                        log::info!("Done: 1 successful out of 1 (0 failed)");
+                                        log::info!("Done: 1 sorted, 0 failed, 0 skipped");
+
+                                        if let Some(handler) = &self.handler {
+                                            let report = SortReport {
+                                                success: 1,
+                                                total: 1,
+                                                new_paths: vec![new_path.clone()],
+                                                ..Default::default()
+                                            };
+
+                                            handler(&path, &report);
+                                        }
+
                                         self.ignore_path(new_path, root)?;
                                     }
 
@@ -160,6 +245,16 @@ impl Watcher {
         }
     }
 
+    fn matches_ignore_globs(&self, path: &Path) -> bool {
+        match path.file_name() {
+            Some(name) => self
+                .ignore_globs
+                .matched(Path::new(name), path.is_dir())
+                .is_ignore(),
+            None => false,
+        }
+    }
+
     fn root_for(&self, path: impl AsRef<Path>) -> Option<PathBuf> {
         let path = path.as_ref();
         for ancestor in path.ancestors() {
@@ -171,3 +266,24 @@ impl Watcher {
         None
     }
 }
+
+/// Runs `sort`, retrying it while it fails with I/O errors (e.g. stale handles on network
+/// filesystems), any other error is returned right away.
+fn with_retries<T>(retries: u32, mut sort: impl FnMut() -> Result<T>) -> Result<T> {
+    let mut delay = RETRY_DELAY;
+
+    for _ in 0..retries {
+        match sort() {
+            // Files removed in the meantime won't come back
+            Err(Error::IoError { source }) if source.kind() != io::ErrorKind::NotFound => {
+                log::warn!("{} (retrying in {}ms)", source, delay.as_millis());
+                thread::sleep(delay);
+                delay *= 2;
+            }
+
+            result => return result,
+        }
+    }
+
+    sort()
+}
@@ -0,0 +1,237 @@
+use std::fs;
+use std::path::PathBuf;
+use std::str::FromStr;
+
+use muso::config::Config;
+use muso::metadata::Metadata;
+use muso::Result;
+
+#[test]
+fn relative_folders_are_resolved() -> Result<()> {
+    let dir = tempfile::tempdir()?;
+    fs::create_dir_all(dir.path().join("Music/FLAC"))?;
+
+    let config_path = dir.path().join("config.toml");
+    fs::write(
+        &config_path,
+        r#"
+        [watch]
+        libraries = ['default']
+
+        [libraries.default]
+        format = '{artist}/{title}.{ext}'
+        folders = ['Music/FLAC', 'Missing']
+        "#,
+    )?;
+
+    let (config, warnings) = Config::from_path_with_warnings(&config_path)?;
+    let expected = fs::canonicalize(dir.path())?.join("Music/FLAC");
+
+    assert_eq!(config.libraries["default"].folders, vec![expected]);
+    assert_eq!(warnings.len(), 1);
+    assert!(warnings[0].contains("Missing"));
+
+    Ok(())
+}
+
+#[test]
+fn config_from_str() -> Result<()> {
+    let dir = tempfile::tempdir()?;
+    let folder = fs::canonicalize(dir.path())?;
+
+    let config = Config::from_str(&format!(
+        r#"
+        [watch]
+        libraries = ['default']
+
+        [libraries.default]
+        format = '{{artist}}/{{title}}.{{ext}}'
+        folders = ['{}']
+        "#,
+        folder.display()
+    ))?;
+
+    assert_eq!(config.libraries["default"].folders, vec![folder.clone()]);
+
+    let config = Config::from_reader(
+        format!(
+            "[watch]\nlibraries = []\n[libraries.default]\nformat = '{{title}}.{{ext}}'\nfolders = ['{}']",
+            folder.display()
+        )
+        .as_bytes(),
+    )?;
+    assert_eq!(config.libraries["default"].folders, vec![folder]);
+
+    Ok(())
+}
+
+#[test]
+fn library_for_descendants() -> Result<()> {
+    let dir = tempfile::tempdir()?;
+    fs::create_dir_all(dir.path().join("Music/FLAC"))?;
+    fs::create_dir_all(dir.path().join("Music/MP3"))?;
+
+    let config_path = dir.path().join("config.toml");
+    fs::write(
+        &config_path,
+        r#"
+        [watch]
+        libraries = []
+
+        [libraries.flac]
+        format = '{artist}/{title}.{ext}'
+        folders = ['Music/FLAC']
+
+        [libraries.mp3]
+        format = '{title}.{ext}'
+        folders = ['Music/MP3']
+        "#,
+    )?;
+
+    let config = Config::from_path(&config_path)?;
+    let music = fs::canonicalize(dir.path())?.join("Music");
+
+    assert_eq!(config.library_for(music.join("FLAC")), Some("flac"));
+    assert_eq!(
+        config.library_for(music.join("MP3/Artist/a.mp3")),
+        Some("mp3")
+    );
+    assert_eq!(config.library_for(&music), None);
+
+    let metadata = Metadata {
+        artist: Some("Artist".into()),
+        title: Some("Title".into()),
+        ext: "flac".into(),
+        ..Default::default()
+    };
+
+    let format = config
+        .search_format(music.join("FLAC/Artist/Album"))
+        .unwrap();
+    assert_eq!(
+        format.build_path(&metadata, &Default::default())?,
+        PathBuf::from("Artist/Title.flac")
+    );
+    assert!(config.search_format(&music).is_none());
+
+    Ok(())
+}
+
+#[test]
+#[cfg(all(feature = "flac", feature = "mp3"))]
+fn iter_library_files() -> Result<()> {
+    let dir = tempfile::tempdir()?;
+    let folder = fs::canonicalize(dir.path())?;
+    fs::create_dir_all(folder.join("Album/Scans"))?;
+    fs::create_dir_all(folder.join("Skipped"))?;
+
+    fs::copy("test_files/complete.flac", folder.join("Album/01.flac"))?;
+    fs::copy("test_files/partial.mp3", folder.join("Album/02.mp3"))?;
+    fs::copy("test_files/complete.flac", folder.join("Skipped/03.flac"))?;
+    fs::write(folder.join("Album/Scans/cover.txt"), "not music")?;
+    fs::write(folder.join(".musoignore"), "Skipped/\n")?;
+
+    let config = Config::from_str(&format!(
+        "[watch]\nlibraries = []\n[libraries.default]\nformat = '{{title}}.{{ext}}'\nfolders = ['{}']",
+        folder.display()
+    ))?;
+
+    let mut files: Vec<_> = config.iter_library_files("default").collect();
+    files.sort_by(|(a, _), (b, _)| a.cmp(b));
+
+    let paths: Vec<_> = files.iter().map(|(path, _)| path.clone()).collect();
+    assert_eq!(
+        paths,
+        vec![folder.join("Album/01.flac"), folder.join("Album/02.mp3")]
+    );
+
+    let (_, metadata) = &files[0];
+    assert_eq!("Album", metadata.as_ref().unwrap().get_album()?);
+
+    assert_eq!(config.iter_library_files("missing").count(), 0);
+
+    Ok(())
+}
+
+#[test]
+fn watch_every_with_units() -> Result<()> {
+    use std::time::Duration;
+
+    let every = |value: &str| {
+        Config::from_str(&format!(
+            "[watch]\nevery = {}\nlibraries = []\n[libraries]",
+            value
+        ))
+        .map(|config| config.watch.every)
+    };
+
+    assert_eq!(every("2")?, Some(Duration::from_secs(2)));
+    assert_eq!(every("'500ms'")?, Some(Duration::from_millis(500)));
+    assert_eq!(every("'2s'")?, Some(Duration::from_secs(2)));
+    assert_eq!(every("'1m'")?, Some(Duration::from_secs(60)));
+    assert!(every("'1 fortnight'").is_err());
+    assert!(every("-1").is_err());
+
+    let config = Config::from_str("[watch]\nlibraries = []\n[libraries]")?;
+    assert_eq!(config.watch.every, None);
+
+    Ok(())
+}
+
+#[test]
+fn logging_section() -> Result<()> {
+    use log::LevelFilter;
+
+    let config = Config::from_str(
+        "[watch]\nlibraries = []\n[libraries]\n[logging]\nlevel = 'debug'\ntimestamps = true",
+    )?;
+
+    assert_eq!(config.logging.level, Some(LevelFilter::Debug));
+    assert!(config.logging.color);
+    assert!(config.logging.timestamps);
+
+    let config = Config::from_str("[watch]\nlibraries = []\n[libraries]")?;
+    assert_eq!(config.logging.level, None);
+    assert!(config.logging.color && !config.logging.timestamps);
+
+    Ok(())
+}
+
+#[test]
+fn library_options() -> Result<()> {
+    use muso::format::TargetFs;
+    use muso::sorting::ConflictPolicy;
+
+    let config = Config::from_str(
+        "[watch]\nlibraries = []\n[libraries.main]\nformat = '{title}.{ext}'\nfolders = []\n\
+         exfat-compat = true\nconflict = 'rename'\nfix-tags = true\nmin-size = 1024",
+    )?;
+
+    let options = config.libraries["main"].options();
+    assert_eq!(options.target_fs, TargetFs::ExFat);
+    assert_eq!(options.conflict, ConflictPolicy::Rename);
+    assert_eq!(options.min_size, Some(1024));
+    assert!(options.fix_tags);
+    assert!(!options.dryrun && !options.recursive);
+
+    Ok(())
+}
+
+#[test]
+fn char_map_to_dots_is_rejected() {
+    use muso::Error;
+
+    for mapped in &[".", ".."] {
+        let config = Config::from_str(&format!(
+            "[watch]\nlibraries = []\n[libraries.main]\nformat = '{{title}}.{{ext}}'\n\
+             folders = []\nchar-map = {{ '/' = '{}' }}",
+            mapped
+        ));
+
+        assert!(
+            matches!(config, Err(Error::InvalidConfig { .. })),
+            "{}",
+            mapped
+        );
+    }
+}
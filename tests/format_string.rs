@@ -5,7 +5,7 @@ macro_rules! define_tests_for {
             use std::path::PathBuf;
             use std::str::FromStr;
 
-            use muso::format::ParsedFormat;
+            use muso::format::{FormatOptions, ParsedFormat, Tag};
             use muso::metadata::Metadata;
             use muso::{Error, Result};
 
@@ -20,7 +20,10 @@ macro_rules! define_tests_for {
                 let expected = format!("Album Artist/Album/1.1 - Title.{}", ext);
                 let expected = PathBuf::from(expected);
 
-                assert_eq!(expected, format.build_path(&metadata, false)?);
+                assert_eq!(
+                    expected,
+                    format.build_path(&metadata, &FormatOptions::default())?
+                );
 
                 Ok(())
             }
@@ -36,7 +39,10 @@ macro_rules! define_tests_for {
                 let expected = format!("Artist/1.1 - Title.{}", ext);
                 let expected = PathBuf::from(expected);
 
-                assert_eq!(expected, format.build_path(&metadata, false)?);
+                assert_eq!(
+                    expected,
+                    format.build_path(&metadata, &FormatOptions::default())?
+                );
 
                 Ok(())
             }
@@ -52,14 +58,80 @@ macro_rules! define_tests_for {
                 let expected = format!("Artist/ - Title.{}", ext);
                 let expected = PathBuf::from(expected);
 
-                assert_eq!(expected, format.build_path(&metadata, false)?);
+                assert_eq!(
+                    expected,
+                    format.build_path(&metadata, &FormatOptions::default())?
+                );
 
                 let metadata = Metadata::from_path(format!("test_files/complete.{}", ext))?;
 
                 let expected = format!("Album Artist/Album - Title.{}", ext);
                 let expected = PathBuf::from(expected);
 
-                assert_eq!(expected, format.build_path(&metadata, false)?);
+                assert_eq!(
+                    expected,
+                    format.build_path(&metadata, &FormatOptions::default())?
+                );
+
+                Ok(())
+            }
+
+            #[test]
+            fn both_with_conditional_group() -> Result<()> {
+                let ext = stringify!($ext);
+                let metadata = Metadata::from_path(format!("test_files/partial.{}", ext))?;
+
+                let format = "{artist}/[{album} - ]{disc}.{track} - {title}.{ext}";
+                let format = ParsedFormat::from_str(format)?;
+
+                let expected = format!("Artist/1.1 - Title.{}", ext);
+                let expected = PathBuf::from(expected);
+
+                assert_eq!(
+                    expected,
+                    format.build_path(&metadata, &FormatOptions::default())?
+                );
+
+                let metadata = Metadata::from_path(format!("test_files/complete.{}", ext))?;
+
+                let expected = format!("Album Artist/Album - 1.1 - Title.{}", ext);
+                let expected = PathBuf::from(expected);
+
+                assert_eq!(
+                    expected,
+                    format.build_path(&metadata, &FormatOptions::default())?
+                );
+
+                Ok(())
+            }
+
+            #[test]
+            fn detailed_empty_optionals() -> Result<()> {
+                let ext = stringify!($ext);
+                let metadata = Metadata::from_path(format!("test_files/partial.{}", ext))?;
+
+                let format = "{artist}/[{album} - ]{track} - {title}{disc?}.{ext}";
+                let format = ParsedFormat::from_str(format)?;
+
+                let outcome = format.build_path_detailed(&metadata, &FormatOptions::default())?;
+
+                // Some fixtures are tagged as single-disc, which leaves the disc out
+                if metadata.total_discs == Some(1) {
+                    assert_eq!(
+                        PathBuf::from(format!("Artist/1 - Title.{}", ext)),
+                        outcome.path
+                    );
+                    assert_eq!(
+                        vec![Tag::Album, Tag::Disc { leading: 0 }],
+                        outcome.empty_optionals
+                    );
+                } else {
+                    assert_eq!(
+                        PathBuf::from(format!("Artist/1 - Title1.{}", ext)),
+                        outcome.path
+                    );
+                    assert_eq!(vec![Tag::Album], outcome.empty_optionals);
+                }
 
                 Ok(())
             }
@@ -73,7 +145,7 @@ macro_rules! define_tests_for {
                 let format = ParsedFormat::from_str(format)?;
 
                 assert!(matches!(
-                    format.build_path(&metadata, false),
+                    format.build_path(&metadata, &FormatOptions::default()),
                     Err(Error::OptionalInDir)
                 ));
 
@@ -81,10 +153,17 @@ macro_rules! define_tests_for {
                 let format = ParsedFormat::from_str(format)?;
 
                 assert!(matches!(
-                    format.build_path(&metadata, false),
+                    format.build_path(&metadata, &FormatOptions::default()),
                     Err(Error::RequiredInFile)
                 ));
 
+                let format = "{artist}/[{album}/]{title}.{ext}";
+
+                assert!(matches!(
+                    ParsedFormat::from_str(format),
+                    Err(Error::SeparatorInGroup)
+                ));
+
                 Ok(())
             }
 
@@ -97,7 +176,7 @@ macro_rules! define_tests_for {
                 let format = ParsedFormat::from_str(format)?;
 
                 assert!(matches!(
-                    format.build_path(&metadata, false),
+                    format.build_path(&metadata, &FormatOptions::default()),
                     Err(Error::RequiredInFile)
                 ));
 
@@ -113,7 +192,7 @@ macro_rules! define_tests_for {
                 let format = ParsedFormat::from_str(format)?;
 
                 assert!(matches!(
-                    format.build_path(&metadata, false),
+                    format.build_path(&metadata, &FormatOptions::default()),
                     Err(Error::MissingTag { .. })
                 ));
 
@@ -123,8 +202,815 @@ macro_rules! define_tests_for {
     };
 }
 
+#[cfg(feature = "flac")]
 define_tests_for!(flac);
+#[cfg(feature = "mp3")]
 define_tests_for!(mp3);
+#[cfg(feature = "ogg")]
 define_tests_for!(ogg);
+#[cfg(feature = "m4a")]
 define_tests_for!(m4a);
+#[cfg(feature = "m4a")]
 define_tests_for!(m4p);
+#[cfg(feature = "m4a")]
+define_tests_for!(m4b);
+define_tests_for!(mka);
+#[cfg(feature = "mp3")]
+define_tests_for!(aiff);
+define_tests_for!(ape);
+define_tests_for!(wv);
+
+#[test]
+fn char_map_replacements() -> muso::Result<()> {
+    use std::collections::HashMap;
+    use std::path::PathBuf;
+    use std::str::FromStr;
+
+    use muso::format::{FormatOptions, ParsedFormat, TargetFs};
+    use muso::metadata::Metadata;
+
+    let metadata = Metadata {
+        artist: Some("AC/DC".into()),
+        album: Some("Live: 1992".into()),
+        disc: None,
+        track: Some(1),
+        title: Some("Thunderstruck".into()),
+        ext: "flac".into(),
+        ..Default::default()
+    };
+
+    let format = ParsedFormat::from_str("{artist}/{album}/{track} - {title}.{ext}")?;
+
+    let mut char_map = HashMap::new();
+    char_map.insert('/', "-".to_string());
+    char_map.insert(':', " -".to_string());
+
+    let options = FormatOptions {
+        target_fs: TargetFs::ExFat,
+        char_map: Some(&char_map),
+        ..Default::default()
+    };
+
+    let expected = PathBuf::from("AC-DC/Live - 1992/1 - Thunderstruck.flac");
+    assert_eq!(expected, format.build_path(&metadata, &options)?);
+
+    let expected = PathBuf::from("AC_DC/Live: 1992/1 - Thunderstruck.flac");
+    assert_eq!(
+        expected,
+        format.build_path(&metadata, &FormatOptions::default())?
+    );
+
+    // Mapped values can't bring back characters the target filesystem forbids
+    char_map.insert('/', ":".to_string());
+    let options = FormatOptions {
+        target_fs: TargetFs::ExFat,
+        char_map: Some(&char_map),
+        ..Default::default()
+    };
+
+    let expected = PathBuf::from("AC_DC/Live - 1992/1 - Thunderstruck.flac");
+    assert_eq!(expected, format.build_path(&metadata, &options)?);
+
+    Ok(())
+}
+
+#[test]
+fn joined_artists() -> muso::Result<()> {
+    use std::path::PathBuf;
+    use std::str::FromStr;
+
+    use muso::format::{FormatOptions, ParsedFormat};
+    use muso::metadata::Metadata;
+
+    let mut metadata = Metadata {
+        artist: Some("Simon".into()),
+        artists: vec!["Simon".into(), "Garfunkel".into()],
+        title: Some("The Boxer".into()),
+        ext: "flac".into(),
+        ..Default::default()
+    };
+
+    let options = FormatOptions::default();
+    let format = ParsedFormat::from_str("{artist:join(, )}/{artist} - {title}.{ext}")?;
+    assert_eq!(
+        PathBuf::from("Simon, Garfunkel/Simon - The Boxer.flac"),
+        format.build_path(&metadata, &options)?
+    );
+
+    // Falls back to the single artist when the others weren't read
+    metadata.artists.clear();
+    assert_eq!(
+        PathBuf::from("Simon/Simon - The Boxer.flac"),
+        format.build_path(&metadata, &options)?
+    );
+
+    Ok(())
+}
+
+#[test]
+fn blank_names() -> muso::Result<()> {
+    use std::path::PathBuf;
+    use std::str::FromStr;
+
+    use muso::format::{FormatOptions, ParsedFormat};
+    use muso::metadata::Metadata;
+
+    let mut metadata = Metadata {
+        artist: Some("Artist".into()),
+        album: Some("   ".into()),
+        title: Some(" \t ".into()),
+        ext: "flac".into(),
+        ..Default::default()
+    };
+
+    let format = ParsedFormat::from_str("{artist}/{album}/{title}")?;
+    let options = FormatOptions::default();
+    assert_eq!(
+        PathBuf::from("Artist/_/_"),
+        format.build_path(&metadata, &options)?
+    );
+
+    // Trailing dots are dropped from directories, the remaining ones are replaced
+    metadata.album = Some("..".into());
+    metadata.title = Some("..".into());
+    assert_eq!(
+        PathBuf::from("Artist/_/__"),
+        format.build_path(&metadata, &options)?
+    );
+
+    // An absolute root from a variable isn't a blank name
+    std::env::set_var("MUSO_TEST_ROOT", "/mnt/music");
+    let format = ParsedFormat::from_str("$MUSO_TEST_ROOT/{artist}/{title}")?;
+    metadata.title = Some("Title".into());
+    assert_eq!(
+        PathBuf::from("/mnt/music/Artist/Title"),
+        format.build_path(&metadata, &options)?
+    );
+
+    Ok(())
+}
+
+#[test]
+fn target_fs_reserved_names() -> muso::Result<()> {
+    use std::path::PathBuf;
+    use std::str::FromStr;
+
+    use muso::format::{FormatOptions, ParsedFormat, TargetFs};
+    use muso::metadata::Metadata;
+
+    let metadata = Metadata {
+        artist: Some("Con".into()),
+        album: Some("What?".into()),
+        title: Some("NUL".into()),
+        ext: "flac".into(),
+        ..Default::default()
+    };
+
+    let format = ParsedFormat::from_str("{artist}/{album}/{title}.{ext}")?;
+    let build = |target_fs| {
+        let options = FormatOptions {
+            target_fs,
+            ..Default::default()
+        };
+
+        format.build_path(&metadata, &options)
+    };
+
+    assert_eq!(build(TargetFs::Posix)?, PathBuf::from("Con/What?/NUL.flac"));
+    assert_eq!(build(TargetFs::ExFat)?, PathBuf::from("Con/What_/NUL.flac"));
+    assert_eq!(
+        build(TargetFs::Ntfs)?,
+        PathBuf::from("Con_/What_/NUL_.flac")
+    );
+    assert_eq!(
+        build(TargetFs::Vfat)?,
+        PathBuf::from("Con_/What_/NUL_.flac")
+    );
+
+    Ok(())
+}
+
+#[test]
+fn disc_subtitle() -> muso::Result<()> {
+    use std::path::PathBuf;
+    use std::str::FromStr;
+
+    use muso::format::{FormatOptions, ParsedFormat};
+    use muso::metadata::Metadata;
+
+    let mut metadata = Metadata {
+        artist: Some("Artist".into()),
+        album: Some("Box Set".into()),
+        disc: Some(2),
+        track: Some(1),
+        title: Some("Title".into()),
+        ext: "flac".into(),
+        disc_subtitle: Some("Studio Outtakes".into()),
+        ..Default::default()
+    };
+
+    let format = "{album}/Disc {disc}[ - {discsubtitle}]/{track} - {title}.{ext}";
+    let format = ParsedFormat::from_str(format)?;
+
+    let expected = PathBuf::from("Box Set/Disc 2 - Studio Outtakes/1 - Title.flac");
+    assert_eq!(
+        expected,
+        format.build_path(&metadata, &FormatOptions::default())?
+    );
+
+    metadata.disc_subtitle = None;
+
+    let expected = PathBuf::from("Box Set/Disc 2/1 - Title.flac");
+    assert_eq!(
+        expected,
+        format.build_path(&metadata, &FormatOptions::default())?
+    );
+
+    Ok(())
+}
+
+#[test]
+fn extract_from_path() -> muso::Result<()> {
+    use std::path::Path;
+    use std::str::FromStr;
+
+    use muso::format::ParsedFormat;
+
+    let format = ParsedFormat::from_str("{artist}/{album}/[{disc}.]{track} - {title}.{ext}")?;
+
+    let path = Path::new("Beatles/Abbey Road/01 - Come Together - Remastered.mp3");
+    let metadata = format.extract(path).unwrap();
+    assert_eq!(metadata.artist.as_deref(), Some("Beatles"));
+    assert_eq!(metadata.album.as_deref(), Some("Abbey Road"));
+    assert_eq!(metadata.disc, None);
+    assert_eq!(metadata.track, Some(1));
+    assert_eq!(
+        metadata.title.as_deref(),
+        Some("Come Together - Remastered")
+    );
+    assert_eq!(metadata.ext, "mp3");
+
+    let path = Path::new("Beatles/Abbey Road/2.03 - Something.flac");
+    let metadata = format.extract(path).unwrap();
+    assert_eq!(metadata.disc, Some(2));
+    assert_eq!(metadata.track, Some(3));
+
+    assert!(format
+        .extract(Path::new("Beatles/Come Together.mp3"))
+        .is_none());
+    assert!(format
+        .extract(Path::new("Beatles/Abbey Road/Come Together.mp3"))
+        .is_none());
+
+    Ok(())
+}
+
+#[test]
+fn collapse_empty_dirs() -> muso::Result<()> {
+    use std::path::PathBuf;
+    use std::str::FromStr;
+
+    use muso::format::{FormatOptions, ParsedFormat};
+    use muso::metadata::Metadata;
+    use muso::Error;
+
+    let mut metadata = Metadata {
+        artist: Some("Artist".into()),
+        album: Some("Album".into()),
+        disc: Some(2),
+        track: Some(1),
+        title: Some("Title".into()),
+        ext: "flac".into(),
+        ..Default::default()
+    };
+
+    let format = ParsedFormat::from_str("{artist}/{album}/Disc {disc?}/{track} - {title}.{ext}")?;
+    let options = FormatOptions {
+        collapse_empty_dirs: true,
+        ..Default::default()
+    };
+
+    let expected = PathBuf::from("Artist/Album/Disc 2/1 - Title.flac");
+    assert_eq!(expected, format.build_path(&metadata, &options)?);
+
+    metadata.disc = None;
+
+    let expected = PathBuf::from("Artist/Album/1 - Title.flac");
+    assert_eq!(expected, format.build_path(&metadata, &options)?);
+
+    assert!(matches!(
+        format.build_path(&metadata, &FormatOptions::default()),
+        Err(Error::OptionalInDir)
+    ));
+
+    Ok(())
+}
+
+#[test]
+fn env_vars_in_literals() -> muso::Result<()> {
+    use std::path::PathBuf;
+    use std::str::FromStr;
+
+    use muso::format::{FormatOptions, ParsedFormat};
+    use muso::metadata::Metadata;
+    use muso::Error;
+
+    std::env::set_var("MUSO_TEST_HOST", "nas");
+
+    let metadata = Metadata {
+        artist: Some("Artist".into()),
+        title: Some("Title".into()),
+        ext: "flac".into(),
+        ..Default::default()
+    };
+
+    let format =
+        ParsedFormat::from_str("${MUSO_TEST_HOST}/$MUSO_TEST_HOST-{artist}/$${title}.{ext}")?;
+    let expected = PathBuf::from("nas/nas-Artist/$Title.flac");
+    assert_eq!(
+        expected,
+        format.build_path(&metadata, &FormatOptions::default())?
+    );
+
+    assert!(matches!(
+        ParsedFormat::from_str("$MUSO_TEST_UNDEFINED/{title}.{ext}"),
+        Err(Error::UndefinedVariable { .. })
+    ));
+
+    Ok(())
+}
+
+#[test]
+fn prefer_sort_tags() -> muso::Result<()> {
+    use std::path::PathBuf;
+    use std::str::FromStr;
+
+    use muso::format::{FormatOptions, ParsedFormat};
+    use muso::metadata::Metadata;
+
+    let metadata = Metadata {
+        artist: Some("The Beatles".into()),
+        album: Some("Abbey Road".into()),
+        title: Some("Something".into()),
+        ext: "flac".into(),
+        artist_sort: Some("Beatles, The".into()),
+        ..Default::default()
+    };
+
+    let format = ParsedFormat::from_str("{artist}/{album}/{title}.{ext}")?;
+    let options = FormatOptions {
+        prefer_sort_tags: true,
+        ..Default::default()
+    };
+
+    let expected = PathBuf::from("Beatles, The/Abbey Road/Something.flac");
+    assert_eq!(expected, format.build_path(&metadata, &options)?);
+
+    let expected = PathBuf::from("The Beatles/Abbey Road/Something.flac");
+    assert_eq!(
+        expected,
+        format.build_path(&metadata, &FormatOptions::default())?
+    );
+
+    Ok(())
+}
+
+#[test]
+fn ext_map() -> muso::Result<()> {
+    use std::collections::HashMap;
+    use std::path::PathBuf;
+    use std::str::FromStr;
+
+    use muso::format::{FormatOptions, ParsedFormat};
+    use muso::metadata::Metadata;
+
+    let metadata = Metadata {
+        title: Some("Title".into()),
+        ext: "ogg".into(),
+        ..Default::default()
+    };
+
+    let mut ext_map = HashMap::new();
+    ext_map.insert("ogg".to_string(), "oga".to_string());
+
+    let format = ParsedFormat::from_str("{title}.{ext}")?;
+    let options = FormatOptions {
+        ext_map: Some(&ext_map),
+        ..Default::default()
+    };
+
+    assert_eq!(
+        PathBuf::from("Title.oga"),
+        format.build_path(&metadata, &options)?
+    );
+
+    // Lowercasing happens before mapping
+    let metadata = Metadata {
+        ext: "OGG".into(),
+        ..metadata
+    };
+
+    let options = FormatOptions {
+        lowercase_ext: true,
+        ..options
+    };
+
+    assert_eq!(
+        PathBuf::from("Title.oga"),
+        format.build_path(&metadata, &options)?
+    );
+
+    Ok(())
+}
+
+#[test]
+fn trailing_dots_in_dirs() -> muso::Result<()> {
+    use std::path::PathBuf;
+    use std::str::FromStr;
+
+    use muso::format::{FormatOptions, ParsedFormat};
+    use muso::metadata::Metadata;
+
+    let metadata = Metadata {
+        artist: Some("Artist ".into()),
+        album: Some("Greatest Hits.".into()),
+        title: Some("Title".into()),
+        ext: "flac".into(),
+        ..Default::default()
+    };
+
+    let format = ParsedFormat::from_str("{artist}/{album}/Disc 1./{title}.{ext}")?;
+
+    assert_eq!(
+        PathBuf::from("Artist/Greatest Hits/Disc 1/Title.flac"),
+        format.build_path(&metadata, &FormatOptions::default())?
+    );
+
+    // Only the end of the whole directory name is trimmed, values in the middle are kept
+    let metadata = Metadata {
+        album: Some("Vol.".into()),
+        year: Some(2001),
+        ..metadata
+    };
+
+    let format = ParsedFormat::from_str("{artist}/{album} - {year}/{title}.{ext}")?;
+
+    assert_eq!(
+        PathBuf::from("Artist/Vol_ - 2001/Title.flac"),
+        format.build_path(&metadata, &FormatOptions::default())?
+    );
+
+    Ok(())
+}
+
+#[test]
+fn has_art() -> muso::Result<()> {
+    use std::path::PathBuf;
+    use std::str::FromStr;
+
+    use muso::format::{FormatOptions, ParsedFormat};
+    use muso::metadata::{CoverArt, Metadata};
+
+    let mut metadata = Metadata {
+        artist: Some("Artist".into()),
+        title: Some("Title".into()),
+        ext: "flac".into(),
+        ..Default::default()
+    };
+
+    let format = ParsedFormat::from_str("{hasart}/{artist}/{title}.{ext}")?;
+
+    assert_eq!(
+        PathBuf::from("no-art/Artist/Title.flac"),
+        format.build_path(&metadata, &FormatOptions::default())?
+    );
+
+    metadata.cover = Some(CoverArt { dimensions: None });
+
+    assert_eq!(
+        PathBuf::from("with-art/Artist/Title.flac"),
+        format.build_path(&metadata, &FormatOptions::default())?
+    );
+
+    Ok(())
+}
+
+#[test]
+fn has_lyrics() -> muso::Result<()> {
+    use std::path::PathBuf;
+    use std::str::FromStr;
+
+    use muso::format::{FormatOptions, ParsedFormat};
+    use muso::metadata::Metadata;
+
+    let mut metadata = Metadata {
+        artist: Some("Artist".into()),
+        title: Some("Title".into()),
+        ext: "mp3".into(),
+        ..Default::default()
+    };
+
+    let format = ParsedFormat::from_str("{haslyrics}/{artist}/{title}.{ext}")?;
+
+    assert_eq!(
+        PathBuf::from("no-lyrics/Artist/Title.mp3"),
+        format.build_path(&metadata, &FormatOptions::default())?
+    );
+
+    metadata.has_lyrics = true;
+
+    assert_eq!(
+        PathBuf::from("lyrics/Artist/Title.mp3"),
+        format.build_path(&metadata, &FormatOptions::default())?
+    );
+
+    Ok(())
+}
+
+#[test]
+fn stream_properties() -> muso::Result<()> {
+    use std::path::PathBuf;
+    use std::str::FromStr;
+
+    use muso::format::{FormatOptions, ParsedFormat};
+    use muso::metadata::Metadata;
+
+    let mut metadata = Metadata {
+        artist: Some("Artist".into()),
+        title: Some("Title".into()),
+        ext: "flac".into(),
+        sample_rate: Some(96000),
+        bit_depth: Some(24),
+        ..Default::default()
+    };
+
+    let format = ParsedFormat::from_str("{artist}/{title}[ ({samplerate}-{bitdepth})].{ext}")?;
+
+    assert_eq!(
+        PathBuf::from("Artist/Title (96000-24).flac"),
+        format.build_path(&metadata, &FormatOptions::default())?
+    );
+
+    // Lossy files have no bit depth
+    metadata.bit_depth = None;
+
+    assert_eq!(
+        PathBuf::from("Artist/Title.flac"),
+        format.build_path(&metadata, &FormatOptions::default())?
+    );
+
+    Ok(())
+}
+
+#[test]
+fn validate_formats() -> muso::Result<()> {
+    use std::str::FromStr;
+
+    use muso::format::ParsedFormat;
+    use muso::Error;
+
+    let validate = |format: &str, collapse_empty_dirs| {
+        ParsedFormat::from_str(format).and_then(|format| format.validate(collapse_empty_dirs))
+    };
+
+    assert!(validate("{artist}/{album}/[{disc}.]{track} - {title?}.{ext}", false).is_ok());
+    assert!(validate("{artist}/{album?}/{title}.{ext}", true).is_ok());
+
+    assert!(matches!(
+        validate("{artist}/{album?}/{title}.{ext}", false),
+        Err(Error::OptionalInDir)
+    ));
+    assert!(matches!(
+        validate("{artist}/{album} {disc?}/{title}.{ext}", true),
+        Err(Error::OptionalInDir)
+    ));
+    assert!(matches!(
+        validate("{artist}/{title?}.{ext}", false),
+        Err(Error::RequiredInFile)
+    ));
+
+    Ok(())
+}
+
+#[test]
+fn split_artist() -> muso::Result<()> {
+    use std::path::PathBuf;
+    use std::str::FromStr;
+
+    use muso::format::{FormatOptions, ParsedFormat};
+    use muso::metadata::Metadata;
+
+    let metadata = Metadata {
+        artist: Some("Artist A; Artist B".into()),
+        title: Some("Title".into()),
+        ext: "flac".into(),
+        ..Default::default()
+    };
+
+    let format = ParsedFormat::from_str("{artist}/{artist} - {title}.{ext}")?;
+    let options = FormatOptions {
+        split_artist: Some(";"),
+        ..Default::default()
+    };
+
+    assert_eq!(
+        PathBuf::from("Artist A/Artist A; Artist B - Title.flac"),
+        format.build_path(&metadata, &options)?
+    );
+
+    assert_eq!("Artist A", &metadata.get_primary_artist(";")?);
+    assert_eq!(
+        "Artist A; Artist B",
+        &metadata.get_primary_artist(" feat. ")?
+    );
+
+    Ok(())
+}
+
+#[test]
+fn missing_tag_placeholder() -> muso::Result<()> {
+    use std::path::PathBuf;
+    use std::str::FromStr;
+
+    use muso::format::{FormatOptions, ParsedFormat};
+    use muso::metadata::Metadata;
+
+    let metadata = Metadata {
+        artist: Some("Artist".into()),
+        title: Some("Title".into()),
+        ext: "flac".into(),
+        ..Default::default()
+    };
+
+    let format = ParsedFormat::from_str("{artist}/{album}/[{disc}.]{track?}{title}.{ext}")?;
+    assert!(format
+        .build_path(&metadata, &FormatOptions::default())
+        .is_err());
+
+    let options = FormatOptions {
+        missing_tag: Some("Unknown"),
+        ..Default::default()
+    };
+
+    assert_eq!(
+        PathBuf::from("Artist/Unknown/Title.flac"),
+        format.build_path(&metadata, &options)?
+    );
+
+    Ok(())
+}
+
+#[test]
+fn optional_disc_of_single_disc_albums() -> muso::Result<()> {
+    use std::path::PathBuf;
+    use std::str::FromStr;
+
+    use muso::format::{FormatOptions, ParsedFormat};
+    use muso::metadata::Metadata;
+
+    let mut metadata = Metadata {
+        artist: Some("Artist".into()),
+        disc: Some(1),
+        track: Some(5),
+        total_discs: Some(2),
+        ext: "flac".into(),
+        ..Default::default()
+    };
+
+    let format = ParsedFormat::from_str("{artist}/[{disc?}.]{track:2}.{ext}")?;
+    let options = FormatOptions::default();
+    assert_eq!(
+        PathBuf::from("Artist/1.05.flac"),
+        format.build_path(&metadata, &options)?
+    );
+
+    metadata.total_discs = Some(1);
+    assert_eq!(
+        PathBuf::from("Artist/05.flac"),
+        format.build_path(&metadata, &options)?
+    );
+
+    // Required discs are always there
+    let format = ParsedFormat::from_str("{artist}/[{disc}.]{track:2}.{ext}")?;
+    assert_eq!(
+        PathBuf::from("Artist/1.05.flac"),
+        format.build_path(&metadata, &options)?
+    );
+
+    Ok(())
+}
+
+#[test]
+fn original_year() -> muso::Result<()> {
+    use std::path::PathBuf;
+    use std::str::FromStr;
+
+    use muso::format::{FormatOptions, ParsedFormat};
+    use muso::metadata::Metadata;
+
+    let mut metadata = Metadata {
+        artist: Some("Artist".into()),
+        title: Some("Title".into()),
+        ext: "flac".into(),
+        year: Some(2016),
+        original_year: Some(1959),
+        ..Default::default()
+    };
+
+    let format = ParsedFormat::from_str("{artist}/{originalyear} ({year})/{title}.{ext}")?;
+    let options = FormatOptions::default();
+
+    assert_eq!(
+        PathBuf::from("Artist/1959 (2016)/Title.flac"),
+        format.build_path(&metadata, &options)?
+    );
+
+    metadata.original_year = None;
+    assert_eq!(
+        PathBuf::from("Artist/2016 (2016)/Title.flac"),
+        format.build_path(&metadata, &options)?
+    );
+
+    let options = FormatOptions {
+        strict_original_year: true,
+        ..options
+    };
+
+    assert!(format.build_path(&metadata, &options).is_err());
+
+    Ok(())
+}
+
+#[test]
+fn placeholders() -> muso::Result<()> {
+    use std::str::FromStr;
+
+    use muso::format::{ParsedFormat, Placeholder, Tag};
+
+    let format = ParsedFormat::from_str("{artist}/[{disc}.]{track:2} - {title?} {artist}.{ext}")?;
+
+    assert_eq!(
+        format.placeholders(),
+        vec![
+            Placeholder::Required(Tag::Artist),
+            Placeholder::Required(Tag::Disc { leading: 0 }),
+            Placeholder::Required(Tag::Track { leading: 2 }),
+            Placeholder::Optional(Tag::Title),
+            Placeholder::Required(Tag::Artist),
+            Placeholder::Required(Tag::Ext),
+        ]
+    );
+
+    assert_eq!(
+        format.required_tags(),
+        vec![Tag::Artist, Tag::Track { leading: 2 }, Tag::Ext]
+    );
+
+    Ok(())
+}
+
+#[test]
+fn padded_track_is_not_padded_twice() -> muso::Result<()> {
+    use std::path::Path;
+    use std::str::FromStr;
+
+    use muso::format::{FormatOptions, ParsedFormat};
+
+    let format = ParsedFormat::from_str("{track:2} - {title}.{ext}")?;
+    let path = Path::new("01 - Title.flac");
+    let metadata = format.extract(path).unwrap();
+
+    assert_eq!(
+        format.build_path(&metadata, &FormatOptions::default())?,
+        path
+    );
+
+    Ok(())
+}
+
+#[test]
+fn build_dir_skips_the_file_name() -> muso::Result<()> {
+    use std::path::PathBuf;
+    use std::str::FromStr;
+
+    use muso::format::{FormatOptions, ParsedFormat};
+    use muso::metadata::Metadata;
+
+    let metadata = Metadata {
+        artist: Some("Artist".into()),
+        album: Some("Album".into()),
+        ext: "flac".into(),
+        ..Default::default()
+    };
+
+    let format = ParsedFormat::from_str("{artist}/{album}/{track} - {title}.{ext}")?;
+    let options = FormatOptions::default();
+
+    assert_eq!(
+        format.build_dir(&metadata, &options)?,
+        PathBuf::from("Artist/Album")
+    );
+    assert!(format.build_path(&metadata, &options).is_err());
+
+    Ok(())
+}
@@ -0,0 +1,773 @@
+#![cfg(all(feature = "flac", feature = "mp3", feature = "ogg"))]
+
+use std::fs;
+use std::path::PathBuf;
+use std::str::FromStr;
+
+use muso::format::ParsedFormat;
+use muso::sorting::{
+    apply_plan, plan_folder, plan_folder_detailed, plan_script, resume_journal, rollback_journal,
+    sort_file, sort_folder, ConflictPolicy, Journal, JournalStatus, MissingTagPolicy, Move,
+    Options,
+};
+use muso::{Error, Result};
+
+fn options(format: &str) -> Result<Options<ParsedFormat>> {
+    Ok(Options {
+        format: ParsedFormat::from_str(format)?,
+        recursive: true,
+        ..Default::default()
+    })
+}
+
+#[test]
+#[cfg(unix)]
+fn already_sorted_file_is_untouched() -> Result<()> {
+    use std::os::unix::fs::MetadataExt;
+
+    let root = tempfile::tempdir()?;
+    let sorted = root.path().join("Album Artist/Album/1 - Title.flac");
+    fs::create_dir_all(sorted.parent().unwrap())?;
+    fs::copy("test_files/complete.flac", &sorted)?;
+
+    let before = fs::metadata(&sorted)?;
+    let options = options("{artist}/{album}/{track} - {title}.{ext}")?;
+    let new_path = sort_file(root.path(), &sorted, &options)?;
+    let after = fs::metadata(&sorted)?;
+
+    assert_eq!(root.path().join(new_path), sorted);
+    assert_eq!(before.ino(), after.ino());
+    assert_eq!(fs::read("test_files/complete.flac")?, fs::read(&sorted)?);
+
+    Ok(())
+}
+
+#[test]
+fn leftovers_are_removed() -> Result<()> {
+    let root = tempfile::tempdir()?;
+    let incoming = root.path().join("Incoming");
+    fs::create_dir_all(&incoming)?;
+    fs::copy("test_files/complete.flac", incoming.join("01.flac"))?;
+    fs::write(incoming.join("info.nfo"), "")?;
+    fs::write(incoming.join("cover.JPG"), "")?;
+
+    let mut options = options("{artist}/{album}/{track} - {title}.{ext}")?;
+    options.clean_leftovers = vec!["nfo".into(), ".jpg".into()];
+
+    let report = sort_folder(root.path(), root.path(), &options)?;
+
+    assert_eq!(report.success, 1);
+    assert!(!incoming.exists());
+    assert!(root
+        .path()
+        .join("Album Artist/Album/1 - Title.flac")
+        .exists());
+
+    Ok(())
+}
+
+#[test]
+fn collisions_in_the_same_run() -> Result<()> {
+    let root = tempfile::tempdir()?;
+    fs::copy("test_files/complete.flac", root.path().join("a.flac"))?;
+    fs::copy("test_files/complete.flac", root.path().join("b.flac"))?;
+
+    let mut options = options("{artist}/{album}/{track} - {title}.{ext}")?;
+    options.dryrun = true;
+
+    let report = sort_folder(root.path(), root.path(), &options)?;
+    assert_eq!((report.success, report.total, report.collisions), (1, 2, 1));
+
+    options.dryrun = false;
+    options.conflict = ConflictPolicy::Rename;
+
+    let report = sort_folder(root.path(), root.path(), &options)?;
+    assert_eq!((report.success, report.total, report.collisions), (2, 2, 1));
+    assert!(root
+        .path()
+        .join("Album Artist/Album/1 - Title.flac")
+        .exists());
+    assert!(root
+        .path()
+        .join("Album Artist/Album/1 - Title (1).flac")
+        .exists());
+
+    Ok(())
+}
+
+#[test]
+fn compilations_are_detected() -> Result<()> {
+    let root = tempfile::tempdir()?;
+
+    for (n, artist) in ["First", "Second"].iter().enumerate() {
+        let path = root.path().join(format!("{}.flac", n));
+        fs::copy("test_files/complete.flac", &path)?;
+
+        let mut tag = metaflac::Tag::read_from_path(&path)?;
+        tag.remove_vorbis("ALBUMARTIST");
+        tag.set_vorbis("ARTIST", vec![*artist]);
+        tag.set_vorbis("TITLE", vec![*artist]);
+        tag.save()?;
+    }
+
+    let mut options = options("{artist}/{album}/{title}.{ext}")?;
+    options.compilation_threshold = Some(2);
+
+    let report = sort_folder(root.path(), root.path(), &options)?;
+
+    assert_eq!(report.success, 2);
+    assert!(root
+        .path()
+        .join("Various Artists/Album/First.flac")
+        .exists());
+    assert!(root
+        .path()
+        .join("Various Artists/Album/Second.flac")
+        .exists());
+
+    Ok(())
+}
+
+#[test]
+#[cfg(unix)]
+fn compilations_skip_ignored_files_and_loops() -> Result<()> {
+    use std::os::unix::fs::symlink;
+
+    let root = tempfile::tempdir()?;
+
+    for (n, artist) in ["First", "Second", "Third"].iter().enumerate() {
+        let path = root.path().join(format!("{}.flac", n));
+        fs::copy("test_files/complete.flac", &path)?;
+
+        let mut tag = metaflac::Tag::read_from_path(&path)?;
+        tag.remove_vorbis("ALBUMARTIST");
+        tag.set_vorbis("ARTIST", vec![*artist]);
+        tag.set_vorbis("TITLE", vec![*artist]);
+        tag.save()?;
+    }
+
+    fs::write(root.path().join(".musoignore"), "2.flac\n")?;
+    symlink(root.path(), root.path().join("Loop"))?;
+
+    let mut options = options("{artist}/{album}/{title}.{ext}")?;
+    options.compilation_threshold = Some(3);
+    options.follow_symlinks = true;
+
+    let report = sort_folder(root.path(), root.path(), &options)?;
+
+    // The ignored third artist doesn't count towards the threshold
+    assert_eq!(report.success, 2);
+    assert!(root.path().join("First/Album/First.flac").exists());
+    assert!(root.path().join("Second/Album/Second.flac").exists());
+    assert!(root.path().join("2.flac").exists());
+
+    Ok(())
+}
+
+#[test]
+#[cfg(unix)]
+fn created_dirs_use_dir_mode() -> Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+
+    let root = tempfile::tempdir()?;
+    let file = root.path().join("complete.flac");
+    fs::copy("test_files/complete.flac", &file)?;
+
+    let mut options = options("{artist}/{album}/{title}.{ext}")?;
+    options.dir_mode = Some(0o775);
+    sort_file(root.path(), &file, &options)?;
+
+    for dir in &["Album Artist", "Album Artist/Album"] {
+        let mode = fs::metadata(root.path().join(dir))?.permissions().mode();
+        assert_eq!(mode & 0o777, 0o775);
+    }
+
+    let mode = fs::metadata(root.path())?.permissions().mode();
+    assert_ne!(mode & 0o777, 0o775);
+
+    Ok(())
+}
+
+#[test]
+fn musoignore_files_compose() -> Result<()> {
+    let root = tempfile::tempdir()?;
+    fs::create_dir_all(root.path().join("ignored"))?;
+    fs::create_dir_all(root.path().join("sub"))?;
+
+    fs::write(root.path().join(".musoignore"), "ignored/\n")?;
+    fs::write(root.path().join("sub/.musoignore"), "*.mp3\n")?;
+    fs::copy(
+        "test_files/complete.flac",
+        root.path().join("ignored/a.flac"),
+    )?;
+    fs::copy("test_files/complete.mp3", root.path().join("sub/b.mp3"))?;
+    fs::copy("test_files/complete.ogg", root.path().join("sub/c.ogg"))?;
+
+    let options = options("{artist}/{album}/{title}.{ext}")?;
+    let report = sort_folder(root.path(), root.path(), &options)?;
+
+    assert_eq!(report.total, 1);
+    assert_eq!(report.success, 1);
+    assert!(root.path().join("ignored/a.flac").exists());
+    assert!(root.path().join("sub/b.mp3").exists());
+    assert!(!root.path().join("sub/c.ogg").exists());
+
+    Ok(())
+}
+
+#[test]
+#[cfg(unix)]
+fn each_file_hook_runs_after_moving() -> Result<()> {
+    let root = tempfile::tempdir()?;
+    fs::copy("test_files/complete.flac", root.path().join("a.flac"))?;
+    fs::copy("test_files/complete.mp3", root.path().join("b.mp3"))?;
+
+    let mut options = options("{title}.{ext}")?;
+    options.each_file_hook =
+        Some(r#"echo "$MUSO_SRC" > "$MUSO_DST.src"; [ "${MUSO_SRC##*/}" = a.flac ]"#.into());
+
+    let report = sort_folder(root.path(), root.path(), &options)?;
+
+    assert_eq!(report.success, 2);
+    assert_eq!(report.hook_failures, 1);
+    assert_eq!(
+        fs::read_to_string(root.path().join("Title.flac.src"))?.trim(),
+        root.path().join("a.flac").to_string_lossy()
+    );
+
+    Ok(())
+}
+
+#[test]
+fn path_placeholder_keeps_original_layout() -> Result<()> {
+    let root = tempfile::tempdir()?;
+    fs::create_dir_all(root.path().join("Vinyl Rips/misc"))?;
+    fs::copy(
+        "test_files/complete.flac",
+        root.path().join("Vinyl Rips/misc/a.flac"),
+    )?;
+
+    let options = options("{path0}/{artist}/{title}.{ext}")?;
+    let report = sort_folder(root.path(), root.path(), &options)?;
+
+    assert_eq!(report.success, 1);
+    assert!(root
+        .path()
+        .join("Vinyl Rips/Album Artist/Title.flac")
+        .exists());
+
+    Ok(())
+}
+
+#[test]
+fn conflicts_are_reported() -> Result<()> {
+    let root = tempfile::tempdir()?;
+    fs::copy("test_files/complete.flac", root.path().join("a.flac"))?;
+    fs::copy("test_files/complete.flac", root.path().join("b.flac"))?;
+    fs::copy("test_files/complete.mp3", root.path().join("c.mp3"))?;
+
+    let mut options = options("{artist}/{title}.{ext}")?;
+    options.dryrun = true;
+    let report = sort_folder(root.path(), root.path(), &options)?;
+
+    let destination = root.path().join("Album Artist/Title.flac");
+    assert_eq!(report.conflicts.len(), 1);
+
+    let mut sources = report.conflicts[&destination].clone();
+    sources.sort();
+    assert_eq!(
+        sources,
+        vec![root.path().join("a.flac"), root.path().join("b.flac")]
+    );
+
+    Ok(())
+}
+
+#[test]
+fn fail_fast_stops_at_first_error() -> Result<()> {
+    let root = tempfile::tempdir()?;
+    fs::copy("test_files/complete.flac", root.path().join("a.flac"))?;
+    fs::copy("test_files/partial.flac", root.path().join("b.flac"))?;
+
+    let mut options = options("{album}/{title}.{ext}")?;
+    options.dryrun = true;
+    let report = sort_folder(root.path(), root.path(), &options)?;
+    assert_eq!(report.total, 2);
+    assert_eq!(report.success, 1);
+
+    options.fail_fast = true;
+    assert!(sort_folder(root.path(), root.path(), &options).is_err());
+
+    assert!(sort_folder(root.path(), root.path().join("missing"), &options).is_err());
+    options.fail_fast = false;
+    assert!(sort_folder(root.path(), root.path().join("missing"), &options).is_err());
+
+    Ok(())
+}
+
+#[test]
+fn unsupported_files_are_not_counted() -> Result<()> {
+    let root = tempfile::tempdir()?;
+    fs::write(root.path().join("notes.txt"), "not music")?;
+    fs::write(root.path().join("booklet.pdf"), "%PDF-1.4")?;
+
+    let options = options("{artist}/{title}.{ext}")?;
+    let report = sort_folder(root.path(), root.path(), &options)?;
+
+    assert_eq!((report.success, report.total, report.skipped), (0, 0, 2));
+    assert!(root.path().join("notes.txt").exists());
+
+    Ok(())
+}
+
+#[test]
+fn options_builder() -> Result<()> {
+    let format = ParsedFormat::from_str("{artist}/{title}.{ext}")?;
+    let options = Options::builder()
+        .format(format)
+        .dryrun(true)
+        .conflict(ConflictPolicy::Rename)
+        .build();
+
+    assert!(options.dryrun);
+    assert!(!options.recursive);
+    assert_eq!(options.conflict, ConflictPolicy::Rename);
+
+    let root = tempfile::tempdir()?;
+    fs::copy("test_files/complete.flac", root.path().join("a.flac"))?;
+
+    let report = sort_folder(root.path(), root.path(), &options)?;
+    assert_eq!(
+        report.new_paths,
+        vec![PathBuf::from("Album Artist/Title.flac")]
+    );
+    assert!(root.path().join("a.flac").exists());
+
+    Ok(())
+}
+
+#[test]
+fn plan_has_no_side_effects() -> Result<()> {
+    let root = tempfile::tempdir()?;
+    fs::create_dir_all(root.path().join("empty"))?;
+    fs::copy("test_files/complete.flac", root.path().join("a.flac"))?;
+
+    let mut options = options("{artist}/{title}.{ext}")?;
+    options.remove_empty = true;
+    let plan = plan_folder(root.path(), root.path(), &options)?;
+
+    assert_eq!(
+        plan,
+        vec![Move {
+            from: root.path().join("a.flac"),
+            to: root.path().join("Album Artist/Title.flac"),
+        }]
+    );
+    assert!(root.path().join("a.flac").exists());
+    assert!(root.path().join("empty").exists());
+
+    Ok(())
+}
+
+#[test]
+fn plan_counts_files_in_place() -> Result<()> {
+    let root = tempfile::tempdir()?;
+    fs::create_dir_all(root.path().join("Album Artist"))?;
+    fs::copy(
+        "test_files/complete.flac",
+        root.path().join("Album Artist/Title.flac"),
+    )?;
+    fs::copy("test_files/complete.mp3", root.path().join("b.mp3"))?;
+
+    let options = options("{artist}/{title}.{ext}")?;
+    let (plan, report) = plan_folder_detailed(root.path(), root.path(), &options)?;
+
+    assert_eq!(
+        plan,
+        vec![Move {
+            from: root.path().join("b.mp3"),
+            to: root.path().join("Album Artist/Title.mp3"),
+        }]
+    );
+    assert_eq!(report.in_place, 1);
+    assert_eq!(report.success, 2);
+
+    Ok(())
+}
+
+#[test]
+fn apply_plan_skips_vanished_sources() -> Result<()> {
+    let root = tempfile::tempdir()?;
+    fs::copy("test_files/complete.flac", root.path().join("a.flac"))?;
+    fs::copy("test_files/complete.mp3", root.path().join("b.mp3"))?;
+
+    let options = options("{artist}/{title}.{ext}")?;
+    let plan = plan_folder(root.path(), root.path(), &options)?;
+    assert_eq!(plan.len(), 2);
+
+    fs::remove_file(root.path().join("b.mp3"))?;
+    let report = apply_plan(&plan, &options)?;
+
+    assert_eq!(report.total, 1);
+    assert_eq!(report.success, 1);
+    assert!(root.path().join("Album Artist/Title.flac").exists());
+
+    Ok(())
+}
+
+#[test]
+fn small_files_are_skipped() -> Result<()> {
+    let root = tempfile::tempdir()?;
+    fs::copy("test_files/complete.flac", root.path().join("a.flac"))?;
+
+    let mut options = options("{artist}/{title}.{ext}")?;
+    options.min_size = Some(fs::metadata("test_files/complete.flac")?.len() + 1);
+
+    let report = sort_folder(root.path(), root.path(), &options)?;
+    assert_eq!((report.total, report.skipped), (0, 1));
+    assert!(root.path().join("a.flac").exists());
+
+    Ok(())
+}
+
+#[test]
+fn files_are_sorted_into_dest_root() -> Result<()> {
+    let downloads = tempfile::tempdir()?;
+    let music = tempfile::tempdir()?;
+    fs::copy("test_files/complete.flac", downloads.path().join("a.flac"))?;
+
+    let mut options = options("{artist}/{title}.{ext}")?;
+    options.dest_root = Some(music.path().to_path_buf());
+
+    let report = sort_folder(downloads.path(), downloads.path(), &options)?;
+    assert_eq!(report.success, 1);
+    assert!(music.path().join("Album Artist/Title.flac").exists());
+    assert!(!downloads.path().join("a.flac").exists());
+
+    Ok(())
+}
+
+#[test]
+fn missing_dest_root_is_created() -> Result<()> {
+    let downloads = tempfile::tempdir()?;
+    let music = tempfile::tempdir()?;
+    let dest = music.path().join("New/Music");
+
+    let mut options = options("{artist}/{title}.{ext}")?;
+    options.dest_root = Some(dest.clone());
+    options.create_root = true;
+    options.dryrun = true;
+
+    sort_folder(downloads.path(), downloads.path(), &options)?;
+    assert!(!dest.exists());
+
+    options.dryrun = false;
+    sort_folder(downloads.path(), downloads.path(), &options)?;
+    assert!(dest.is_dir());
+
+    Ok(())
+}
+
+#[test]
+fn nested_empty_dirs_are_removed() -> Result<()> {
+    let root = tempfile::tempdir()?;
+    let nested = root.path().join("a/b/c");
+    fs::create_dir_all(&nested)?;
+    fs::copy("test_files/complete.flac", nested.join("a.flac"))?;
+
+    let mut options = options("{artist}/{title}.{ext}")?;
+    options.remove_empty = true;
+
+    let report = sort_folder(root.path(), root.path(), &options)?;
+    assert_eq!(report.success, 1);
+    assert_eq!(report.dirs_removed, 3);
+    assert!(!root.path().join("a").exists());
+    assert!(root.path().join("Album Artist/Title.flac").exists());
+
+    Ok(())
+}
+
+#[test]
+fn plan_script_quotes_paths() {
+    let plan = vec![Move {
+        from: PathBuf::from("/music/it's.flac"),
+        to: PathBuf::from("/music/Artist/Title.flac"),
+    }];
+
+    assert_eq!(
+        plan_script(&plan),
+        "#!/bin/sh\nset -e\n\n\
+         mkdir -p '/music/Artist'\n\
+         mv -n '/music/it'\\''s.flac' '/music/Artist/Title.flac'\n"
+    );
+}
+
+#[test]
+fn album_mode_keeps_albums_together() -> Result<()> {
+    let root = tempfile::tempdir()?;
+    let incoming = root.path().join("Incoming");
+    fs::create_dir_all(&incoming)?;
+    fs::copy("test_files/complete.mp3", incoming.join("01.mp3"))?;
+    fs::copy("test_files/partial.mp3", incoming.join("02.mp3"))?;
+
+    let mut options = options("{artist}/{album}/{title}.{ext}")?;
+    options.album_mode = true;
+    options.conflict = ConflictPolicy::Rename;
+
+    let report = sort_folder(root.path(), root.path(), &options)?;
+    assert_eq!(report.success, 2);
+
+    let album = root.path().join("Album Artist/Album");
+    assert_eq!(fs::read_dir(album)?.count(), 2);
+    assert!(!root.path().join("Artist").exists());
+
+    Ok(())
+}
+
+#[test]
+fn source_tree_is_preserved() -> Result<()> {
+    let root = tempfile::tempdir()?;
+    fs::create_dir_all(root.path().join("Collection A"))?;
+    fs::create_dir_all(root.path().join("Collection B/CD1"))?;
+    fs::copy(
+        "test_files/complete.flac",
+        root.path().join("Collection A/a.flac"),
+    )?;
+    fs::copy(
+        "test_files/complete.flac",
+        root.path().join("Collection B/CD1/b.flac"),
+    )?;
+
+    let mut options = options("{artist}/{title}.{ext}")?;
+    options.preserve_source_tree = true;
+
+    let report = sort_folder(root.path(), root.path(), &options)?;
+
+    assert_eq!((report.success, report.collisions), (2, 0));
+    assert!(root
+        .path()
+        .join("Collection A/Album Artist/Title.flac")
+        .exists());
+    assert!(root
+        .path()
+        .join("Collection B/CD1/Album Artist/Title.flac")
+        .exists());
+
+    Ok(())
+}
+
+#[test]
+fn source_tree_is_kept_from_another_folder() -> Result<()> {
+    let source = tempfile::tempdir()?;
+    let dest = tempfile::tempdir()?;
+    fs::create_dir_all(source.path().join("Collection A"))?;
+    fs::copy(
+        "test_files/complete.flac",
+        source.path().join("Collection A/a.flac"),
+    )?;
+
+    let mut options = options("{artist}/{title}.{ext}")?;
+    options.preserve_source_tree = true;
+
+    let report = sort_folder(dest.path(), source.path(), &options)?;
+
+    assert_eq!(report.success, 1);
+    assert!(dest
+        .path()
+        .join("Collection A/Album Artist/Title.flac")
+        .exists());
+
+    Ok(())
+}
+
+#[test]
+#[cfg(unix)]
+fn symlinks_are_followed_only_when_enabled() -> Result<()> {
+    use std::os::unix::fs::symlink;
+
+    let root = tempfile::tempdir()?;
+    let elsewhere = tempfile::tempdir()?;
+    fs::copy("test_files/complete.flac", root.path().join("a.flac"))?;
+    fs::copy("test_files/complete.flac", elsewhere.path().join("b.flac"))?;
+    symlink(elsewhere.path(), root.path().join("Linked"))?;
+    symlink(root.path(), root.path().join("Loop"))?;
+
+    let mut options = options("{artist}/{title}.{ext}")?;
+    options.dryrun = true;
+
+    let report = sort_folder(root.path(), root.path(), &options)?;
+    assert_eq!(report.total, 1);
+
+    options.follow_symlinks = true;
+
+    let report = sort_folder(root.path(), root.path(), &options)?;
+    assert_eq!(report.total, 2);
+
+    Ok(())
+}
+
+#[test]
+fn unnormalized_roots_are_resolved() -> Result<()> {
+    let root = tempfile::tempdir()?;
+    fs::create_dir(root.path().join("Downloads"))?;
+    fs::copy(
+        "test_files/complete.flac",
+        root.path().join("Downloads/a.flac"),
+    )?;
+
+    let mut options = options("{path0}/{title}.{ext}")?;
+    options.dryrun = true;
+
+    let spelled = root.path().join("Downloads/..");
+    let report = sort_folder(&spelled, root.path(), &options)?;
+    assert_eq!(
+        report.new_paths,
+        vec![PathBuf::from("Downloads/Title.flac")]
+    );
+
+    Ok(())
+}
+
+#[test]
+fn conforming_files_are_skipped() -> Result<()> {
+    let root = tempfile::tempdir()?;
+    let conforming = PathBuf::from("Someone/Else/2 - Song.flac");
+    fs::create_dir_all(root.path().join("Someone/Else"))?;
+    fs::copy("test_files/complete.flac", root.path().join(&conforming))?;
+
+    let mut options = options("{artist}/{album}/{track} - {title}.{ext}")?;
+    options.dryrun = true;
+
+    let report = sort_folder(root.path(), root.path(), &options)?;
+    assert_eq!(
+        report.new_paths,
+        vec![PathBuf::from("Album Artist/Album/1 - Title.flac")]
+    );
+
+    options.skip_conforming = true;
+
+    let report = sort_folder(root.path(), root.path(), &options)?;
+    assert_eq!(report.new_paths, vec![conforming]);
+
+    Ok(())
+}
+
+#[test]
+fn missing_tags_can_be_skipped() -> Result<()> {
+    let root = tempfile::tempdir()?;
+    fs::copy("test_files/complete.flac", root.path().join("a.flac"))?;
+
+    let mut options = options("{artist}/{bpm}/{title}.{ext}")?;
+    options.dryrun = true;
+
+    let report = sort_folder(root.path(), root.path(), &options)?;
+    assert_eq!((report.success, report.total, report.skipped), (0, 1, 0));
+
+    options.missing_tag_policy = MissingTagPolicy::Skip;
+
+    let report = sort_folder(root.path(), root.path(), &options)?;
+    assert_eq!((report.success, report.total, report.skipped), (0, 0, 1));
+
+    options.missing_tag_policy = MissingTagPolicy::Placeholder("Unknown".into());
+
+    let report = sort_folder(root.path(), root.path(), &options)?;
+    assert_eq!(
+        report.new_paths,
+        vec![PathBuf::from("Album Artist/Unknown/Title.flac")]
+    );
+
+    Ok(())
+}
+
+#[test]
+fn journal_resume_and_rollback() -> Result<()> {
+    let root = tempfile::tempdir()?;
+    let elsewhere = tempfile::tempdir()?;
+    let journal = root.path().join(".muso-journal");
+    let (a, b) = (root.path().join("a.flac"), root.path().join("b.flac"));
+    fs::copy("test_files/complete.flac", &a)?;
+    fs::copy("test_files/complete.flac", &b)?;
+
+    // As if muso was interrupted right after planning to move `b`
+    let mv = Move {
+        from: b.clone(),
+        to: elsewhere.path().join("Album Artist/Title.flac"),
+    };
+
+    Journal::open(&journal)?.record(JournalStatus::Planned, &mv)?;
+
+    let mut options = options("{artist}/{title}.{ext}")?;
+    options.journal = Some(journal.clone());
+
+    let report = resume_journal(&journal, &options)?;
+    assert_eq!((report.success, report.total), (1, 1));
+    assert!(mv.to.exists());
+
+    let report = sort_folder(root.path(), root.path(), &options)?;
+    assert_eq!(report.success, 1);
+    assert!(root.path().join("Album Artist/Title.flac").exists());
+
+    let report = rollback_journal(&journal, &options)?;
+    assert_eq!((report.success, report.total), (2, 2));
+    assert!(a.exists() && b.exists());
+
+    // Nothing left to roll back
+    let report = rollback_journal(&journal, &options)?;
+    assert_eq!(report.total, 0);
+
+    Ok(())
+}
+
+#[test]
+fn hidden_entries_are_skipped() -> Result<()> {
+    let root = tempfile::tempdir()?;
+    let hidden_root = root.path().join(".music");
+    fs::create_dir_all(hidden_root.join(".git"))?;
+    fs::copy("test_files/complete.flac", hidden_root.join("a.flac"))?;
+    fs::copy("test_files/complete.flac", hidden_root.join(".b.flac"))?;
+    fs::copy("test_files/complete.flac", hidden_root.join(".git/c.flac"))?;
+
+    let mut options = options("{artist}/{title}.{ext}")?;
+    options.dryrun = true;
+
+    let report = sort_folder(&hidden_root, &hidden_root, &options)?;
+    assert_eq!(report.total, 1);
+
+    options.include_hidden = true;
+
+    let report = sort_folder(&hidden_root, &hidden_root, &options)?;
+    assert_eq!(report.total, 3);
+
+    Ok(())
+}
+
+#[test]
+fn device_sentinel_guards_the_destination() -> Result<()> {
+    let root = tempfile::tempdir()?;
+    fs::copy("test_files/complete.flac", root.path().join("a.flac"))?;
+
+    let mut options = options("{artist}/{title}.{ext}")?;
+    options.device_sentinel = Some(root.path().join(".mounted"));
+
+    let sorted = sort_folder(root.path(), root.path(), &options);
+    assert!(matches!(sorted, Err(Error::SentinelNotFound { .. })));
+    assert!(root.path().join("a.flac").exists());
+
+    // A different filesystem than any temporary folder
+    #[cfg(target_os = "linux")]
+    {
+        options.device_sentinel = Some(PathBuf::from("/proc/self"));
+
+        let sorted = sort_folder(root.path(), root.path(), &options);
+        assert!(matches!(sorted, Err(Error::WrongDevice { .. })));
+        assert!(root.path().join("a.flac").exists());
+    }
+
+    fs::write(root.path().join(".mounted"), "")?;
+    options.device_sentinel = Some(root.path().join(".mounted"));
+
+    let report = sort_folder(root.path(), root.path(), &options)?;
+    assert_eq!(report.success, 1);
+
+    Ok(())
+}